@@ -0,0 +1,83 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether the process should get traffic, answered with 503 if any check failed
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    /// name of each check, `ok` or why it failed
+    pub checks: BTreeMap<String, String>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self {
+            ready: true,
+            checks: BTreeMap::new(),
+        }
+    }
+
+    pub fn check(mut self, name: &str, result: Result<(), String>) -> Self {
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                self.ready = false;
+                e
+            }
+        };
+        self.checks.insert(name.to_string(), status);
+        self
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntoResponse for Readiness {
+    fn into_response(self) -> axum::response::Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// A connection can be taken from the pool and answers within a couple of seconds
+pub async fn check_pool(pool: &PgPool) -> Result<(), String> {
+    let query = sqlx::query("SELECT 1").execute(pool);
+    match tokio::time::timeout(CHECK_TIMEOUT, query).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {:?}", CHECK_TIMEOUT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_should_fail_if_any_check_fails() {
+        let readiness = Readiness::new().check("db", Ok(()));
+        assert!(readiness.ready);
+
+        let readiness = readiness.check("listener", Err("not attached".to_string()));
+        assert!(!readiness.ready);
+        assert_eq!(readiness.checks["db"], "ok");
+        assert_eq!(
+            readiness.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
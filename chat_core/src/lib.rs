@@ -1,5 +1,11 @@
 mod utils;
+pub mod config;
+pub mod health;
+pub mod metrics;
 pub mod middlewares;
+pub mod migrate;
+pub mod shutdown;
+pub mod telemetry;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
@@ -45,17 +51,50 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    pub topic: Option<String>,
     pub created_at: DateTime<Utc>,
 }
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "message_content_type", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ContentType {
+    /// plain text, rendered as is
+    #[default]
+    Text,
+    /// a json rich text document
+    Rich,
+    /// a json event written by the server, e.g. members added or the topic changed
+    System,
+}
 #[derive(Debug, Clone, FromRow, ToSchema,Serialize, Deserialize, PartialEq)]
 pub struct Message {
     pub id: i64,
     pub chat_id: i64,
     pub sender_id: i64,
+    pub content_type: ContentType,
     pub content: String,
     pub files: Vec<String>,
     pub created_at: DateTime<Utc>,
 }
+#[derive(Debug, Clone, FromRow, ToSchema,Serialize, Deserialize, PartialEq)]
+pub struct LinkPreview {
+    pub id: i64,
+    pub message_id: i64,
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+#[derive(Debug, Clone, FromRow, ToSchema,Serialize, Deserialize, PartialEq)]
+pub struct Reminder {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub content: String,
+    pub remind_at: DateTime<Utc>,
+}
 impl User {
     pub fn new(id: i64, fullname: &str, email: &str) -> Self {
         Self {
@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    io,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use ::metrics::{counter, gauge, histogram};
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::PgPool;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const POOL_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+static HANDLE: OnceLock<Option<PrometheusHandle>> = OnceLock::new();
+
+/// The process wide Prometheus recorder, installed on first use
+fn handle() -> Option<&'static PrometheusHandle> {
+    HANDLE
+        .get_or_init(|| {
+            let builder = PrometheusBuilder::new().set_buckets_for_metric(
+                Matcher::Full(REQUEST_DURATION.to_string()),
+                LATENCY_BUCKETS,
+            );
+            match builder.and_then(|b| b.install_recorder()) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    warn!("Failed to install the metrics recorder: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Make sure metrics recorded before the first request aren't lost
+pub fn init() {
+    handle();
+}
+
+/// `GET /metrics` in the Prometheus text format
+pub async fn metrics_handler() -> Response {
+    match handle() {
+        Some(handle) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            handle.render(),
+        )
+            .into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// Only `GET /metrics`, served apart from the api so it can stay off the public network,
+/// or merged into it by `set_layer`
+pub fn metrics_router() -> Router {
+    init();
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+/// Serve the metrics router on `port` in the background until `shutdown` resolves, fails
+/// if the port can't be bound
+pub async fn spawn_metrics_server(
+    port: u16,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on: {}", addr);
+    tokio::spawn(async move {
+        let server = axum::serve(listener, metrics_router().into_make_service())
+            .with_graceful_shutdown(shutdown);
+        if let Err(e) = server.await {
+            warn!("Metrics server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Count requests and record their latency per route and status. Routes are the
+/// patterns they matched, e.g. `/api/chats/:id`, so ids don't blow up the label space.
+pub async fn track_metrics(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+
+    let res = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", res.status().as_u16().to_string()),
+    ];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!(REQUEST_DURATION, &labels).record(start.elapsed().as_secs_f64());
+    res
+}
+
+/// Sample the connections of `pool` in the background
+pub fn track_pool(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POOL_SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let size = pool.size();
+            let idle = pool.num_idle() as u32;
+            gauge!("db_pool_connections", "state" => "idle").set(idle as f64);
+            gauge!("db_pool_connections", "state" => "active")
+                .set(size.saturating_sub(idle) as f64);
+            gauge!("db_pool_max_connections").set(pool.options().get_max_connections() as f64);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::metrics_router;
+    use crate::middlewares::set_layer;
+
+    #[tokio::test]
+    async fn metrics_should_count_requests_per_route() -> Result<()> {
+        let app = set_layer(
+            Router::new().route("/chats/:id", get(|| async { "ok" })),
+            false,
+        );
+        for id in 1..=2 {
+            let req = axum::http::Request::get(format!("/chats/{}", id)).body(Body::empty())?;
+            app.clone().oneshot(req).await?;
+        }
+
+        // only served by the metrics router, not next to the api
+        let req = axum::http::Request::get("/metrics").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = axum::http::Request::get("/metrics").body(Body::empty())?;
+        let res = metrics_router().oneshot(req).await?;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        let body = String::from_utf8(body.to_vec())?;
+        let requests = body
+            .lines()
+            .find(|line| {
+                line.starts_with("http_requests_total{") && line.contains(r#"route="/chats/:id""#)
+            })
+            .expect("requests of the route should be counted");
+        assert!(requests.contains(r#"status="200""#));
+        assert!(requests.ends_with(" 2"));
+        assert!(body.contains("http_request_duration_seconds_bucket"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_layer_should_serve_metrics_if_asked() -> Result<()> {
+        let app = set_layer(Router::new(), true);
+        let req = axum::http::Request::get("/metrics").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
@@ -7,7 +7,7 @@ use tower::ServiceBuilder;
 use tower_http::trace::DefaultOnRequest;
 use tower_http::{
     compression::CompressionLayer,
-    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnResponse, TraceLayer},
     LatencyUnit,
 };
 use tracing::Level;
@@ -19,6 +19,8 @@ mod server_time;
 pub use auth::verify_token;
 
 
+use crate::metrics::track_metrics;
+use crate::telemetry::make_request_span;
 use crate::User;
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
@@ -30,12 +32,20 @@ pub trait TokenVerify {
     fn verify(&self, token: &str) -> Result<User, Self::Error>;
 }
 
-pub fn set_layer(app: Router) -> Router {
+/// The layers every server shares. With `serve_metrics` the app also answers
+/// `GET /metrics`, for servers whose port isn't public. Otherwise metrics are served on
+/// their own port, see [`crate::metrics::spawn_metrics_server`].
+pub fn set_layer(app: Router, serve_metrics: bool) -> Router {
+    crate::metrics::init();
+    let app = match serve_metrics {
+        true => app.merge(crate::metrics::metrics_router()),
+        false => app,
+    };
     app.layer(
         ServiceBuilder::new()
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                    .make_span_with(make_request_span::<axum::body::Body>)
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
                     .on_response(
                         DefaultOnResponse::new()
@@ -43,6 +53,7 @@ pub fn set_layer(app: Router) -> Router {
                             .latency_unit(LatencyUnit::Micros),
                     ),
             )
+            .layer(from_fn(track_metrics))
             .layer(CompressionLayer::new().gzip(true).br(true).deflate(true))
             .layer(from_fn(set_request_id))
             .layer(ServerTimeLayer),
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::Migrator, PgConnection, PgPool};
+use tracing::info;
+
+/// The migrations in `/migrations`, compiled into every binary so a server never runs
+/// against a schema it wasn't built for
+pub static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+/// Held while migrations are checked and applied, so replicas starting together take
+/// turns instead of racing for the same versions
+const MIGRATION_LOCK_KEY: i64 = 0x6368_6174_6d69_6772;
+
+/// What a server does with the schema when it starts
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrateMode {
+    /// leave the schema to outside tooling
+    #[default]
+    Off,
+    /// refuse to start if the schema is behind the embedded migrations
+    Check,
+    /// apply the pending migrations
+    Apply,
+}
+
+/// Versions of the embedded migrations not applied to the database yet
+pub async fn pending_migrations(pool: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    pending(&mut conn).await
+}
+
+/// Apply the pending migrations under an advisory lock, returns their versions
+pub async fn run_migrations(pool: &PgPool) -> Result<Vec<i64>, sqlx::migrate::MigrateError> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await?;
+    let ret = async {
+        let pending = pending(&mut conn).await?;
+        if !pending.is_empty() {
+            MIGRATOR.run(&mut *conn).await?;
+        }
+        Ok::<_, sqlx::migrate::MigrateError>(pending)
+    }
+    .await;
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await?;
+
+    ret
+}
+
+/// Bring the schema in line with `mode` before serving, errors if it is behind in
+/// check mode
+pub async fn prepare_schema(pool: &PgPool, mode: MigrateMode) -> anyhow::Result<()> {
+    match mode {
+        MigrateMode::Off => {}
+        MigrateMode::Check => {
+            let pending = pending_migrations(pool).await?;
+            if !pending.is_empty() {
+                anyhow::bail!(
+                    "database schema is behind, {} migrations pending: {:?}",
+                    pending.len(),
+                    pending
+                );
+            }
+            info!("Database schema is up to date");
+        }
+        MigrateMode::Apply => {
+            let applied = run_migrations(pool).await?;
+            info!("Applied {} migrations {:?}", applied.len(), applied);
+        }
+    }
+    Ok(())
+}
+
+async fn pending(conn: &mut PgConnection) -> Result<Vec<i64>, sqlx::Error> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *conn)
+        .await?;
+    let applied: HashSet<i64> = if exists {
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+        .map(|m| m.version)
+        .collect())
+}
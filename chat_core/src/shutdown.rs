@@ -0,0 +1,121 @@
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Tells everything serving requests that the process is going down, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown is triggered, right away if it already was
+    pub fn triggered(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.0.subscribe();
+        async move {
+            // the sender lives as long as any clone of self, an error can't happen first
+            let _ = rx.wait_for(|triggered| *triggered).await;
+        }
+    }
+
+    /// Trigger on SIGTERM or ctrl-c
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received, draining requests");
+            shutdown.trigger();
+        });
+    }
+
+    /// Run `server` to completion, giving in-flight requests at most `timeout` after
+    /// shutdown is triggered. `server` should itself stop accepting connections on
+    /// [`Shutdown::triggered`].
+    pub async fn drain<F>(&self, server: F, timeout: Duration) -> io::Result<()>
+    where
+        F: Future<Output = io::Result<()>>,
+    {
+        tokio::pin!(server);
+        tokio::select! {
+            ret = &mut server => return ret,
+            _ = self.triggered() => {}
+        }
+        match tokio::time::timeout(timeout, server).await {
+            Ok(ret) => ret,
+            Err(_) => {
+                warn!(
+                    "Requests still running after {:?}, shutting down anyway",
+                    timeout
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_should_stop_waiting_after_timeout() -> io::Result<()> {
+        let shutdown = Shutdown::new();
+        let triggered = shutdown.triggered();
+        shutdown.trigger();
+        triggered.await;
+        assert!(shutdown.is_triggered());
+
+        // a request which never finishes doesn't hold the process up
+        let server = std::future::pending::<io::Result<()>>();
+        shutdown.drain(server, Duration::from_millis(10)).await?;
+
+        let server = async { Ok(()) };
+        shutdown.drain(server, Duration::from_secs(10)).await
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use axum::http::{HeaderMap, Request};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self as sdktrace, Sampler, TracerProvider},
+    Resource,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, level_filters::LevelFilter, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+/// W3C trace context header, also the key of the trace in `pg_notify` payloads
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Export of spans to an OpenTelemetry collector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// export spans over OTLP, otherwise they are only logged
+    pub enabled: bool,
+    /// OTLP/HTTP traces url of the collector, used as is
+    pub endpoint: String,
+    /// share of new traces sampled, traces started by a caller follow its decision
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Flushes the spans not exported yet when dropped, keep it until the server stopped
+#[must_use]
+pub struct TelemetryGuard {
+    enabled: bool,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Log spans locally and, if enabled, export them as `service` over OTLP. Trace context
+/// is propagated in the W3C `traceparent` format either way.
+pub fn init_tracing(service: &str, config: &TelemetryConfig) -> anyhow::Result<TelemetryGuard> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let fmt = fmt::layer().with_filter(LevelFilter::INFO);
+
+    if !config.enabled {
+        tracing_subscriber::registry().with(fmt).try_init()?;
+        return Ok(TelemetryGuard { enabled: false });
+    }
+    let provider = tracer_provider(service, config)?;
+    let tracer = provider.tracer(service.to_string());
+    global::set_tracer_provider(provider);
+    let otel = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(otel)
+        .try_init()?;
+
+    Ok(TelemetryGuard { enabled: true })
+}
+
+fn tracer_provider(service: &str, config: &TelemetryConfig) -> Result<TracerProvider, TraceError> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.endpoint);
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            sdktrace::config()
+                .with_sampler(sampler)
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service.to_string(),
+                )])),
+        )
+        .install_batch(runtime::Tokio)
+}
+
+/// Span of an incoming request, a child of the caller's trace if it sent `traceparent`.
+/// Spans are exported, so they leave out the headers and the query, both may carry a token.
+pub fn make_request_span<B>(req: &Request<B>) -> Span {
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri().path(),
+        version = ?req.version(),
+    );
+    let cx = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    span.set_parent(cx);
+    span
+}
+
+/// `traceparent` of the current span, `None` if it isn't exported
+pub fn current_traceparent() -> Option<String> {
+    let cx = Span::current().context();
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|p| p.inject_context(&cx, &mut carrier));
+    carrier.remove(TRACEPARENT)
+}
+
+/// Make `span` continue the trace of `traceparent`, e.g. one passed through Postgres
+pub fn set_remote_parent(span: &Span, traceparent: &str) {
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let cx = global::get_text_map_propagator(|p| p.extract(&carrier));
+    span.set_parent(cx);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use anyhow::Result;
+    use axum::{routing::post, Router};
+    use opentelemetry::trace::{TraceContextExt, Tracer, TracerProvider as _};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn traceparent_should_survive_a_round_trip() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("send_message");
+            let traceparent = span.in_scope(current_traceparent).expect("should be set");
+            let trace_id = span.context().span().span_context().trace_id();
+            assert!(traceparent.contains(&trace_id.to_string()));
+
+            // e.g. notify_server picking the message up from pg_notify
+            let span = info_span!("pg_notification");
+            set_remote_parent(&span, &traceparent);
+            assert_eq!(span.context().span().span_context().trace_id(), trace_id);
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_should_be_exported_to_the_collector() -> Result<()> {
+        // a stand-in for the collector which only counts the export requests
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let app = Router::new().route(
+            "/v1/traces",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = TelemetryConfig {
+            enabled: true,
+            endpoint: format!("http://{}/v1/traces", addr),
+            sample_ratio: 1.0,
+        };
+        let provider = tracer_provider("chat-test", &config)?;
+        provider.tracer("test").in_span("post_message", |_| {});
+        tokio::task::spawn_blocking(move || provider.force_flush()).await?;
+
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
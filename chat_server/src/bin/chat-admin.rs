@@ -0,0 +1,402 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use chat_core::{User, Workspace};
+use chat_server::{random_hex, AppConfig, AppState, AuditContext, CreateChat, CreateUser};
+use clap::{Args, Parser, Subcommand};
+use jwt_simple::prelude::Ed25519KeyPair;
+use serde::Serialize;
+
+/// Administration of a chat server, reads the same config as the server. Changes are
+/// audited like those made through the api, without an ip or request id.
+#[derive(Debug, Parser)]
+#[command(name = "chat-admin", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage workspaces
+    #[command(subcommand)]
+    Workspace(WorkspaceCommand),
+    /// Manage chats
+    #[command(subcommand)]
+    Chat(ChatCommand),
+    /// Generate the Ed25519 key pair used to sign tokens, `auth.sk` and `auth.pk`
+    Keygen {
+        /// write encoding.pem and decoding.pem into this directory instead of printing
+        /// them as config
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Apply the embedded migrations which aren't applied yet
+    Migrate {
+        /// only list the pending migrations, fails if there are any
+        #[arg(long)]
+        check: bool,
+    },
+    /// Import a Slack export archive into the workspace of a user
+    ImportSlack {
+        /// the archive
+        path: PathBuf,
+        /// email of the user running the import, messages of unknown senders are posted
+        /// by them
+        #[arg(long = "as")]
+        admin: String,
+        /// Slack token to download files the export doesn't include
+        #[arg(long, env = "SLACK_TOKEN")]
+        slack_token: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Create a user, and their workspace if it doesn't exist with them as owner
+    Create {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        workspace: String,
+        /// ask for a password, read from stdin if it isn't a terminal. Without one the user
+        /// gets an email to set it
+        #[arg(long)]
+        password: bool,
+    },
+    /// List the users of a workspace
+    List {
+        #[arg(long)]
+        workspace: String,
+    },
+    /// Set the password of a user, or email them a reset link
+    ResetPassword {
+        #[arg(long)]
+        email: String,
+        /// ask for the new password, read from stdin if it isn't a terminal
+        #[arg(long)]
+        password: bool,
+    },
+    /// Move a user to another workspace, they leave the chats of the old one and their
+    /// single chats there are deleted. They have to sign in again to use the new one
+    Move {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        workspace: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum WorkspaceCommand {
+    /// Create an empty workspace, its first user becomes the owner
+    Create {
+        name: String,
+    },
+    List,
+    /// Hand a workspace over to one of its users
+    SetOwner {
+        #[arg(long)]
+        workspace: String,
+        #[arg(long)]
+        email: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ChatCommand {
+    /// List the chats of a workspace
+    List {
+        #[arg(long)]
+        workspace: String,
+    },
+    /// Create a chat, named chats are channels
+    Create {
+        #[arg(long)]
+        workspace: String,
+        #[arg(long)]
+        name: Option<String>,
+        /// emails of the members
+        #[arg(long = "member", required = true)]
+        members: Vec<String>,
+        #[arg(long)]
+        private: bool,
+    },
+    /// Add users to a chat
+    AddMembers(Members),
+    /// Remove users from a chat
+    RemoveMembers(Members),
+}
+
+#[derive(Debug, Args)]
+struct Members {
+    chat_id: u64,
+    /// emails of the members
+    #[arg(long = "member", required = true)]
+    members: Vec<String>,
+    /// email of the user shown as making the change, defaults to the workspace owner
+    #[arg(long = "as")]
+    actor: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    // keys are needed before there is a config to load
+    if let Command::Keygen { out } = &cli.command {
+        return keygen(out.as_ref());
+    }
+
+    let config = AppConfig::load()?;
+    let state = AppState::try_new(config).await?;
+    match cli.command {
+        Command::User(command) => user(&state, command).await,
+        Command::Workspace(command) => workspace(&state, command).await,
+        Command::Chat(command) => chat(&state, command).await,
+        Command::Keygen { .. } => unreachable!("handled above"),
+        Command::Migrate { check: true } => {
+            let pending = state.pending_migrations().await?;
+            if !pending.is_empty() {
+                bail!("{} migrations pending: {:?}", pending.len(), pending);
+            }
+            println!("Database schema is up to date");
+            Ok(())
+        }
+        Command::Migrate { check: false } => {
+            let applied = state.run_migrations().await?;
+            println!("Applied {} migrations {:?}", applied.len(), applied);
+            Ok(())
+        }
+        Command::ImportSlack {
+            path,
+            admin,
+            slack_token,
+        } => {
+            let admin = find_user(&state, &admin).await?;
+            let data = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            // queued like an upload and run right away, so it is recorded the same way
+            let job = state
+                .create_slack_import(&admin, data.into(), slack_token.as_deref())
+                .await?;
+            print_json(&state.run_import_now(job.id as _).await?)
+        }
+    }
+}
+
+async fn user(state: &AppState, command: UserCommand) -> Result<()> {
+    match command {
+        UserCommand::Create {
+            email,
+            name,
+            workspace,
+            password,
+        } => {
+            let input = CreateUser {
+                fullname: name,
+                email,
+                workspace,
+                // never shown to anyone, the user sets their own through the reset email
+                password: if password {
+                    read_password()?
+                } else {
+                    random_hex(32)
+                },
+            };
+            let user = state.create_user(&input, &AuditContext::default()).await?;
+            if !password {
+                state.send_password_reset(&user).await?;
+            }
+            print_json(&user)
+        }
+        UserCommand::List { workspace } => {
+            let ws = find_workspace(state, &workspace).await?;
+            print_json(&state.fetch_chat_users(ws.id as _).await?)
+        }
+        UserCommand::ResetPassword { email, password } => {
+            let user = find_user(state, &email).await?;
+            if password {
+                let password = read_password()?;
+                let ctx = AuditContext::default();
+                state.set_user_password(user.id, &password, &ctx).await?;
+                println!("Password of {} changed", user.email);
+            } else {
+                state.send_password_reset(&user).await?;
+                println!("Password reset sent to {}", user.email);
+            }
+            Ok(())
+        }
+        UserCommand::Move { email, workspace } => {
+            let user = find_user(state, &email).await?;
+            let ws = find_workspace(state, &workspace).await?;
+            if user.ws_id == ws.id {
+                bail!("{} is already in workspace {}", email, workspace);
+            }
+            let ctx = AuditContext::default();
+            print_json(
+                &state
+                    .move_user_to_workspace(user.id, ws.id as _, &ctx)
+                    .await?,
+            )
+        }
+    }
+}
+
+async fn workspace(state: &AppState, command: WorkspaceCommand) -> Result<()> {
+    match command {
+        WorkspaceCommand::Create { name } => {
+            if state.find_workspace_by_name(&name).await?.is_some() {
+                bail!("workspace {} already exists", name);
+            }
+            print_json(
+                &state
+                    .create_workspace(&name, 0, &AuditContext::default())
+                    .await?,
+            )
+        }
+        WorkspaceCommand::List => print_json(&state.list_workspaces().await?),
+        WorkspaceCommand::SetOwner { workspace, email } => {
+            let ws = find_workspace(state, &workspace).await?;
+            let user = find_user(state, &email).await?;
+            if user.ws_id != ws.id {
+                bail!("{} is not in workspace {}", email, workspace);
+            }
+            print_json(
+                &state
+                    .update_workspace_owner(ws.id as _, user.id as _, &AuditContext::default())
+                    .await?,
+            )
+        }
+    }
+}
+
+async fn chat(state: &AppState, command: ChatCommand) -> Result<()> {
+    match command {
+        ChatCommand::List { workspace } => {
+            let ws = find_workspace(state, &workspace).await?;
+            print_json(&state.fetch_chats(ws.id as _).await?)
+        }
+        ChatCommand::Create {
+            workspace,
+            name,
+            members,
+            private,
+        } => {
+            let ws = find_workspace(state, &workspace).await?;
+            let input = CreateChat {
+                name,
+                members: find_members(state, &ws, &members).await?,
+                public: !private,
+            };
+            let ctx = AuditContext::default();
+            print_json(&state.create_chat(input, ws.id as _, None, &ctx).await?)
+        }
+        ChatCommand::AddMembers(args) => {
+            let (actor, members) = chat_members(state, &args).await?;
+            let chat = state
+                .add_chat_members(args.chat_id, actor as _, &members, &AuditContext::default())
+                .await?;
+            print_json(&chat)
+        }
+        ChatCommand::RemoveMembers(args) => {
+            let (actor, members) = chat_members(state, &args).await?;
+            let chat = state
+                .remove_chat_members(args.chat_id, actor as _, &members, &AuditContext::default())
+                .await?;
+            print_json(&chat)
+        }
+    }
+}
+
+/// The actor and the members to add or remove, all of the workspace of the chat
+async fn chat_members(state: &AppState, args: &Members) -> Result<(i64, Vec<i64>)> {
+    let Some(chat) = state.get_chat_by_id(args.chat_id).await? else {
+        bail!("chat {} not found", args.chat_id);
+    };
+    let Some(ws) = state.find_workspace_by_id(chat.ws_id as _).await? else {
+        bail!("workspace {} not found", chat.ws_id);
+    };
+    let actor = match &args.actor {
+        Some(email) => find_user(state, email).await?.id,
+        None if ws.owner_id != 0 => ws.owner_id,
+        None => bail!("workspace {} has no owner, pass --as", ws.name),
+    };
+    let members = find_members(state, &ws, &args.members).await?;
+    Ok((actor, members))
+}
+
+async fn find_members(state: &AppState, ws: &Workspace, emails: &[String]) -> Result<Vec<i64>> {
+    let mut ids = vec![];
+    for email in emails {
+        let user = find_user(state, email).await?;
+        if user.ws_id != ws.id {
+            bail!("{} is not in workspace {}", email, ws.name);
+        }
+        ids.push(user.id);
+    }
+    Ok(ids)
+}
+
+async fn find_user(state: &AppState, email: &str) -> Result<User> {
+    match state.find_user_by_email(email).await? {
+        Some(user) => Ok(user),
+        None => bail!("user {} not found", email),
+    }
+}
+
+async fn find_workspace(state: &AppState, name: &str) -> Result<Workspace> {
+    match state.find_workspace_by_name(name).await? {
+        Some(ws) => Ok(ws),
+        None => bail!("workspace {} not found", name),
+    }
+}
+
+fn keygen(out: Option<&PathBuf>) -> Result<()> {
+    let pair = Ed25519KeyPair::generate();
+    let sk = pair.to_pem();
+    let pk = pair.public_key().to_pem();
+    match out {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(dir.join("encoding.pem"), &sk)?;
+            std::fs::write(dir.join("decoding.pem"), &pk)?;
+            println!("Wrote encoding.pem and decoding.pem to {}", dir.display());
+        }
+        None => {
+            let indent = |pem: &str| {
+                pem.lines()
+                    .map(|line| format!("    {}\n", line))
+                    .collect::<String>()
+            };
+            print!("auth:\n  sk: |\n{}  pk: |\n{}", indent(&sk), indent(&pk));
+        }
+    }
+    Ok(())
+}
+
+/// Prompt for a password without echoing it, or read the first line of stdin if it is piped
+fn read_password() -> Result<String> {
+    let password = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password("Password: ")?
+    } else {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        bail!("password must not be empty");
+    }
+    Ok(password)
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -1,12 +1,38 @@
-use anyhow::bail;
+use chat_core::config::{load_config, ConfigReport, ConfigValidator};
+use chat_core::migrate::MigrateMode;
+use chat_core::telemetry::TelemetryConfig;
+use chat_core::{DecodingKey, EncodingKey};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::{env, path::PathBuf};
+use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub signed_url: SignedUrlConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub unfurl: UnfurlConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub import: ImportConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,23 +46,368 @@ pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
     pub base_dir: PathBuf,
+    /// check or apply the embedded migrations on startup, `--check` forces `check`
+    #[serde(default)]
+    pub migrate: MigrateMode,
+    /// how long in-flight requests may run on after SIGTERM
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    /// serve `GET /metrics` on this port, apart from the api so it can stay off the public
+    /// network
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// serve `GET /metrics` next to the api as well, only if the api isn't public
+    #[serde(default)]
+    pub metrics_on_api: bool,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// max deliveries per webhook within one window
+    pub rate_limit: u32,
+    pub rate_window_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit: 30,
+            rate_window_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// max size of a single file in bytes
+    pub max_file_size: u64,
+    /// max number of files in one upload request
+    pub max_files: usize,
+    /// allowed mime types, `image/*` matches all images, empty allows all types
+    pub allowed_types: Vec<String>,
+    /// denied mime types, checked before allowed_types
+    pub denied_types: Vec<String>,
+    /// default storage quota of a workspace in bytes
+    pub workspace_quota: u64,
+    /// resumable uploads which got no chunk within this are dropped with their chunks
+    pub session_ttl_secs: u64,
+    /// how often abandoned resumable uploads are looked for
+    pub expire_interval_secs: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 10,
+            allowed_types: vec![],
+            denied_types: vec![
+                "application/x-executable".to_string(),
+                "application/x-msdownload".to_string(),
+                "application/vnd.microsoft.portable-executable".to_string(),
+            ],
+            workspace_quota: 1024 * 1024 * 1024,
+            session_ttl_secs: 24 * 3600,
+            expire_interval_secs: 3600,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn is_type_allowed(&self, mime: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => pattern == mime,
+        };
+        !self.denied_types.iter().any(matches)
+            && (self.allowed_types.is_empty() || self.allowed_types.iter().any(matches))
+    }
+}
+
+/// Signed file urls, usable in `<img src>` where no Authorization header can be sent
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignedUrlConfig {
+    /// hmac key, derived from `auth.sk` if not set
+    pub secret: Option<String>,
+    /// how long a signed url stays valid
+    pub ttl_secs: u64,
+}
+
+impl Default for SignedUrlConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            ttl_secs: 300,
+        }
+    }
+}
+
+impl std::fmt::Debug for SignedUrlConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedUrlConfig")
+            .field("secret", &self.secret.as_ref().map(|_| "***"))
+            .field("ttl_secs", &self.ttl_secs)
+            .finish()
+    }
+}
+
+/// Garbage collection of uploaded files no message references
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GcConfig {
+    /// run the collector in the background
+    pub enabled: bool,
+    /// only log what would be deleted
+    pub dry_run: bool,
+    pub interval_secs: u64,
+    /// files younger than this are kept, so users can upload then send the message
+    pub grace_secs: u64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dry_run: false,
+            interval_secs: 3600,
+            grace_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Link previews of the urls in messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnfurlConfig {
+    /// fetch the urls users post, off unless the server may reach the internet
+    pub enabled: bool,
+    /// timeout of a single fetch
+    pub timeout_secs: u64,
+    /// max urls previewed in one message
+    pub max_links: usize,
+    /// only this many bytes of a page are read
+    pub max_body_size: usize,
+    /// previews of an url are reused for this long
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for UnfurlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 5,
+            max_links: 3,
+            max_body_size: 512 * 1024,
+            cache_ttl_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Purge of messages older than the retention of their chat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// run the purge in the background
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 3600,
+        }
+    }
+}
+
+/// Outgoing emails, only logged if no endpoint is set
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    /// http endpoint of a mail delivery service, emails are posted to it as json
+    pub endpoint: Option<String>,
+    pub api_key: Option<String>,
+    pub from: String,
+    /// page of the web app where users set a new password, the token is appended
+    pub reset_url: String,
+    /// how long a password reset link stays valid
+    pub reset_ttl_secs: u64,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            api_key: None,
+            from: "noreply@localhost".to_string(),
+            reset_url: "http://localhost:6688/reset-password".to_string(),
+            reset_ttl_secs: 3 * 24 * 3600,
+        }
+    }
+}
+
+impl std::fmt::Debug for MailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MailConfig")
+            .field("endpoint", &self.endpoint)
+            .field("api_key", &self.api_key.as_ref().map(|_| "***"))
+            .field("from", &self.from)
+            .field("reset_url", &self.reset_url)
+            .field("reset_ttl_secs", &self.reset_ttl_secs)
+            .finish()
+    }
+}
+
+/// Exports of chat history into zip archives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// archives are deleted this long after the export finished
+    pub archive_ttl_secs: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            archive_ttl_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Imports of chat history from other services
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportConfig {
+    /// max size of an uploaded export archive
+    pub max_archive_size: usize,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            max_archive_size: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// Audit log of security relevant actions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// take the client ip from the last hop of `x-forwarded-for`, only if a proxy in front
+    /// appends to it
+    pub trust_proxy: bool,
+}
+
+/// Where uploaded files are stored
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// store files under `server.base_dir`
+    #[default]
+    Local,
+    /// store files in an S3 compatible bucket, e.g. AWS S3 or MinIO
+    S3(S3Config),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    /// e.g. https://s3.us-east-1.amazonaws.com or http://localhost:9000
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .finish()
+    }
 }
 
 impl AppConfig {
     pub fn load() -> anyhow::Result<AppConfig> {
-        // read from ./chat.yml or /etc/config/chat.yml or from env CHAT_CONFIG
-        let ret = match (
-            File::open("chat.yml"),
-            File::open("/etc/config/chat.yml"),
-            env::var("CHAT_CONFIG"),
-        ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader)?,
-            (_, Ok(reader), _) => serde_yaml::from_reader(reader)?,
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?)?,
-            _ => bail!("Config file not found"),
-        };
+        Ok(Self::load_with_report()?.0)
+    }
 
-        Ok(ret)
+    /// Defaults, then the first of `CHAT_CONFIG`, ./chat.yml or /etc/config/chat.yml,
+    /// then env vars like `CHAT_SERVER__PORT`. The report says where each value came from.
+    pub fn load_with_report() -> anyhow::Result<(AppConfig, ConfigReport)> {
+        let (config, report) = load_config::<AppConfig>("chat")?;
+        config.validate()?;
+        Ok((config, report))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut v = ConfigValidator::new();
+        let db_url = &self.server.db_url;
+        v.positive(self.server.port as _, "server.port")
+            .positive(self.server.shutdown_timeout_secs, "server.shutdown_timeout_secs")
+            .check(
+                db_url.starts_with("postgres://") || db_url.starts_with("postgresql://"),
+                "server.db_url",
+                "must be a postgres:// url",
+            )
+            .check(
+                self.server.metrics_port != Some(self.server.port),
+                "server.metrics_port",
+                "must differ from server.port",
+            );
+        if let Err(e) = EncodingKey::load(&self.auth.sk) {
+            v.check(false, "auth.sk", format!("not an Ed25519 private key: {}", e));
+        }
+        if let Err(e) = DecodingKey::load(&self.auth.pk) {
+            v.check(false, "auth.pk", format!("not an Ed25519 public key: {}", e));
+        }
+        v.positive(self.webhook.rate_limit as _, "webhook.rate_limit")
+            .positive(self.webhook.rate_window_secs, "webhook.rate_window_secs")
+            .positive(self.upload.max_file_size, "upload.max_file_size")
+            .positive(self.upload.max_files as _, "upload.max_files")
+            .positive(self.upload.session_ttl_secs, "upload.session_ttl_secs")
+            .positive(self.upload.expire_interval_secs, "upload.expire_interval_secs")
+            .positive(self.signed_url.ttl_secs, "signed_url.ttl_secs")
+            .positive(self.gc.interval_secs, "gc.interval_secs")
+            .positive(self.unfurl.timeout_secs, "unfurl.timeout_secs")
+            .positive(self.unfurl.max_body_size as _, "unfurl.max_body_size")
+            .positive(self.retention.interval_secs, "retention.interval_secs")
+            .http_url(&self.mail.reset_url, "mail.reset_url")
+            .positive(self.mail.reset_ttl_secs, "mail.reset_ttl_secs")
+            .positive(self.export.archive_ttl_secs, "export.archive_ttl_secs")
+            .positive(self.import.max_archive_size as _, "import.max_archive_size");
+        if let Some(endpoint) = &self.mail.endpoint {
+            v.http_url(endpoint, "mail.endpoint");
+        }
+        if self.telemetry.enabled {
+            v.http_url(&self.telemetry.endpoint, "telemetry.endpoint");
+        }
+        v.check(
+            (0.0..=1.0).contains(&self.telemetry.sample_ratio),
+            "telemetry.sample_ratio",
+            "must be between 0 and 1",
+        );
+        if let StorageConfig::S3(s3) = &self.storage {
+            v.http_url(&s3.endpoint, "storage.endpoint")
+                .check(!s3.bucket.is_empty(), "storage.bucket", "must not be empty");
+        }
+        v.finish()
     }
 }
 
@@ -51,4 +422,32 @@ mod tests {
         assert!(app_config.is_ok());
         println!("{:#?}", app_config.unwrap());
     }
+
+    #[test]
+    fn validate_should_report_every_problem() -> anyhow::Result<()> {
+        let mut config = AppConfig::load()?;
+        config.server.db_url = "mysql://localhost/chat".to_string();
+        config.webhook.rate_limit = 0;
+        config.mail.endpoint = Some("smtp.acme.org".to_string());
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("server.db_url: must be a postgres:// url"));
+        assert!(err.contains("webhook.rate_limit: must be greater than 0"));
+        assert!(err.contains("mail.endpoint: \"smtp.acme.org\" is not an http(s) url"));
+        Ok(())
+    }
+
+    #[test]
+    fn upload_type_check_should_work() {
+        let config = UploadConfig {
+            allowed_types: vec!["image/*".to_string(), "application/pdf".to_string()],
+            denied_types: vec!["image/svg+xml".to_string()],
+            ..Default::default()
+        };
+        assert!(config.is_type_allowed("image/png"));
+        assert!(config.is_type_allowed("application/pdf"));
+        assert!(!config.is_type_allowed("image/svg+xml"));
+        assert!(!config.is_type_allowed("text/plain"));
+        assert!(UploadConfig::default().is_type_allowed("text/plain"));
+    }
 }
@@ -24,6 +24,8 @@ pub enum AppError {
     HttpHeaderError(#[from] axum::http::header::InvalidHeaderValue),
     #[error("create chat error: {0}")]
     CreateChatError(String),
+    #[error("update chat error: {0}")]
+    UpdateChatError(String),
     #[error("Not found: {0}")]
     NotFound(String),
     #[error("io error: {0}")]
@@ -34,6 +36,42 @@ pub enum AppError {
     ChatFileError(String),
     #[error("{0}")]
     AnyhowError(#[from] anyhow::Error),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("webhook error: {0}")]
+    WebhookError(String),
+    #[error("{0}")]
+    CommandError(String),
+    #[error("create command error: {0}")]
+    CreateCommandError(String),
+    #[error("storage error: {0}")]
+    StorageError(String),
+    #[error("upload error: {0}")]
+    UploadError(String),
+    #[error("file too large: {0}")]
+    FileTooLarge(String),
+    #[error("unsupported file type: {0}")]
+    UnsupportedFileType(String),
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("upload conflict: {0}")]
+    UploadConflict(String),
+    #[error("schedule message error: {0}")]
+    ScheduleMessageError(String),
+    #[error("retention error: {0}")]
+    RetentionError(String),
+    #[error("export error: {0}")]
+    ExportError(String),
+    #[error("mail error: {0}")]
+    MailError(String),
+    #[error("import error: {0}")]
+    ImportError(String),
+    #[error("password reset error: {0}")]
+    PasswordResetError(String),
+    #[error("migrate error: {0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
 }
 
 #[derive(Debug, serde::Serialize, ToSchema,serde::Deserialize)]
@@ -59,11 +97,30 @@ impl IntoResponse for AppError {
             Self::EmailAlreadyExists(_) => StatusCode::CONFLICT,
             Self::CustomError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             &Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             &Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
             &Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
             &Self::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            &Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            &Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            &Self::WebhookError(_) => StatusCode::BAD_REQUEST,
+            &Self::CommandError(_) => StatusCode::BAD_REQUEST,
+            &Self::CreateCommandError(_) => StatusCode::BAD_REQUEST,
+            &Self::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            &Self::UploadError(_) => StatusCode::BAD_REQUEST,
+            &Self::FileTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            &Self::UnsupportedFileType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            &Self::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            &Self::UploadConflict(_) => StatusCode::CONFLICT,
+            &Self::ScheduleMessageError(_) => StatusCode::BAD_REQUEST,
+            &Self::RetentionError(_) => StatusCode::BAD_REQUEST,
+            &Self::ExportError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            &Self::MailError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            &Self::ImportError(_) => StatusCode::BAD_REQUEST,
+            &Self::PasswordResetError(_) => StatusCode::BAD_REQUEST,
+            &Self::MigrateError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
     }
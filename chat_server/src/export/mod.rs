@@ -0,0 +1,248 @@
+mod render;
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Seek, Write},
+};
+
+use chat_core::ContentType;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{error::AppError, models::ExportFormat};
+
+use render::HtmlPage;
+
+/// A message as written to an export archive
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ArchiveMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub chat_name: Option<String>,
+    pub sender_id: i64,
+    pub sender_name: String,
+    pub content_type: ContentType,
+    pub content: String,
+    /// plain text projection of the content
+    pub text: String,
+    pub files: Vec<ArchiveFile>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ArchiveFile {
+    pub url: String,
+    pub filename: String,
+    pub content_type: String,
+    /// where the blob is in the archive, None if it's not included
+    pub path: Option<String>,
+}
+
+/// Builds a zip archive with the messages in one document and the attached blobs under
+/// `files/`. Messages are added in pages and must come ordered by chat. Only the entries
+/// are written to `out`, the document is spooled to a temp file meanwhile and copied in
+/// at the end, so neither is held in memory.
+pub struct ArchiveWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    doc: Document,
+    files: HashSet<String>,
+}
+
+enum Document {
+    Json { out: BufWriter<File>, empty: bool },
+    Csv(csv::Writer<File>),
+    Html { page: HtmlPage, out: BufWriter<File> },
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(format: ExportFormat, title: &str, out: W) -> Result<Self, AppError> {
+        let spool = tempfile::tempfile()?;
+        let doc = match format {
+            ExportFormat::Json => {
+                let mut out = BufWriter::new(spool);
+                out.write_all(b"[")?;
+                Document::Json { out, empty: true }
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(spool);
+                writer
+                    .write_record(render::CSV_HEADER)
+                    .map_err(export_error)?;
+                Document::Csv(writer)
+            }
+            ExportFormat::Html => Document::Html {
+                page: HtmlPage::new(title),
+                out: BufWriter::new(spool),
+            },
+        };
+        Ok(Self {
+            zip: ZipWriter::new(out),
+            doc,
+            files: HashSet::new(),
+        })
+    }
+
+    pub fn add_messages(&mut self, messages: &[ArchiveMessage]) -> Result<(), AppError> {
+        match &mut self.doc {
+            Document::Json { out, empty } => {
+                for message in messages {
+                    if !*empty {
+                        out.write_all(b",")?;
+                    }
+                    *empty = false;
+                    serde_json::to_writer(&mut *out, message).map_err(export_error)?;
+                }
+            }
+            Document::Csv(writer) => {
+                for message in messages {
+                    writer
+                        .write_record(render::csv_record(message))
+                        .map_err(export_error)?;
+                }
+            }
+            Document::Html { page, out } => {
+                for message in messages {
+                    page.push(message);
+                }
+                out.write_all(page.take().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_file(&self, path: &str) -> bool {
+        self.files.contains(path)
+    }
+
+    /// Blobs are stored as is, most of them are compressed already
+    pub fn add_file(&mut self, path: &str, data: &[u8]) -> Result<(), AppError> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        self.zip.start_file(path, options).map_err(export_error)?;
+        self.zip.write_all(data)?;
+        self.files.insert(path.to_string());
+        Ok(())
+    }
+
+    /// Copy the document into the archive and return `out` with the complete archive
+    pub fn finish(mut self) -> Result<W, AppError> {
+        let (name, mut spool) = match self.doc {
+            Document::Json { mut out, .. } => {
+                out.write_all(b"]")?;
+                ("messages.json", out.into_inner().map_err(|e| export_error(e.error()))?)
+            }
+            Document::Csv(writer) => (
+                "messages.csv",
+                writer.into_inner().map_err(|e| export_error(e.error()))?,
+            ),
+            Document::Html { page, mut out } => {
+                out.write_all(page.finish().as_bytes())?;
+                ("index.html", out.into_inner().map_err(|e| export_error(e.error()))?)
+            }
+        };
+        spool.rewind()?;
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(true);
+        self.zip.start_file(name, options).map_err(export_error)?;
+        io::copy(&mut spool, &mut self.zip)?;
+        self.zip.finish().map_err(export_error)
+    }
+}
+
+fn export_error(e: impl std::fmt::Display) -> AppError {
+    AppError::ExportError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    fn message(id: i64, text: &str, path: Option<&str>) -> ArchiveMessage {
+        ArchiveMessage {
+            id,
+            chat_id: 1,
+            chat_name: Some("general".to_string()),
+            sender_id: 1,
+            sender_name: "Tyr Chen".to_string(),
+            content_type: ContentType::Text,
+            content: text.to_string(),
+            text: text.to_string(),
+            files: path
+                .map(|path| ArchiveFile {
+                    url: "/files/1/abc/def/0123.png".to_string(),
+                    filename: "cat.png".to_string(),
+                    content_type: "image/png".to_string(),
+                    path: Some(path.to_string()),
+                })
+                .into_iter()
+                .collect(),
+            created_at: "2025-01-01T08:00:00Z".parse().unwrap(),
+        }
+    }
+
+    fn new_writer(format: ExportFormat) -> Result<ArchiveWriter<Cursor<Vec<u8>>>> {
+        Ok(ArchiveWriter::new(format, "general", Cursor::new(vec![]))?)
+    }
+
+    fn read(data: Cursor<Vec<u8>>, name: &str) -> Result<String> {
+        let mut archive = ZipArchive::new(data)?;
+        let mut s = String::new();
+        archive.by_name(name)?.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    #[test]
+    fn json_archive_should_work() -> Result<()> {
+        let mut writer = new_writer(ExportFormat::Json)?;
+        writer.add_messages(&[message(1, "hi", None)])?;
+        writer.add_file("files/1/abc/def/0123.png", b"png")?;
+        writer.add_messages(&[message(2, "there", Some("files/1/abc/def/0123.png"))])?;
+        assert!(writer.has_file("files/1/abc/def/0123.png"));
+        let data = writer.finish()?;
+
+        let messages: Vec<serde_json::Value> =
+            serde_json::from_str(&read(data.clone(), "messages.json")?)?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["files"][0]["filename"], "cat.png");
+        assert_eq!(read(data, "files/1/abc/def/0123.png")?, "png");
+        Ok(())
+    }
+
+    #[test]
+    fn csv_archive_should_work() -> Result<()> {
+        let mut writer = new_writer(ExportFormat::Csv)?;
+        writer.add_messages(&[message(1, "hello, \"world\"", None)])?;
+        let csv = read(writer.finish()?, "messages.csv")?;
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,chat_id,chat,sender_id,sender,created_at,content_type,text,files")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(r#"1,1,general,1,Tyr Chen,2025-01-01T08:00:00+00:00,text,"hello, ""world""","#)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn html_archive_should_escape() -> Result<()> {
+        let mut writer = new_writer(ExportFormat::Html)?;
+        writer.add_messages(&[
+            message(1, "<script>alert(1)</script>", None),
+            message(2, "look", Some("files/1/abc/def/0123.png")),
+        ])?;
+        let html = read(writer.finish()?, "index.html")?;
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains(r#"<img src="files/1/abc/def/0123.png" alt="cat.png">"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+use super::ArchiveMessage;
+
+pub(super) const CSV_HEADER: [&str; 9] = [
+    "id",
+    "chat_id",
+    "chat",
+    "sender_id",
+    "sender",
+    "created_at",
+    "content_type",
+    "text",
+    "files",
+];
+
+const STYLE: &str =
+    "body{font-family:sans-serif;max-width:48em;margin:auto;padding:1em;color:#222}\
+h2{border-bottom:1px solid #ddd;padding-bottom:.3em}\
+.msg{margin:.8em 0}.meta{color:#888;font-size:.85em}\
+.text{white-space:pre-wrap}img{max-width:100%;display:block;margin-top:.3em}";
+
+pub(super) fn csv_record(message: &ArchiveMessage) -> [String; 9] {
+    let files = message
+        .files
+        .iter()
+        .map(|f| f.path.clone().unwrap_or_else(|| f.url.clone()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let content_type = serde_json::to_value(message.content_type)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default();
+    [
+        message.id.to_string(),
+        message.chat_id.to_string(),
+        message.chat_name.clone().unwrap_or_default(),
+        message.sender_id.to_string(),
+        message.sender_name.clone(),
+        message.created_at.to_rfc3339(),
+        content_type,
+        message.text.clone(),
+        files,
+    ]
+}
+
+/// A single html page with inline styles, attachments link to their blobs in the archive
+pub(super) struct HtmlPage {
+    buf: String,
+    chat_id: Option<i64>,
+}
+
+impl HtmlPage {
+    pub(super) fn new(title: &str) -> Self {
+        let title = escape(title);
+        let buf = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+             <style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            title, STYLE, title
+        );
+        Self { buf, chat_id: None }
+    }
+
+    pub(super) fn push(&mut self, message: &ArchiveMessage) {
+        if self.chat_id != Some(message.chat_id) {
+            if self.chat_id.is_some() {
+                self.buf.push_str("</section>\n");
+            }
+            self.chat_id = Some(message.chat_id);
+            let name = match &message.chat_name {
+                Some(name) => escape(name),
+                None => format!("Chat {}", message.chat_id),
+            };
+            let _ = writeln!(self.buf, "<section>\n<h2>{}</h2>", name);
+        }
+
+        let _ = write!(
+            self.buf,
+            "<div class=\"msg\"><div class=\"meta\">{} &middot; {}</div><div class=\"text\">{}</div>",
+            escape(&message.sender_name),
+            message.created_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            escape(&message.text)
+        );
+        for file in &message.files {
+            let name = escape(&file.filename);
+            match &file.path {
+                Some(path) if file.content_type.starts_with("image/") => {
+                    let _ = write!(self.buf, "<img src=\"{}\" alt=\"{}\">", escape(path), name);
+                }
+                Some(path) => {
+                    let _ = write!(
+                        self.buf,
+                        "<div><a href=\"{}\">{}</a></div>",
+                        escape(path),
+                        name
+                    );
+                }
+                None => {
+                    let _ = write!(self.buf, "<div>{}</div>", name);
+                }
+            }
+        }
+        self.buf.push_str("</div>\n");
+    }
+
+    /// The html of the messages pushed since the last take
+    pub(super) fn take(&mut self) -> String {
+        std::mem::take(&mut self.buf)
+    }
+
+    pub(super) fn finish(mut self) -> String {
+        if self.chat_id.is_some() {
+            self.buf.push_str("</section>\n");
+        }
+        self.buf.push_str("</body>\n</html>\n");
+        self.buf
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,41 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{error::AppError, models::ListAuditEvents, AppState};
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/audit",
+    params(
+        ListAuditEvents
+    ),
+    responses(
+        (status = 200, description = "Audit events of the workspace, newest first", body = Vec<AuditEvent>),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Audit log of the workspace for security reviews, workspace owners only
+pub(crate) async fn list_audit_events_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListAuditEvents>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state
+        .is_workspace_owner(user.ws_id as _, user.id as _)
+        .await?
+    {
+        return Err(AppError::PermissionDenied(
+            "only workspace owners can read the audit log".to_string(),
+        ));
+    }
+    let events = state.list_audit_events(user.ws_id as _, input).await?;
+
+    Ok(Json(events))
+}
@@ -2,7 +2,7 @@ use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 
 use crate::{
     error::{AppError, ErrorOutput},
-    models::{CreateUser, SigninUser},
+    models::{AuditAction, AuditContext, CreateUser, NewAuditEvent, ResetPassword, SigninUser},
     AppState,
 };
 use utoipa::ToSchema;
@@ -21,16 +21,27 @@ pub struct AuthOutput {
 )]
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.verify_user(&input).await?;
 
     match user {
         Some(user) => {
+            let event = NewAuditEvent::new(&user, AuditAction::UserSignin).target("user", user.id);
+            state.record_audit(&ctx, event).await?;
             let token = state.ek.sign(user)?;
             Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
         }
         None => {
+            // failures for a known email show up in the log of its workspace
+            let ws_id = state
+                .find_user_by_email(&input.email)
+                .await?
+                .map(|user| user.ws_id);
+            let event = NewAuditEvent::anonymous(ws_id, AuditAction::UserSigninFailed)
+                .target("email", &input.email);
+            state.record_audit(&ctx, event).await?;
             let body = Json(ErrorOutput::new("Invalid email or password"));
             Ok((StatusCode::FORBIDDEN, body).into_response())
         }
@@ -50,15 +61,35 @@ pub(crate) async fn signin_handler(
 /// - If the workspace doesn't exist, it will create one.
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = state.create_user(&input).await?;
+    let user = state.create_user(&input, &ctx).await?;
     let token = state.ek.sign(user)?;
     let body = Json(AuthOutput { token });
 
     Ok((StatusCode::CREATED, body))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 400, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+/// Set a new password with the token of a reset email
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(input, &ctx).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -69,7 +100,7 @@ mod tests {
     async fn signup_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateUser::new("none", "Tyr Chen", "tchen@acme.org", "Hunter42");
-        let ret = signup_handler(State(state), Json(input))
+        let ret = signup_handler(State(state), AuditContext::default(), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::CREATED);
@@ -82,8 +113,8 @@ mod tests {
     async fn signup_duplicate_user_should_409() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateUser::new("none", "Tyr Chen", "tchen@acme.org", "Hunter42");
-        signup_handler(State(state.clone()), Json(input.clone())).await?;
-        let ret = signup_handler(State(state.clone()), Json(input.clone()))
+        signup_handler(State(state.clone()), AuditContext::default(), Json(input.clone())).await?;
+        let ret = signup_handler(State(state.clone()), AuditContext::default(), Json(input.clone()))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::CONFLICT);
@@ -100,9 +131,9 @@ mod tests {
         let password = "Hunter42";
         let ws = "none";
         let user = CreateUser::new(ws, name, email, password);
-        state.create_user(&user).await?;
+        state.create_user(&user, &AuditContext::default()).await?;
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), AuditContext::default(), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
@@ -117,13 +148,17 @@ mod tests {
         let email = "alice@acme.org";
         let password = "Hunter42";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state.clone()), AuditContext::default(), Json(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::FORBIDDEN);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: ErrorOutput = serde_json::from_slice(&body)?;
         assert_eq!(ret.error, "Invalid email or password");
+
+        let events = state.list_audit_events(1, Default::default()).await?;
+        assert_eq!(events[0].action, AuditAction::UserSigninFailed);
+        assert_eq!(events[0].target_id.as_deref(), Some(email));
         Ok(())
     }
 }
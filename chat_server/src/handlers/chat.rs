@@ -1,6 +1,6 @@
 use crate::{
     error::AppError,
-    models::{CreateChat},
+    models::{AuditContext, CreateChat, UpdateChat},
     AppState,
 };
 use axum::{
@@ -43,15 +43,41 @@ pub(crate) async fn list_chat_handler(
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    ctx: AuditContext,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.create_chat(input, user.ws_id as _).await?;
+    let chat = state
+        .create_chat(input, user.ws_id as _, Some(user.id as _), &ctx)
+        .await?;
 
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    "update chat"
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat updated, each change is recorded as a system message", body = Chat),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.update_chat(id, user.id as _, input, &ctx).await?;
+
+    Ok(Json(chat))
 }
 
 #[utoipa::path(
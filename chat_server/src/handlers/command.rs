@@ -0,0 +1,68 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{AuditContext, CreateCommand},
+    AppState,
+};
+
+#[utoipa::path(
+    post,
+    path = "/api/commands",
+    responses(
+        (status = 201, description = "Command registered", body = Command),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Register a custom slash command, the command is forwarded to `url` and the response posted back.
+pub(crate) async fn create_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(input): Json<CreateCommand>,
+) -> Result<impl IntoResponse, AppError> {
+    let command = state.create_command(input, &user, &ctx).await?;
+
+    Ok((StatusCode::CREATED, Json(command)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/commands",
+    responses(
+        (status = 200, description = "List of custom commands", body = Vec<Command>),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let commands = state.list_commands(&user).await?;
+
+    Ok(Json(commands))
+}
+
+pub(crate) async fn delete_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_command(id, &user, &ctx).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
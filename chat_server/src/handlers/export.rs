@@ -0,0 +1,80 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{AuditContext, CreateExport},
+    AppState,
+};
+
+#[utoipa::path(
+    post,
+    path = "/api/exports",
+    responses(
+        (status = 202, description = "Export queued, poll it for progress", body = ExportJob),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Export a chat or the whole workspace into a zip archive
+pub(crate) async fn create_export_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(input): Json<CreateExport>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state.create_export(input, &user, &ctx).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/exports",
+    responses(
+        (status = 200, description = "Exports of the user", body = Vec<ExportJob>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_exports_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let jobs = state.list_exports(user.id as _).await?;
+
+    Ok(Json(jobs))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/exports/{id}",
+    params(
+        ("id" = u64, Path, description = "Export id")
+    ),
+    responses(
+        (status = 200, description = "Export with its progress, download `url` from /api once done, it takes a token or a signed url like any file", body = ExportJob),
+        (status = 404, description = "Export not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_export_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state.get_export(id, user.id as _).await?;
+
+    Ok(Json(job))
+}
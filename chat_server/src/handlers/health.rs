@@ -0,0 +1,54 @@
+use axum::{extract::State, response::IntoResponse};
+use chat_core::health::{check_pool, Readiness};
+
+use crate::AppState;
+
+/// The process is up, says nothing about its dependencies
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "Server is alive"),
+    )
+)]
+pub(crate) async fn healthz_handler() -> impl IntoResponse {
+    "ok"
+}
+
+/// Postgres answers and the server isn't shutting down
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Server can take traffic", body = Readiness),
+        (status = 503, description = "A check failed", body = Readiness),
+    )
+)]
+pub(crate) async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let shutdown = match state.shutdown.is_triggered() {
+        true => Err("shutting down".to_string()),
+        false => Ok(()),
+    };
+    Readiness::new()
+        .check("db", check_pool(&state.pool).await)
+        .check("shutdown", shutdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn readyz_should_fail_when_shutting_down() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = readyz_handler(State(state.clone())).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        state.shutdown().trigger();
+        let ret = readyz_handler(State(state)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+}
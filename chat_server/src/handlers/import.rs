@@ -0,0 +1,76 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{error::AppError, AppState};
+
+#[utoipa::path(
+    post,
+    path = "/api/workspace/import/slack",
+    request_body(content = Vec<u8>, description = "Slack export zip", content_type = "application/zip"),
+    params(
+        ("x-slack-token" = Option<String>, Header, description = "Slack token to download files the export doesn't include")
+    ),
+    responses(
+        (status = 202, description = "Import queued, poll it for its report", body = ImportJob),
+        (status = 400, description = "Invalid archive", body = ErrorOutput),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Import a Slack export archive into the workspace, workspace owners only.
+///
+/// Importing the same archive again skips users, chats and messages imported before.
+pub(crate) async fn import_slack_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    if !state
+        .is_workspace_owner(user.ws_id as _, user.id as _)
+        .await?
+    {
+        return Err(AppError::PermissionDenied(
+            "only workspace owners can import".to_string(),
+        ));
+    }
+    let token = headers
+        .get("x-slack-token")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty());
+    let job = state.create_slack_import(&user, body, token).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/imports/{id}",
+    params(
+        ("id" = u64, Path, description = "Import id")
+    ),
+    responses(
+        (status = 200, description = "Import with its report once done", body = ImportJob),
+        (status = 404, description = "Import not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_import_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state.get_import(id, user.id as _).await?;
+
+    Ok(Json(job))
+}
@@ -1,28 +1,62 @@
-use crate::models::ChatFile;
 use crate::{
     error::AppError,
-    models::{CreateMessage, ListMessages},
+    models::{
+        Attachment, AuditAction, AuditContext, ChatFile, CreateMessage, ListMessages,
+        MessageOutput, NewAuditEvent, SignFile,
+    },
+    utils::{content_disposition, etag_matches, parse_range, ByteRange, ThumbnailSize},
     AppState,
 };
 use axum::{
+    body::Body,
     extract::{Multipart, Path, Query, State},
-    http::{Extensions, HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::{header, Extensions, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Extension, Json,
 };
 use chat_core::User;
-use tokio::fs;
-use tracing::{info, warn};
+use serde::Deserialize;
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
 
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 201, description = "Message created", body = Message),
+        (status = 200, description = "Ephemeral reply of a slash command, only the caller sees it", body = EphemeralReply),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Send a message to the chat, messages starting with `/` run a slash command.
 pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    ctx: AuditContext,
     Path(id): Path<u64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(input, id, user.id as _).await?;
+    let output = state.create_message(input, id, user.id as _, &ctx).await?;
 
-    Ok((StatusCode::CREATED,Json(msg)))
+    Ok(output)
+}
+
+impl IntoResponse for MessageOutput {
+    fn into_response(self) -> Response {
+        match self {
+            MessageOutput::Message(msg) => (StatusCode::CREATED, Json(msg)).into_response(),
+            MessageOutput::Ephemeral(reply) => (StatusCode::OK, Json(reply)).into_response(),
+        }
+    }
 }
 
 #[utoipa::path(
@@ -33,7 +67,7 @@ pub(crate) async fn send_message_handler(
         ListMessages
     ),
     responses(
-        (status = 200, description = "List of messages", body = Vec<Message>),
+        (status = 200, description = "List of messages, files are expanded into attachments and system messages carry their payload in `event`", body = Vec<MessageDetail>),
         (status = 400, description = "Invalid input", body = ErrorOutput),
     ),
     security(
@@ -50,57 +84,228 @@ pub(crate) async fn list_message_handler(
     Ok(Json(messages))
 }
 
+#[derive(Debug, Deserialize)]
+pub(crate) struct FileQuery {
+    /// Serve a thumbnail instead of the original image
+    size: Option<ThumbnailSize>,
+}
+
+/// Stream a file, supports single byte ranges and conditional requests. The content of a
+/// url never changes, so the hash is a strong ETag and caches may keep it forever.
 pub(crate) async fn file_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
-        return Err(AppError::NotFound(
-            "File doesn't exist or you don't have permission to access it".to_string(),
-        ));
+    Query(query): Query<FileQuery>,
+    req_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    // every denial looks the same, so the response doesn't tell whether a file exists
+    let url = format!("/files/{}/{}", ws_id, path);
+    let Ok(file) = ChatFile::from_str(&url) else {
+        return Err(file_not_found());
+    };
+    if user.ws_id != ws_id || !state.can_access_file(&url, user.id as _).await? {
+        return Err(file_not_found());
     }
+    let mut found = None;
+    // images smaller than the requested size have no thumbnail, serve the original
+    if let Some(size) = query.size {
+        if let Some(thumb) = file.thumbnail_path(size) {
+            if let Some(meta) = state.store.stat(&thumb).await? {
+                let etag = format!("\"{}-{}\"", file.hash, size.as_str());
+                found = Some((thumb, meta, etag));
+            }
+        }
+    }
+    let (key, meta, etag) = match found {
+        Some(found) => found,
+        None => {
+            let key = file.hash_to_path();
+            let Some(meta) = state.store.stat(&key).await? else {
+                return Err(file_not_found());
+            };
+            (key, meta, format!("\"{}\"", file.hash))
+        }
+    };
 
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
+    let attachment = state
+        .get_attachments(&[file.url()], user.id)
+        .await?
+        .remove(0);
+    let mime = mime_guess::from_path(&key).first_or_octet_stream();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, etag.parse()?);
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=31536000, immutable"),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition(&attachment.filename).parse()?,
+    );
+    if let Some(modified) = meta.modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(modified).parse()?,
+        );
+    }
 
-    if !path.exists() {
-        return Err(AppError::NotFound("File doesn't exist".to_string()));
+    if is_not_modified(&req_headers, &etag, meta.modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
     }
 
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-    let body = fs::read(path).await?;
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", mime.to_string().parse().unwrap());
+    // If-Range: only honour the range if the client still has the same content
+    let range = match req_headers.get(header::IF_RANGE) {
+        Some(v) if v.to_str().ok() != Some(etag.as_str()) => None,
+        _ => req_headers.get(header::RANGE),
+    };
+    let range = range
+        .and_then(|v| v.to_str().ok())
+        .map(|v| parse_range(v, meta.size))
+        .unwrap_or(ByteRange::Full);
 
-    Ok((headers, body))
+    headers.insert(header::CONTENT_TYPE, mime.to_string().parse()?);
+    let (status, range) = match range {
+        ByteRange::Full => {
+            headers.insert(header::CONTENT_LENGTH, meta.size.into());
+            (StatusCode::OK, None)
+        }
+        ByteRange::Partial(start, end) => {
+            headers.insert(header::CONTENT_LENGTH, (end - start + 1).into());
+            headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, meta.size).parse()?,
+            );
+            (StatusCode::PARTIAL_CONTENT, Some((start, end)))
+        }
+        ByteRange::Unsatisfiable => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{}", meta.size).parse()?,
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+    };
+
+    let Some(stream) = state.store.stream(&key, range).await? else {
+        return Err(file_not_found());
+    };
+    Ok((status, headers, Body::from_stream(stream)).into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/files/sign",
+    request_body = SignFile,
+    responses(
+        (status = 200, description = "Short lived download url, usable without a token", body = SignedFileUrl),
+        (status = 404, description = "File doesn't exist or no permission", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Sign a file url so it can be used in `<img src>` without leaking the session token
+pub(crate) async fn sign_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(input): Json<SignFile>,
+) -> Result<impl IntoResponse, AppError> {
+    let signed = state.sign_file_url(&input.url, user.id as _).await?;
+    let event = NewAuditEvent::new(&user, AuditAction::FileUrlSigned).target("file", &input.url);
+    state.record_audit(&ctx, event).await?;
+    Ok(Json(signed))
+}
+
+fn file_not_found() -> AppError {
+    AppError::NotFound(
+        "File doesn't exist or you don't have permission to access it".to_string(),
+    )
+}
+
+/// If-None-Match wins over If-Modified-Since, dates only have a precision of seconds
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(v) = headers.get(header::IF_NONE_MATCH) {
+        return v.to_str().map(|v| etag_matches(v, etag)).unwrap_or_default();
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => {
+            let secs = |t: SystemTime| {
+                t.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            };
+            secs(modified) <= secs(since)
+        }
+        _ => false,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/upload",
+    responses(
+        (status = 200, description = "Uploaded files", body = Vec<Attachment>),
+        (status = 400, description = "Invalid upload", body = ErrorOutput),
+        (status = 413, description = "File too large or workspace out of storage", body = ErrorOutput),
+        (status = 415, description = "File type not allowed", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    ctx: AuditContext,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
+    let limits = &state.config.upload;
     let mut files = vec![];
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let filename = field.file_name().map(|name| name.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
-            warn!("Failed to read multipart field");
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::UploadError(e.body_text()))?
+    {
+        let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+            warn!("Skip multipart field without filename");
             continue;
         };
+        if files.len() >= limits.max_files {
+            return Err(AppError::UploadError(format!(
+                "at most {} files can be uploaded at once",
+                limits.max_files
+            )));
+        }
 
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(&base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
+        // read by chunks so oversized files are rejected before being fully buffered
+        let mut data = Vec::new();
+        while let Some(chunk) = field
+            .chunk()
+            .await
+            .map_err(|e| AppError::UploadError(e.body_text()))?
+        {
+            if (data.len() + chunk.len()) as u64 > limits.max_file_size {
+                return Err(AppError::FileTooLarge(format!(
+                    "{} exceeds {} bytes",
+                    filename, limits.max_file_size
+                )));
+            }
+            data.extend_from_slice(&chunk);
         }
-        files.push(file.url());
+
+        let file = state
+            .upload_file(ws_id, user.id as _, &filename, data.into(), &ctx)
+            .await?;
+        files.push(file);
     }
     Ok(Json(files))
 }
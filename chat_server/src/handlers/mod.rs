@@ -1,11 +1,29 @@
+mod audit;
 mod auth;
 mod chat;
+mod command;
+mod export;
+mod health;
+mod import;
 mod message;
+mod retention;
+mod scheduled;
+mod upload;
+mod webhook;
 mod workspace;
 
+pub(crate) use audit::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use command::*;
+pub(crate) use export::*;
+pub(crate) use health::*;
+pub(crate) use import::*;
 pub(crate) use message::*;
+pub(crate) use retention::*;
+pub(crate) use scheduled::*;
+pub(crate) use upload::*;
+pub(crate) use webhook::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl axum::response::IntoResponse {
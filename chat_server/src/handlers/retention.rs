@@ -0,0 +1,159 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{AuditContext, RetentionPolicy},
+    AppState,
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/retention",
+    responses(
+        (status = 200, description = "Retention policy of the workspace", body = RetentionPolicy),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_workspace_retention_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let policy = state.get_workspace_retention(user.ws_id as _).await?;
+    Ok(Json(policy))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/workspace/retention",
+    responses(
+        (status = 200, description = "Retention policy updated", body = RetentionPolicy),
+        (status = 400, description = "Invalid policy", body = ErrorOutput),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Set how long messages of the workspace are kept, workspace owners only
+pub(crate) async fn set_workspace_retention_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(input): Json<RetentionPolicy>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let policy = state.set_workspace_retention(&user, input, &ctx).await?;
+    Ok(Json(policy))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/retention",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Retention policy of the chat", body = RetentionPolicy),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_chat_retention_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let policy = state.get_chat_retention(id).await?;
+    Ok(Json(policy))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/retention",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Retention policy updated", body = RetentionPolicy),
+        (status = 400, description = "Invalid policy", body = ErrorOutput),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Override the retention of the workspace for a chat, workspace owners only
+pub(crate) async fn set_chat_retention_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<u64>,
+    Json(input): Json<RetentionPolicy>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let policy = state.set_chat_retention(id, &user, input, &ctx).await?;
+    Ok(Json(policy))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspace/retention/purge",
+    responses(
+        (status = 200, description = "Purges done", body = Vec<RetentionPurge>),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Purge expired messages of the workspace now instead of waiting for the background job
+pub(crate) async fn purge_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let purges = state
+        .purge_expired_messages(Some(user.ws_id as _), Some(&user), &ctx)
+        .await?;
+    Ok(Json(purges))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/retention/purges",
+    responses(
+        (status = 200, description = "Audit trail of the latest purges", body = Vec<RetentionPurge>),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_purges_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let purges = state.list_retention_purges(user.ws_id as _).await?;
+    Ok(Json(purges))
+}
+
+async fn ensure_owner(state: &AppState, user: &User) -> Result<(), AppError> {
+    if !state
+        .is_workspace_owner(user.ws_id as _, user.id as _)
+        .await?
+    {
+        return Err(AppError::PermissionDenied(
+            "only workspace owners can manage retention".to_string(),
+        ));
+    }
+    Ok(())
+}
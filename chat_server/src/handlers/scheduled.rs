@@ -0,0 +1,110 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{CreateScheduledMessage, UpdateScheduledMessage},
+    AppState,
+};
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/scheduled",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 201, description = "Message scheduled", body = ScheduledMessage),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Schedule a message to be sent to the chat later
+pub(crate) async fn schedule_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateScheduledMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state.schedule_message(input, id, user.id as _).await?;
+
+    Ok((StatusCode::CREATED, Json(scheduled)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/scheduled",
+    responses(
+        (status = 200, description = "Scheduled messages of the user", body = Vec<ScheduledMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state.list_scheduled_messages(user.id as _).await?;
+
+    Ok(Json(scheduled))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/scheduled/{id}",
+    params(
+        ("id" = u64, Path, description = "Scheduled message id")
+    ),
+    responses(
+        (status = 200, description = "Scheduled message updated", body = ScheduledMessage),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "No pending scheduled message of the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateScheduledMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state
+        .update_scheduled_message(id, user.id as _, input)
+        .await?;
+
+    Ok(Json(scheduled))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/scheduled/{id}",
+    params(
+        ("id" = u64, Path, description = "Scheduled message id")
+    ),
+    responses(
+        (status = 204, description = "Scheduled message cancelled"),
+        (status = 404, description = "No pending scheduled message of the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn cancel_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.cancel_scheduled_message(id, user.id as _).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
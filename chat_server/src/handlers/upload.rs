@@ -0,0 +1,131 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{AuditContext, CreateUpload},
+    AppState,
+};
+
+/// Offset of the chunk in a PATCH, and of the session in responses
+const UPLOAD_OFFSET: &str = "upload-offset";
+
+#[utoipa::path(
+    post,
+    path = "/api/uploads",
+    request_body = CreateUpload,
+    responses(
+        (status = 201, description = "Upload session created", body = UploadSession),
+        (status = 413, description = "File too large", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Start a resumable upload, for files too large to send in one request
+pub(crate) async fn create_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateUpload>,
+) -> Result<impl IntoResponse, AppError> {
+    let session = state
+        .create_upload(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id")
+    ),
+    responses(
+        (status = 200, description = "Upload session, resume from its offset", body = UploadSession),
+        (status = 404, description = "Upload not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let session = state.get_upload(&id, user.id as _).await?;
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, session.offset.into());
+    Ok((headers, Json(session)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("upload-offset" = u64, Header, description = "Offset of the chunk, must be the offset of the upload")
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 200, description = "Chunk stored, `file` is set once the upload is complete", body = UploadProgress),
+        (status = 409, description = "Offset doesn't match the upload", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn append_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    chunk: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(offset) = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+    else {
+        return Err(AppError::UploadError(
+            "missing or invalid upload-offset header".to_string(),
+        ));
+    };
+
+    let progress = state
+        .append_upload(&id, user.ws_id as _, user.id as _, offset, chunk, &ctx)
+        .await?;
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, progress.offset.into());
+    Ok((headers, Json(progress)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id")
+    ),
+    responses(
+        (status = 204, description = "Upload cancelled"),
+        (status = 404, description = "Upload not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_upload(&id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
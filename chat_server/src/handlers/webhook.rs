@@ -0,0 +1,112 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    error::AppError,
+    models::{AuditContext, CreateWebhook, WebhookMessage},
+    AppState,
+};
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 201, description = "Webhook created", body = Webhook),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    let webhook = state.create_webhook(input, id, &user, &ctx).await?;
+
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "List of webhooks", body = Vec<Webhook>),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let webhooks = state.list_webhooks(id, &user).await?;
+
+    Ok(Json(webhooks))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{id}",
+    params(
+        ("id" = u64, Path, description = "Webhook id")
+    ),
+    responses(
+        (status = 204, description = "Webhook deleted"),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+        (status = 404, description = "Webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_webhook(id, &user, &ctx).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/hooks/{secret}",
+    params(
+        ("secret" = String, Path, description = "Webhook secret")
+    ),
+    responses(
+        (status = 201, description = "Message created", body = Message),
+        (status = 404, description = "Webhook not found", body = ErrorOutput),
+        (status = 429, description = "Rate limited", body = ErrorOutput),
+    )
+)]
+/// Post a message into a chat through an incoming webhook, no token needed.
+pub(crate) async fn incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path(secret): Path<String>,
+    Json(input): Json<WebhookMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state.deliver_webhook(&secret, input).await?;
+
+    Ok((StatusCode::CREATED, Json(message)))
+}
@@ -1,8 +1,13 @@
 use axum::Json;
-use axum::{extract::State, response::IntoResponse, Extension};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension,
+};
 use chat_core::User;
 
 use crate::error::AppError;
+use crate::models::{AuditContext, CollectFiles};
 use crate::AppState;
 
 #[utoipa::path(
@@ -24,3 +29,60 @@ pub(crate) async fn list_chat_users_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(user))
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/usage",
+    responses(
+        (status = 200, description = "Storage used by the workspace", body = WorkspaceUsage),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn workspace_usage_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let usage = state.get_workspace_usage(user.ws_id as _).await?;
+    Ok(Json(usage))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspace/files/gc",
+    params(CollectFiles),
+    responses(
+        (status = 200, description = "Files collected, or that would be with dry_run", body = GcReport),
+        (status = 403, description = "Not a workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Delete the uploaded files of the workspace no message references, workspace owners only
+pub(crate) async fn collect_files_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Query(input): Query<CollectFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state
+        .is_workspace_owner(user.ws_id as _, user.id as _)
+        .await?
+    {
+        return Err(AppError::PermissionDenied(
+            "only workspace owners can collect files".to_string(),
+        ));
+    }
+    let report = state
+        .collect_orphaned_files(
+            Some(user.ws_id as _),
+            state.config.gc.grace_secs,
+            input.dry_run,
+            Some(&user),
+            &ctx,
+        )
+        .await?;
+    Ok(Json(report))
+}
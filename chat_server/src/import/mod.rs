@@ -0,0 +1,319 @@
+mod text;
+
+use std::io::{Cursor, Read};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::error::AppError;
+
+pub use text::slack_to_text;
+
+/// Cap of a json file in the archive, the sizes entries declare can't be trusted
+const MAX_JSON_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A Slack workspace export: `users.json`, the conversation lists and a directory of
+/// daily message files per conversation. Blobs of shared files may be included under
+/// `__uploads/{file id}/`, otherwise they are downloaded from Slack.
+pub struct SlackArchive {
+    zip: ZipArchive<Cursor<Bytes>>,
+    /// blobs larger than this are not read
+    max_file_size: u64,
+    pub users: Vec<SlackUser>,
+    pub conversations: Vec<SlackConversation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConversationKind {
+    /// public channel, `channels.json`
+    Channel,
+    /// private channel, `groups.json`
+    Group,
+    /// direct message, `dms.json`
+    Dm,
+    /// multi-party direct message, `mpims.json`
+    Mpim,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackUser {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub real_name: Option<String>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub is_bot: bool,
+    #[serde(default)]
+    pub profile: SlackProfile,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SlackProfile {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub real_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlackConversation {
+    pub id: String,
+    pub kind: ConversationKind,
+    pub name: Option<String>,
+    pub members: Vec<String>,
+    pub topic: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    /// directory of the message files
+    dir: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackMessage {
+    pub ts: String,
+    #[serde(default)]
+    pub subtype: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub user_profile: Option<SlackProfile>,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub files: Vec<SlackFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackFile {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub url_private_download: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawConversation {
+    id: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    members: Vec<String>,
+    #[serde(default)]
+    topic: Option<RawTopic>,
+    #[serde(default)]
+    created: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawTopic {
+    #[serde(default)]
+    value: String,
+}
+
+impl SlackArchive {
+    pub fn open(data: Bytes, max_file_size: u64) -> Result<Self, AppError> {
+        let zip = ZipArchive::new(Cursor::new(data)).map_err(import_error)?;
+        let mut archive = Self {
+            zip,
+            max_file_size,
+            users: vec![],
+            conversations: vec![],
+        };
+        archive.users = archive
+            .read_json("users.json")?
+            .ok_or_else(|| AppError::ImportError("users.json is missing".to_string()))?;
+
+        for (file, kind) in [
+            ("channels.json", ConversationKind::Channel),
+            ("groups.json", ConversationKind::Group),
+            ("dms.json", ConversationKind::Dm),
+            ("mpims.json", ConversationKind::Mpim),
+        ] {
+            let raw: Vec<RawConversation> = archive.read_json(file)?.unwrap_or_default();
+            archive
+                .conversations
+                .extend(raw.into_iter().map(|c| SlackConversation::new(c, kind)));
+        }
+        Ok(archive)
+    }
+
+    /// Messages of a conversation in the order they were sent
+    pub fn messages(
+        &mut self,
+        conversation: &SlackConversation,
+    ) -> Result<Vec<SlackMessage>, AppError> {
+        let prefix = format!("{}/", conversation.dir);
+        let mut names: Vec<String> = self
+            .zip
+            .file_names()
+            .filter(|name| {
+                name.strip_prefix(&prefix)
+                    .is_some_and(|rest| rest.ends_with(".json") && !rest.contains('/'))
+            })
+            .map(str::to_string)
+            .collect();
+        names.sort();
+
+        let mut messages = vec![];
+        for name in names {
+            let page: Vec<SlackMessage> = self.read_json(&name)?.unwrap_or_default();
+            messages.extend(page);
+        }
+        messages.sort_by_key(|m| parse_ts(&m.ts));
+        Ok(messages)
+    }
+
+    /// The blob of a shared file if the export includes it, an error if it is too large
+    pub fn file(&mut self, file: &SlackFile) -> Result<Option<Vec<u8>>, AppError> {
+        let prefix = format!("__uploads/{}/", file.id);
+        let Some(name) = self
+            .zip
+            .file_names()
+            .find(|name| name.starts_with(&prefix) && !name.ends_with('/'))
+            .map(str::to_string)
+        else {
+            return Ok(None);
+        };
+        self.read_entry(&name, self.max_file_size)
+    }
+
+    fn read_json<T: DeserializeOwned>(&mut self, name: &str) -> Result<Option<T>, AppError> {
+        let Some(data) = self.read_entry(name, MAX_JSON_SIZE)? else {
+            return Ok(None);
+        };
+        let value = serde_json::from_slice(&data)
+            .map_err(|e| AppError::ImportError(format!("{}: {}", name, e)))?;
+        Ok(Some(value))
+    }
+
+    /// Read an entry of at most `limit` bytes. Its declared size is checked first, the
+    /// read is cut at the limit as well since the declaration may lie.
+    fn read_entry(&mut self, name: &str, limit: u64) -> Result<Option<Vec<u8>>, AppError> {
+        let entry = match self.zip.by_name(name) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(import_error(e)),
+        };
+        let too_large =
+            || AppError::ImportError(format!("{} is larger than {} bytes", name, limit));
+        if entry.size() > limit {
+            return Err(too_large());
+        }
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.take(limit + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > limit {
+            return Err(too_large());
+        }
+        Ok(Some(data))
+    }
+}
+
+impl SlackUser {
+    pub fn display_name(&self) -> &str {
+        [&self.profile.real_name, &self.real_name]
+            .into_iter()
+            .flatten()
+            .find(|name| !name.is_empty())
+            .unwrap_or(&self.name)
+    }
+}
+
+impl SlackConversation {
+    fn new(raw: RawConversation, kind: ConversationKind) -> Self {
+        // channels are exported by name, direct messages by id
+        let dir = match kind {
+            ConversationKind::Dm => raw.id.clone(),
+            _ => raw.name.clone().unwrap_or_else(|| raw.id.clone()),
+        };
+        let name = match kind {
+            ConversationKind::Channel | ConversationKind::Group => raw.name,
+            ConversationKind::Dm | ConversationKind::Mpim => None,
+        };
+        Self {
+            id: raw.id,
+            kind,
+            name,
+            members: raw.members,
+            topic: raw.topic.map(|t| t.value).filter(|t| !t.is_empty()),
+            created: raw
+                .created
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            dir,
+        }
+    }
+}
+
+impl SlackFile {
+    pub fn filename(&self) -> &str {
+        [&self.name, &self.title]
+            .into_iter()
+            .flatten()
+            .find(|name| !name.is_empty())
+            .map(String::as_str)
+            .unwrap_or(&self.id)
+    }
+}
+
+/// Slack timestamps are `seconds.micros` strings, they double as message ids
+pub fn parse_ts(ts: &str) -> Option<DateTime<Utc>> {
+    let (secs, frac) = ts.split_once('.').unwrap_or((ts, ""));
+    let secs = secs.parse().ok()?;
+    let nanos = match frac {
+        "" => 0,
+        frac if frac.len() <= 9 && frac.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<9}", frac).parse().ok()?
+        }
+        _ => return None,
+    };
+    DateTime::from_timestamp(secs, nanos)
+}
+
+fn import_error(e: impl std::fmt::Display) -> AppError {
+    AppError::ImportError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    #[test]
+    fn oversized_entries_should_be_rejected() -> Result<()> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("users.json", SimpleFileOptions::default())?;
+        zip.write_all(b"[]")?;
+        zip.start_file("__uploads/F1/big.bin", SimpleFileOptions::default())?;
+        zip.write_all(&[0; 64])?;
+        let data = Bytes::from(zip.finish()?.into_inner());
+
+        let file = SlackFile {
+            id: "F1".to_string(),
+            name: None,
+            title: None,
+            url_private_download: None,
+        };
+        let mut archive = SlackArchive::open(data.clone(), 64)?;
+        assert_eq!(archive.file(&file)?.map(|d| d.len()), Some(64));
+        let mut archive = SlackArchive::open(data, 63)?;
+        assert!(matches!(archive.file(&file), Err(AppError::ImportError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_ts_should_work() {
+        let ts = parse_ts("1700000000.000123").unwrap();
+        assert_eq!(ts.timestamp(), 1700000000);
+        assert_eq!(ts.timestamp_subsec_micros(), 123);
+        assert_eq!(parse_ts("1700000000").unwrap().timestamp(), 1700000000);
+        assert!(parse_ts("yesterday").is_none());
+        assert!(parse_ts("1700000000.12x").is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+/// Convert Slack message markup to plain text. Mentions become `@name` with the names of
+/// `users` (slack id -> name), links keep their label and url, and the html escapes
+/// Slack applies to `<`, `>` and `&` are undone.
+pub fn slack_to_text(text: &str, users: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        out.push_str(&unescape(&rest[..start]));
+        out.push_str(&convert_token(&rest[start + 1..start + len], users));
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(&unescape(rest));
+    out
+}
+
+fn convert_token(token: &str, users: &HashMap<String, String>) -> String {
+    let (target, label) = match token.split_once('|') {
+        Some((target, label)) => (target, Some(unescape(label))),
+        None => (token, None),
+    };
+    if let Some(id) = target.strip_prefix('@') {
+        let name = users
+            .get(id)
+            .cloned()
+            .or(label)
+            .unwrap_or_else(|| id.to_string());
+        return format!("@{}", name);
+    }
+    if let Some(id) = target.strip_prefix('#') {
+        return format!("#{}", label.unwrap_or_else(|| id.to_string()));
+    }
+    if let Some(special) = target.strip_prefix('!') {
+        return match special {
+            "here" | "channel" | "everyone" => format!("@{}", special),
+            // user groups and dates carry a readable fallback as label
+            _ => label.unwrap_or_default(),
+        };
+    }
+
+    let url = unescape(target);
+    let shown = url.strip_prefix("mailto:").unwrap_or(&url);
+    match label {
+        Some(label) if label != shown && label != url => format!("{} ({})", label, shown),
+        _ => shown.to_string(),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slack_to_text_should_convert_markup() {
+        let users = HashMap::from([("U1".to_string(), "Tyr Chen".to_string())]);
+        let cases = [
+            ("hi <@U1>", "hi @Tyr Chen"),
+            ("hi <@U2|alice>", "hi @alice"),
+            ("see <#C1|general>", "see #general"),
+            ("<!here> lunch?", "@here lunch?"),
+            ("<!subteam^S1|@devs> ping", "@devs ping"),
+            ("<https://acme.org>", "https://acme.org"),
+            ("<https://acme.org|Acme>", "Acme (https://acme.org)"),
+            ("<mailto:a@acme.org|a@acme.org>", "a@acme.org"),
+            ("1 &lt; 2 &amp;&amp; 3 &gt; 2", "1 < 2 && 3 > 2"),
+            ("unclosed <tag", "unclosed <tag"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(slack_to_text(input, &users), expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{config::MailConfig, error::AppError};
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Send emails, e.g. password resets. Without a mail endpoint configured only their
+/// recipient and subject are logged, never the body with its links and tokens.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+pub struct LogMailer;
+
+/// Post emails as json to the endpoint of a mail delivery service
+pub struct HttpMailer {
+    client: reqwest::Client,
+    endpoint: String,
+    api_key: Option<String>,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        info!("Email to {}: {} (not sent, no mail endpoint)", email.to, email.subject);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for HttpMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let mut req = self.client.post(&self.endpoint).json(&email);
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| AppError::MailError(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(AppError::MailError(format!(
+                "mail endpoint returned {}",
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn from_config(config: &MailConfig) -> Arc<dyn Mailer> {
+    match &config.endpoint {
+        Some(endpoint) => Arc::new(HttpMailer {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.clone(),
+            api_key: config.api_key.clone(),
+        }),
+        None => Arc::new(LogMailer),
+    }
+}
@@ -1,25 +1,51 @@
-use chat_server::{get_router, AppConfig, AppState};
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chat_core::metrics::spawn_metrics_server;
+use chat_core::migrate::MigrateMode;
+use chat_core::telemetry::init_tracing;
+use chat_server::{get_router, spawn_workers, AppConfig, AppState};
 use tokio::net::TcpListener;
 use tracing::info;
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::fmt::Layer;
-use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::Layer as _;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
     //console_subscriber::init();
 
-    let config = AppConfig::load()?;
+    let (mut config, report) = AppConfig::load_with_report()?;
+    // spans still being exported are flushed when the guard drops at the end
+    let _telemetry = init_tracing("chat-server", &config.telemetry)?;
+    report.log();
+    // `--check` only verifies the schema, whatever the config says
+    if std::env::args().skip(1).any(|arg| arg == "--check") {
+        config.server.migrate = MigrateMode::Check;
+    }
     let addr = format!("0.0.0.0:{}", config.server.port);
+    let migrate = config.server.migrate;
+    let metrics_port = config.server.metrics_port;
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
     let state = AppState::try_new(config).await?;
+    state.prepare_schema(migrate).await?;
+    spawn_workers(&state);
+    let shutdown = state.shutdown().clone();
+    shutdown.listen_for_signals();
     let app = get_router(state).await?;
+    if let Some(port) = metrics_port {
+        spawn_metrics_server(port, shutdown.triggered()).await?;
+    }
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    // the peer address is recorded in the audit log
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.triggered());
+    shutdown
+        .drain(server.into_future(), shutdown_timeout)
+        .await?;
+    info!("Server stopped");
     Ok(())
 }
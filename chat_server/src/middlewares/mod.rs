@@ -1,11 +1,11 @@
 use axum::{
-    extract::{FromRequestParts, Path, Request, State},
+    extract::{FromRequestParts, Path, Query, Request, State},
     middleware::Next,
     response::Response,
 };
-use chat_core::User;
+use chat_core::{middlewares::verify_token, User};
 
-use crate::{error::AppError, AppState};
+use crate::{error::AppError, models::FileSignature, AppState};
 use axum::response::IntoResponse;
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
@@ -32,3 +32,25 @@ pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next
 
     next.run(req).await
 }
+
+/// Let a signed file url stand in for the token, requests without a signature go through
+/// the usual token verification
+pub async fn verify_file_url(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let Ok(Query(signature)) = Query::<FileSignature>::from_request_parts(&mut parts, &state).await
+    else {
+        let req = Request::from_parts(parts, body);
+        return verify_token::<AppState>(State(state), req, next).await;
+    };
+
+    // routes nested under /api see the path without the prefix
+    let url = parts.uri.path();
+    let url = url.strip_prefix("/api").unwrap_or(url);
+    let user = match state.verify_file_signature(url, &signature).await {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+    parts.extensions.insert(user);
+
+    next.run(Request::from_parts(parts, body)).await
+}
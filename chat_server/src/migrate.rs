@@ -0,0 +1,47 @@
+use chat_core::migrate::{self, MigrateMode};
+
+use crate::{error::AppError, AppState};
+
+impl AppState {
+    /// Apply the embedded migrations which aren't applied yet, returns their versions
+    pub async fn run_migrations(&self) -> Result<Vec<i64>, AppError> {
+        Ok(migrate::run_migrations(&self.pool).await?)
+    }
+
+    /// Versions of the embedded migrations the database is missing
+    pub async fn pending_migrations(&self) -> Result<Vec<i64>, AppError> {
+        Ok(migrate::pending_migrations(&self.pool).await?)
+    }
+
+    /// Check or migrate the schema as configured, before anything touches it
+    pub async fn prepare_schema(&self, mode: MigrateMode) -> Result<(), AppError> {
+        Ok(migrate::prepare_schema(&self.pool, mode).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn embedded_migrations_should_match_test_schema() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.pending_migrations().await?.is_empty());
+        assert!(state.run_migrations().await?.is_empty());
+        state.prepare_schema(MigrateMode::Check).await?;
+
+        // a database without the latest migration refuses to start in check mode
+        let latest = migrate::MIGRATOR.iter().map(|m| m.version).max();
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(latest)
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(
+            state.pending_migrations().await?,
+            latest.into_iter().collect::<Vec<_>>()
+        );
+        assert!(state.prepare_schema(MigrateMode::Check).await.is_err());
+        Ok(())
+    }
+}
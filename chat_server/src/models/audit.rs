@@ -0,0 +1,352 @@
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{error::AppError, AppState};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+const MAX_AUDIT_PAGE_SIZE: u64 = 500;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "audit_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    UserSignup,
+    UserSignin,
+    /// wrong password, the target is the email tried
+    UserSigninFailed,
+    UserPasswordReset,
+    /// to another workspace, recorded in both of them
+    UserMoved,
+    ChatCreated,
+    ChatRenamed,
+    ChatMembersAdded,
+    ChatMembersRemoved,
+    ChatRetentionUpdated,
+    FileUploaded,
+    FileUrlSigned,
+    WebhookCreated,
+    WebhookDeleted,
+    CommandCreated,
+    CommandDeleted,
+    WorkspaceRetentionUpdated,
+    WorkspaceMessagesPurged,
+    WorkspaceFilesCollected,
+    WorkspaceExportRequested,
+    WorkspaceImported,
+    WorkspaceCreated,
+    WorkspaceOwnerChanged,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub ws_id: Option<i64>,
+    pub actor_id: Option<i64>,
+    pub action: AuditAction,
+    /// e.g. chat, user, file, webhook or command
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    /// details of the action, e.g. the members added to a chat
+    #[schema(value_type = Object)]
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Filters of the audit log, events come newest first. Pass the id of the last event
+/// of a page as `last_id` to get the next one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct ListAuditEvents {
+    pub actor_id: Option<i64>,
+    pub action: Option<AuditAction>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// events at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// events before this time
+    pub until: Option<DateTime<Utc>>,
+    pub last_id: Option<u64>,
+    /// at most 500, defaults to 100
+    pub limit: Option<u64>,
+}
+
+/// Where a request came from, extracted in handlers which write audit events
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditContext {
+    pub ip: Option<String>,
+    /// `x-request-id`, set by the request id middleware if the client didn't send one
+    pub request_id: Option<String>,
+}
+
+/// An event about to be recorded
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    ws_id: Option<i64>,
+    actor_id: Option<i64>,
+    action: AuditAction,
+    target: Option<(&'static str, String)>,
+    metadata: serde_json::Value,
+}
+
+impl NewAuditEvent {
+    /// An action of a signed in user in their workspace
+    pub fn new(actor: &User, action: AuditAction) -> Self {
+        Self {
+            ws_id: Some(actor.ws_id),
+            actor_id: Some(actor.id),
+            action,
+            target: None,
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    /// An action without a signed in user, e.g. a failed signin
+    pub fn anonymous(ws_id: Option<i64>, action: AuditAction) -> Self {
+        Self {
+            ws_id,
+            actor_id: None,
+            action,
+            target: None,
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    /// An action in the workspace of a user known by id, without one if chat-admin or a
+    /// background job acts
+    pub fn in_workspace(ws_id: i64, actor_id: Option<i64>, action: AuditAction) -> Self {
+        Self {
+            ws_id: Some(ws_id),
+            actor_id,
+            action,
+            target: None,
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    pub fn target(mut self, kind: &'static str, id: impl ToString) -> Self {
+        self.target = Some((kind, id.to_string()));
+        self
+    }
+
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuditContext {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+        // x-forwarded-for is only trusted behind a proxy which sets it, otherwise any
+        // client could make up its address. Even then only the last hop is, the proxy
+        // appends the address it saw to whatever the client sent.
+        let forwarded = header(FORWARDED_FOR_HEADER)
+            .filter(|_| state.config.audit.trust_proxy)
+            .and_then(|v| v.rsplit(',').next())
+            .map(|v| v.trim().to_string());
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+        Ok(Self {
+            ip: forwarded.or(peer),
+            request_id: header(REQUEST_ID_HEADER).map(str::to_string),
+        })
+    }
+}
+
+impl AppState {
+    /// Record an event which doesn't go along with a change, e.g. a signin. Changes
+    /// record theirs with `insert_audit_event` in their own transaction.
+    pub async fn record_audit(
+        &self,
+        ctx: &AuditContext,
+        event: NewAuditEvent,
+    ) -> Result<AuditEvent, AppError> {
+        let mut conn = self.pool.acquire().await?;
+        insert_audit_event(&mut conn, ctx, event).await
+    }
+
+    pub async fn list_audit_events(
+        &self,
+        ws_id: u64,
+        input: ListAuditEvents,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = input.limit.unwrap_or(100).clamp(1, MAX_AUDIT_PAGE_SIZE);
+        let events = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, actor_id, action, target_type, target_id, ip, request_id,
+                metadata, created_at
+            FROM audit_events
+            WHERE ws_id = $1 AND id < $2
+              AND ($3::bigint IS NULL OR actor_id = $3)
+              AND ($4::audit_action IS NULL OR action = $4)
+              AND ($5::text IS NULL OR target_type = $5)
+              AND ($6::text IS NULL OR target_id = $6)
+              AND ($7::timestamptz IS NULL OR created_at >= $7)
+              AND ($8::timestamptz IS NULL OR created_at < $8)
+            ORDER BY id DESC
+            LIMIT $9
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(last_id as i64)
+        .bind(input.actor_id)
+        .bind(input.action)
+        .bind(input.target_type)
+        .bind(input.target_id)
+        .bind(input.since)
+        .bind(input.until)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(events)
+    }
+}
+
+/// Record the event in the transaction of the change, so there is no change without
+/// its event and no event of a change which was rolled back
+pub(crate) async fn insert_audit_event(
+    conn: &mut PgConnection,
+    ctx: &AuditContext,
+    event: NewAuditEvent,
+) -> Result<AuditEvent, AppError> {
+    let (target_type, target_id) = event.target.unzip();
+    let event = sqlx::query_as(
+        r#"
+        INSERT INTO audit_events (ws_id, actor_id, action, target_type, target_id, ip,
+            request_id, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id, ws_id, actor_id, action, target_type, target_id, ip, request_id,
+            metadata, created_at
+        "#,
+    )
+    .bind(event.ws_id)
+    .bind(event.actor_id)
+    .bind(event.action)
+    .bind(target_type)
+    .bind(target_id)
+    .bind(&ctx.ip)
+    .bind(&ctx.request_id)
+    .bind(event.metadata)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::http::Request;
+
+    #[tokio::test]
+    async fn audit_events_should_be_filterable_and_paginated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let alice = state.find_user_by_id(2).await?.expect("user should exist");
+        let ctx = AuditContext {
+            ip: Some("10.0.0.1".to_string()),
+            request_id: Some("req-1".to_string()),
+        };
+
+        state
+            .record_audit(&ctx, NewAuditEvent::new(&owner, AuditAction::UserSignin))
+            .await?;
+        for chat_id in 1..=3 {
+            let event = NewAuditEvent::new(&alice, AuditAction::ChatMembersAdded)
+                .target("chat", chat_id)
+                .metadata(serde_json::json!({ "user_ids": [3] }));
+            state.record_audit(&ctx, event).await?;
+        }
+        state
+            .record_audit(
+                &ctx,
+                NewAuditEvent::anonymous(None, AuditAction::UserSigninFailed),
+            )
+            .await?;
+
+        let all = state.list_audit_events(1, Default::default()).await?;
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[0].target_id.as_deref(), Some("3"));
+        assert_eq!(all[0].request_id.as_deref(), Some("req-1"));
+
+        let input = ListAuditEvents {
+            action: Some(AuditAction::ChatMembersAdded),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = state.list_audit_events(1, input.clone()).await?;
+        assert_eq!(page.len(), 2);
+        let input = ListAuditEvents {
+            last_id: Some(page[1].id as _),
+            ..input
+        };
+        let next = state.list_audit_events(1, input).await?;
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].target_id.as_deref(), Some("1"));
+
+        let input = ListAuditEvents {
+            actor_id: Some(owner.id),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(1, input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::UserSignin);
+
+        // the log can't be rewritten
+        assert!(sqlx::query("DELETE FROM audit_events")
+            .execute(&state.pool)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn audit_context_should_only_trust_forwarded_for_behind_proxy() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let req = Request::builder()
+            .header("x-request-id", "req-2")
+            .header("x-forwarded-for", "203.0.113.7, 10.0.0.1")
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 4242))))
+            .body(())?;
+        let (mut parts, _) = req.into_parts();
+
+        let ctx = AuditContext::from_request_parts(&mut parts, &state).await?;
+        assert_eq!(ctx.ip.as_deref(), Some("10.0.0.2"));
+        assert_eq!(ctx.request_id.as_deref(), Some("req-2"));
+
+        // the client made up the first hop, the proxy appended the address it saw
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.audit.trust_proxy = true).await?;
+        let ctx = AuditContext::from_request_parts(&mut parts, &state).await?;
+        assert_eq!(ctx.ip.as_deref(), Some("10.0.0.1"));
+        Ok(())
+    }
+}
@@ -1,8 +1,16 @@
 use chat_core::{Chat, ChatType};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::collections::HashSet;
 use utoipa::ToSchema;
 use crate::{error::AppError, AppState};
 
+use super::{
+    audit::insert_audit_event,
+    system_message::{insert_system_message, SystemEvent},
+    AuditAction, AuditContext, NewAuditEvent,
+};
+
 
 #[derive(Debug, Clone, Serialize, ToSchema,Deserialize)]
 pub struct CreateChat {
@@ -11,9 +19,28 @@ pub struct CreateChat {
     pub public: bool,
 }
 
+/// Changes to a chat, fields left out are kept as they are
+#[derive(Debug, Clone, Default, Serialize, ToSchema, Deserialize)]
+pub struct UpdateChat {
+    pub name: Option<String>,
+    /// An empty topic clears it
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub add_members: Vec<i64>,
+    #[serde(default)]
+    pub remove_members: Vec<i64>,
+}
+
 #[allow(dead_code)]
 impl AppState {
-    pub async fn create_chat(&self, input: CreateChat, ws_id: u64) -> Result<Chat, AppError> {
+    /// Create a chat of the workspace, `actor_id` is None if chat-admin creates it
+    pub async fn create_chat(
+        &self,
+        input: CreateChat,
+        ws_id: u64,
+        actor_id: Option<u64>,
+        ctx: &AuditContext,
+    ) -> Result<Chat, AppError> {
         let len = input.members.len();
         if len < 2 {
             return Err(AppError::CreateChatError(
@@ -47,19 +74,29 @@ impl AppState {
             }
         };
 
-        let chat = sqlx::query_as(
+        let mut tx = self.pool.begin().await?;
+        let chat: Chat = sqlx::query_as(
             r#"
             INSERT INTO chats (ws_id, name, type, members)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            RETURNING id, ws_id, name, type, members, topic, created_at
             "#,
         )
         .bind(ws_id as i64)
         .bind(input.name)
         .bind(chat_type)
         .bind(input.members)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        let event = NewAuditEvent::in_workspace(
+            ws_id as _,
+            actor_id.map(|id| id as _),
+            AuditAction::ChatCreated,
+        )
+        .target("chat", chat.id)
+        .metadata(serde_json::json!({ "type": chat.r#type, "members": chat.members }));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
@@ -67,7 +104,7 @@ impl AppState {
     pub async fn fetch_chats(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-             SELECT id, ws_id, name, type, members, created_at
+             SELECT id, ws_id, name, type, members, topic, created_at
             FROM chats
             WHERE ws_id = $1 
             "#,
@@ -82,7 +119,7 @@ impl AppState {
     pub async fn get_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, topic, created_at
             FROM chats
             WHERE id = $1
             "#,
@@ -109,6 +146,401 @@ impl AppState {
 
         Ok(is_member.is_some())
     }
+
+    /// Add users to the chat, users already in the chat are skipped
+    pub async fn add_chat_members(
+        &self,
+        chat_id: u64,
+        actor_id: u64,
+        user_ids: &[i64],
+        ctx: &AuditContext,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, chat_id).await?;
+        let chat = add_members(&mut tx, chat, actor_id, user_ids, ctx).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    /// Remove users from the chat, users not in the chat are skipped
+    pub async fn remove_chat_members(
+        &self,
+        chat_id: u64,
+        actor_id: u64,
+        user_ids: &[i64],
+        ctx: &AuditContext,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, chat_id).await?;
+        let chat = remove_members(&mut tx, chat, actor_id, user_ids, ctx).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    pub async fn update_chat_topic(
+        &self,
+        chat_id: u64,
+        actor_id: u64,
+        topic: Option<&str>,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, chat_id).await?;
+        let chat = set_topic(&mut tx, chat, actor_id, topic).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    /// Apply the changes in one transaction, so either all of them or none take effect.
+    /// Each change that takes effect writes a system message, and an audit event unless it
+    /// is a new topic.
+    pub async fn update_chat(
+        &self,
+        chat_id: u64,
+        actor_id: u64,
+        input: UpdateChat,
+        ctx: &AuditContext,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let mut chat = lock_chat(&mut tx, chat_id).await?;
+        let changes_members = !input.add_members.is_empty() || !input.remove_members.is_empty();
+        if changes_members && chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Members of a single chat can't be changed".to_string(),
+            ));
+        }
+        if !input.add_members.is_empty() {
+            let found = count_workspace_users(&mut tx, &input.add_members, chat.ws_id).await?;
+            if found != input.add_members.iter().collect::<HashSet<_>>().len() {
+                return Err(AppError::UpdateChatError(
+                    "Some members do not exist".to_string(),
+                ));
+            }
+        }
+
+        if let Some(name) = &input.name {
+            chat = rename(&mut tx, chat, actor_id, name, ctx).await?;
+        }
+        if let Some(topic) = &input.topic {
+            let topic = topic.trim();
+            let topic = (!topic.is_empty()).then_some(topic);
+            chat = set_topic(&mut tx, chat, actor_id, topic).await?;
+        }
+        if !input.add_members.is_empty() {
+            chat = add_members(&mut tx, chat, actor_id, &input.add_members, ctx).await?;
+        }
+        if !input.remove_members.is_empty() {
+            chat = remove_members(&mut tx, chat, actor_id, &input.remove_members, ctx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Muted chats don't push new messages to the user
+    pub async fn set_chat_muted(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        muted: bool,
+    ) -> Result<(), AppError> {
+        let sql = if muted {
+            "INSERT INTO chat_mutes (chat_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM chat_mutes WHERE chat_id = $1 AND user_id = $2"
+        };
+        sqlx::query(sql)
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Lock the chat row until the transaction ends so concurrent changes see each other
+async fn lock_chat(conn: &mut PgConnection, chat_id: u64) -> Result<Chat, AppError> {
+    let chat = sqlx::query_as(
+        r#"
+        SELECT id, ws_id, name, type, members, topic, created_at
+        FROM chats
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(chat_id as i64)
+    .fetch_optional(conn)
+    .await?;
+
+    chat.ok_or_else(|| AppError::NotFound(format!("chat id {}", chat_id)))
+}
+
+/// Remove the user from the chats of the workspace like removing a member does. Single chats
+/// are deleted with their messages instead, as nobody could write to them anymore, unless a
+/// legal hold keeps them. Returns the ids of the chats left and of those deleted.
+pub(super) async fn leave_workspace_chats(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_id: i64,
+    ctx: &AuditContext,
+) -> Result<(Vec<i64>, Vec<i64>), AppError> {
+    let chats: Vec<Chat> = sqlx::query_as(
+        r#"
+        SELECT id, ws_id, name, type, members, topic, created_at
+        FROM chats
+        WHERE ws_id = $1 AND $2 = ANY(members)
+        ORDER BY id
+        FOR UPDATE
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    let (single, others): (Vec<_>, Vec<_>) = chats
+        .into_iter()
+        .partition(|chat| chat.r#type == ChatType::Single);
+    let deleted: Vec<i64> = single.iter().map(|chat| chat.id).collect();
+
+    let held: Option<i64> = sqlx::query_scalar(
+        r#"
+        SELECT c.id
+        FROM chats c
+        JOIN workspaces w ON w.id = c.ws_id
+        WHERE c.id = ANY($1) AND (c.legal_hold OR w.legal_hold)
+        LIMIT 1
+        "#,
+    )
+    .bind(&deleted)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(id) = held {
+        return Err(AppError::PermissionDenied(format!(
+            "chat {} is on legal hold",
+            id
+        )));
+    }
+
+    let mut left = vec![];
+    for chat in others {
+        left.push(chat.id);
+        remove_members(&mut *conn, chat, user_id as _, &[user_id], ctx).await?;
+    }
+    if !deleted.is_empty() {
+        // the files of the messages are collected as orphans
+        let messages: Vec<i64> =
+            sqlx::query_scalar("DELETE FROM messages WHERE chat_id = ANY($1) RETURNING id")
+                .bind(&deleted)
+                .fetch_all(&mut *conn)
+                .await?;
+        sqlx::query("DELETE FROM chats WHERE id = ANY($1)")
+            .bind(&deleted)
+            .execute(&mut *conn)
+            .await?;
+        // so an import of the same archive creates them again
+        sqlx::query(
+            r#"
+            DELETE FROM import_refs
+            WHERE ws_id = $1
+              AND ((kind = 'chat' AND local_id = ANY($2))
+                OR (kind = 'message' AND local_id = ANY($3)))
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&deleted)
+        .bind(&messages)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok((left, deleted))
+}
+
+async fn count_workspace_users(
+    conn: &mut PgConnection,
+    ids: &[i64],
+    ws_id: i64,
+) -> Result<usize, AppError> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT count(*) FROM users WHERE id = ANY($1) AND ws_id = $2 AND disabled_at IS NULL",
+    )
+    .bind(ids)
+    .bind(ws_id)
+    .fetch_one(conn)
+    .await?;
+    Ok(count as usize)
+}
+
+// the steps of update_chat take the chat locked by lock_chat and return it as changed,
+// the audit events record what actually changed rather than what was asked for
+
+async fn add_members(
+    conn: &mut PgConnection,
+    chat: Chat,
+    actor_id: u64,
+    user_ids: &[i64],
+    ctx: &AuditContext,
+) -> Result<Chat, AppError> {
+    let mut added: Vec<i64> = vec![];
+    for id in user_ids {
+        if !chat.members.contains(id) && !added.contains(id) {
+            added.push(*id);
+        }
+    }
+    if added.is_empty() {
+        return Ok(chat);
+    }
+
+    let chat = sqlx::query_as(
+        r#"
+        UPDATE chats
+        SET members = members || $2
+        WHERE id = $1
+        RETURNING id, ws_id, name, type, members, topic, created_at
+        "#,
+    )
+    .bind(chat.id)
+    .bind(&added)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let event = NewAuditEvent::in_workspace(
+        chat.ws_id,
+        Some(actor_id as _),
+        AuditAction::ChatMembersAdded,
+    )
+    .target("chat", chat.id)
+    .metadata(serde_json::json!({ "user_ids": added }));
+    insert_audit_event(&mut *conn, ctx, event).await?;
+    let event = SystemEvent::MembersAdded { user_ids: added };
+    insert_system_message(conn, chat.id as _, actor_id, &event).await?;
+    Ok(chat)
+}
+
+async fn remove_members(
+    conn: &mut PgConnection,
+    chat: Chat,
+    actor_id: u64,
+    user_ids: &[i64],
+    ctx: &AuditContext,
+) -> Result<Chat, AppError> {
+    let mut removed: Vec<i64> = vec![];
+    for id in user_ids {
+        if chat.members.contains(id) && !removed.contains(id) {
+            removed.push(*id);
+        }
+    }
+    if removed.is_empty() {
+        return Ok(chat);
+    }
+
+    let chat = sqlx::query_as(
+        r#"
+        UPDATE chats
+        SET members = ARRAY(
+            SELECT m FROM unnest(members) WITH ORDINALITY AS t(m, i)
+            WHERE m <> ALL($2)
+            ORDER BY i
+        )
+        WHERE id = $1
+        RETURNING id, ws_id, name, type, members, topic, created_at
+        "#,
+    )
+    .bind(chat.id)
+    .bind(&removed)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let event = NewAuditEvent::in_workspace(
+        chat.ws_id,
+        Some(actor_id as _),
+        AuditAction::ChatMembersRemoved,
+    )
+    .target("chat", chat.id)
+    .metadata(serde_json::json!({ "user_ids": removed }));
+    insert_audit_event(&mut *conn, ctx, event).await?;
+    let event = SystemEvent::MembersRemoved { user_ids: removed };
+    insert_system_message(conn, chat.id as _, actor_id, &event).await?;
+    Ok(chat)
+}
+
+async fn set_topic(
+    conn: &mut PgConnection,
+    chat: Chat,
+    actor_id: u64,
+    topic: Option<&str>,
+) -> Result<Chat, AppError> {
+    if chat.topic.as_deref() == topic {
+        return Ok(chat);
+    }
+
+    let new: Chat = sqlx::query_as(
+        r#"
+        UPDATE chats
+        SET topic = $2
+        WHERE id = $1
+        RETURNING id, ws_id, name, type, members, topic, created_at
+        "#,
+    )
+    .bind(chat.id)
+    .bind(topic)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let event = SystemEvent::TopicChanged {
+        old_topic: chat.topic,
+        new_topic: new.topic.clone(),
+    };
+    insert_system_message(conn, chat.id as _, actor_id, &event).await?;
+    Ok(new)
+}
+
+/// Rename a channel or group, single chats are named after the other member
+async fn rename(
+    conn: &mut PgConnection,
+    chat: Chat,
+    actor_id: u64,
+    name: &str,
+    ctx: &AuditContext,
+) -> Result<Chat, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::UpdateChatError(
+            "Chat name cannot be empty".to_string(),
+        ));
+    }
+    if chat.r#type == ChatType::Single {
+        return Err(AppError::UpdateChatError(
+            "Single chat can't be renamed".to_string(),
+        ));
+    }
+    if chat.name.as_deref() == Some(name) {
+        return Ok(chat);
+    }
+
+    let new: Chat = sqlx::query_as(
+        r#"
+        UPDATE chats
+        SET name = $2
+        WHERE id = $1
+        RETURNING id, ws_id, name, type, members, topic, created_at
+        "#,
+    )
+    .bind(chat.id)
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let event =
+        NewAuditEvent::in_workspace(chat.ws_id, Some(actor_id as _), AuditAction::ChatRenamed)
+            .target("chat", chat.id)
+            .metadata(serde_json::json!({ "name": new.name, "old_name": chat.name }));
+    insert_audit_event(&mut *conn, ctx, event).await?;
+    let event = SystemEvent::ChatRenamed {
+        old_name: chat.name,
+        new_name: new.name.clone(),
+    };
+    insert_system_message(conn, chat.id as _, actor_id, &event).await?;
+    Ok(new)
 }
 
 #[cfg(test)]
@@ -139,7 +571,7 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateChat::new("", &[1, 2], false);
         let chat = state
-            .create_chat(input, 1)
+            .create_chat(input, 1, Some(1), &AuditContext::default())
             .await
             .expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
@@ -152,7 +584,7 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateChat::new("general", &[1, 2, 3], true);
         let chat = state
-            .create_chat(input, 1)
+            .create_chat(input, 1, Some(1), &AuditContext::default())
             .await
             .expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
@@ -183,4 +615,51 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn update_chat_should_apply_all_changes_or_none() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            name: Some("secret".to_string()),
+            topic: Some("plans".to_string()),
+            add_members: vec![4, 42],
+            ..Default::default()
+        };
+        let ret = state
+            .update_chat(2, 1, input, &AuditContext::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let chat = state.get_chat_by_id(2).await?.expect("chat should exist");
+        assert_eq!(chat.name.as_deref(), Some("private"));
+        assert_eq!(chat.topic, None);
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        assert!(state
+            .list_audit_events(1, Default::default())
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_audit_the_applied_changes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            name: Some("private".to_string()),
+            add_members: vec![3, 4],
+            remove_members: vec![5],
+            ..Default::default()
+        };
+        state
+            .update_chat(2, 1, input, &AuditContext::default())
+            .await?;
+
+        // the name and member 3 didn't change, 5 wasn't a member
+        let events = state.list_audit_events(1, Default::default()).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::ChatMembersAdded);
+        assert_eq!(events[0].actor_id, Some(1));
+        assert_eq!(events[0].metadata, serde_json::json!({ "user_ids": [4] }));
+        Ok(())
+    }
 }
@@ -0,0 +1,578 @@
+use std::{str::FromStr, time::Duration};
+
+use chat_core::{Chat, ChatType, ContentType, Message, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, AppState};
+
+use super::{
+    audit::insert_audit_event, user::insert_bot_user, AuditAction, AuditContext, CreateMessage,
+    NewAuditEvent,
+};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+const COMMAND_NAME_MAX_LEN: usize = 32;
+
+/// A custom slash command, forwarded to an http endpoint
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Command {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub url: String,
+    pub bot_id: i64,
+    pub creator_id: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct CreateCommand {
+    /// Command name without the leading slash
+    pub name: String,
+    /// Endpoint the command is forwarded to
+    pub url: String,
+}
+
+/// A reply only the caller sees, it is never stored or pushed to other members
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize, PartialEq)]
+pub struct EphemeralReply {
+    pub chat_id: i64,
+    pub content: String,
+}
+
+/// What sending a message produced
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageOutput {
+    Message(Message),
+    Ephemeral(EphemeralReply),
+}
+
+/// A `/name args` message
+#[derive(Debug, PartialEq)]
+struct SlashCommand<'a> {
+    name: &'a str,
+    args: &'a str,
+}
+
+enum Builtin {
+    Topic,
+    Invite,
+    Leave,
+    Me,
+    Mute,
+    Remind,
+}
+
+/// Body POSTed to a custom command endpoint
+#[derive(Debug, Serialize)]
+struct CommandRequest<'a> {
+    command: &'a str,
+    text: &'a str,
+    chat_id: i64,
+    user_id: i64,
+    user_name: &'a str,
+}
+
+/// Body expected back from a custom command endpoint
+#[derive(Debug, Deserialize)]
+struct CommandResponse {
+    content: String,
+    #[serde(default)]
+    ephemeral: bool,
+}
+
+impl AppState {
+    /// Run the slash command in the message, errors of the command are replied ephemerally
+    pub(crate) async fn run_command(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<MessageOutput, AppError> {
+        match self.dispatch_command(input, chat_id, user_id, ctx).await {
+            Err(AppError::CommandError(e)) => Ok(MessageOutput::ephemeral(chat_id, e)),
+            ret => ret,
+        }
+    }
+
+    async fn dispatch_command(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<MessageOutput, AppError> {
+        let Some(cmd) = SlashCommand::parse(&input.content) else {
+            return Err(AppError::CommandError("Not a command".to_string()));
+        };
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+        };
+        let Some(user) = self.find_user_by_id(user_id as _).await? else {
+            return Err(AppError::NotFound(format!("user id {}", user_id)));
+        };
+
+        let Ok(builtin) = cmd.name.parse::<Builtin>() else {
+            return self.custom_command(&cmd, &chat, &user).await;
+        };
+
+        let reply = match builtin {
+            Builtin::Topic => {
+                let topic = (!cmd.args.is_empty()).then_some(cmd.args);
+                self.update_chat_topic(chat_id, user_id, topic).await?;
+                match topic {
+                    Some(topic) => format!("Topic set to: {}", topic),
+                    None => "Topic cleared".to_string(),
+                }
+            }
+            Builtin::Invite => self.invite_command(cmd.args, &chat, user_id, ctx).await?,
+            Builtin::Leave => {
+                if chat.r#type == ChatType::Single {
+                    return Err(AppError::CommandError(
+                        "You can't leave a single chat".to_string(),
+                    ));
+                }
+                self.remove_chat_members(chat_id, user_id, &[user_id as i64], ctx)
+                    .await?;
+                format!("You left {}", chat.name.as_deref().unwrap_or("the chat"))
+            }
+            Builtin::Me => {
+                if cmd.args.is_empty() {
+                    return Err(AppError::CommandError("Usage: /me <action>".to_string()));
+                }
+                let input = CreateMessage {
+                    content: format!("_{} {}_", user.fullname, cmd.args),
+                    files: input.files,
+                    content_type: ContentType::Text,
+                };
+                let msg = self.insert_message(input, chat_id, user_id).await?;
+                return Ok(MessageOutput::Message(msg));
+            }
+            Builtin::Mute => {
+                let muted = match cmd.args {
+                    "" | "on" => true,
+                    "off" => false,
+                    _ => return Err(AppError::CommandError("Usage: /mute [on|off]".to_string())),
+                };
+                self.set_chat_muted(chat_id, user_id, muted).await?;
+                if muted {
+                    "Chat muted".to_string()
+                } else {
+                    "Chat unmuted".to_string()
+                }
+            }
+            Builtin::Remind => {
+                let usage =
+                    || AppError::CommandError("Usage: /remind <30s|10m|2h|1d> <text>".to_string());
+                let (delay, text) = cmd.args.split_once(char::is_whitespace).ok_or_else(usage)?;
+                let delay = parse_delay(delay).ok_or_else(usage)?;
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(usage());
+                }
+                let reminder = self
+                    .create_reminder(user_id, chat_id, text, Utc::now() + delay)
+                    .await?;
+                format!("I will remind you at {}", reminder.remind_at.to_rfc3339())
+            }
+        };
+
+        Ok(MessageOutput::ephemeral(chat_id, reply))
+    }
+
+    async fn invite_command(
+        &self,
+        args: &str,
+        chat: &Chat,
+        user_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<String, AppError> {
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::CommandError(
+                "You can't invite users into a single chat".to_string(),
+            ));
+        }
+        if args.is_empty() {
+            return Err(AppError::CommandError(
+                "Usage: /invite <email|user id> ...".to_string(),
+            ));
+        }
+
+        let mut ids = vec![];
+        for arg in args.split_whitespace() {
+            let user = match arg.parse::<i64>() {
+                Ok(id) => self.find_user_by_id(id).await?,
+                Err(_) => self.find_user_by_email(arg.trim_start_matches('@')).await?,
+            };
+            match user {
+                Some(user) if user.ws_id == chat.ws_id => ids.push(user.id),
+                _ => return Err(AppError::CommandError(format!("User {} not found", arg))),
+            }
+        }
+
+        let chat = self
+            .add_chat_members(chat.id as _, user_id, &ids, ctx)
+            .await?;
+        Ok(format!(
+            "Invited {} user(s), the chat has {} members now",
+            ids.len(),
+            chat.members.len()
+        ))
+    }
+
+    /// Forward the command to its endpoint and post the response back
+    async fn custom_command(
+        &self,
+        cmd: &SlashCommand<'_>,
+        chat: &Chat,
+        user: &User,
+    ) -> Result<MessageOutput, AppError> {
+        let command: Option<Command> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, bot_id, creator_id, created_at
+            FROM commands
+            WHERE ws_id = $1 AND name = $2
+            "#,
+        )
+        .bind(chat.ws_id)
+        .bind(cmd.name)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(command) = command else {
+            return Err(AppError::CommandError(format!(
+                "Unknown command /{}",
+                cmd.name
+            )));
+        };
+
+        let req = CommandRequest {
+            command: &command.name,
+            text: cmd.args,
+            chat_id: chat.id,
+            user_id: user.id,
+            user_name: &user.fullname,
+        };
+        let res = self
+            .http
+            .post(&command.url)
+            .timeout(COMMAND_TIMEOUT)
+            .json(&req)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        let res: CommandResponse = match res {
+            Ok(res) => res.json().await.map_err(|e| {
+                warn!("Invalid response of command /{}: {}", command.name, e);
+                AppError::CommandError(format!("Command /{} failed", command.name))
+            })?,
+            Err(e) => {
+                warn!("Failed to call command /{}: {}", command.name, e);
+                return Err(AppError::CommandError(format!(
+                    "Command /{} failed",
+                    command.name
+                )));
+            }
+        };
+
+        if res.ephemeral {
+            return Ok(MessageOutput::ephemeral(chat.id as _, res.content));
+        }
+
+        let input = CreateMessage {
+            content: res.content,
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let msg = self
+            .insert_message(input, chat.id as _, command.bot_id as _)
+            .await?;
+        Ok(MessageOutput::Message(msg))
+    }
+
+    /// Register a custom command for the user's workspace, only workspace owners are allowed to do so
+    pub async fn create_command(
+        &self,
+        input: CreateCommand,
+        user: &User,
+        ctx: &AuditContext,
+    ) -> Result<Command, AppError> {
+        self.ensure_command_admin(user).await?;
+
+        let name = input.name.trim_start_matches('/');
+        if name.is_empty()
+            || name.len() > COMMAND_NAME_MAX_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        {
+            return Err(AppError::CreateCommandError(format!(
+                "Invalid command name: {}",
+                input.name
+            )));
+        }
+        if name.parse::<Builtin>().is_ok() {
+            return Err(AppError::CreateCommandError(format!(
+                "/{} is a builtin command",
+                name
+            )));
+        }
+        if !input.url.starts_with("http://") && !input.url.starts_with("https://") {
+            return Err(AppError::CreateCommandError(format!(
+                "Invalid command url: {}",
+                input.url
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let bot_id = insert_bot_user(&mut *tx, user.ws_id, name).await?;
+        let command: Command = sqlx::query_as(
+            r#"
+            INSERT INTO commands (ws_id, name, url, bot_id, creator_id)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, name, url, bot_id, creator_id, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(name)
+        .bind(&input.url)
+        .bind(bot_id)
+        .bind(user.id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::CreateCommandError(format!("/{} already exists", name))
+            }
+            e => e.into(),
+        })?;
+        let event = NewAuditEvent::new(user, AuditAction::CommandCreated)
+            .target("command", command.id)
+            .metadata(serde_json::json!({ "name": command.name, "url": command.url }));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(command)
+    }
+
+    pub async fn list_commands(&self, user: &User) -> Result<Vec<Command>, AppError> {
+        self.ensure_command_admin(user).await?;
+
+        let commands = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, url, bot_id, creator_id, created_at
+            FROM commands
+            WHERE ws_id = $1
+            ORDER BY name
+            "#,
+        )
+        .bind(user.ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(commands)
+    }
+
+    pub async fn delete_command(
+        &self,
+        id: u64,
+        user: &User,
+        ctx: &AuditContext,
+    ) -> Result<(), AppError> {
+        self.ensure_command_admin(user).await?;
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query("DELETE FROM commands WHERE id = $1 AND ws_id = $2")
+            .bind(id as i64)
+            .bind(user.ws_id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("command id {}", id)));
+        }
+        let event = NewAuditEvent::new(user, AuditAction::CommandDeleted).target("command", id);
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn ensure_command_admin(&self, user: &User) -> Result<(), AppError> {
+        if !self
+            .is_workspace_owner(user.ws_id as _, user.id as _)
+            .await?
+        {
+            return Err(AppError::PermissionDenied(
+                "only workspace owners can manage commands".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl MessageOutput {
+    pub fn ephemeral(chat_id: u64, content: impl Into<String>) -> Self {
+        Self::Ephemeral(EphemeralReply {
+            chat_id: chat_id as _,
+            content: content.into(),
+        })
+    }
+}
+
+impl<'a> SlashCommand<'a> {
+    fn parse(content: &'a str) -> Option<Self> {
+        let s = content.strip_prefix('/')?;
+        let (name, args) = match s.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (s, ""),
+        };
+        Some(Self { name, args })
+    }
+}
+
+impl FromStr for Builtin {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "topic" => Ok(Self::Topic),
+            "invite" => Ok(Self::Invite),
+            "leave" => Ok(Self::Leave),
+            "me" => Ok(Self::Me),
+            "mute" => Ok(Self::Mute),
+            "remind" => Ok(Self::Remind),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parse delays like 30s, 10m, 2h or 1d
+fn parse_delay(s: &str) -> Option<chrono::Duration> {
+    let unit = s.chars().last()?;
+    let n: i64 = s[..s.len() - unit.len_utf8()].parse().ok()?;
+    if n <= 0 {
+        return None;
+    }
+    match unit {
+        's' => chrono::Duration::try_seconds(n),
+        'm' => chrono::Duration::try_minutes(n),
+        'h' => chrono::Duration::try_hours(n),
+        'd' => chrono::Duration::try_days(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn slash_command_parse_should_work() {
+        let cmd = SlashCommand::parse("/topic  release 1.0 ").unwrap();
+        assert_eq!(cmd.name, "topic");
+        assert_eq!(cmd.args, "release 1.0");
+        let cmd = SlashCommand::parse("/leave").unwrap();
+        assert_eq!(cmd.name, "leave");
+        assert_eq!(cmd.args, "");
+        assert!(SlashCommand::parse("hello").is_none());
+    }
+
+    #[test]
+    fn parse_delay_should_work() {
+        assert_eq!(parse_delay("30s"), chrono::Duration::try_seconds(30));
+        assert_eq!(parse_delay("2h"), chrono::Duration::try_hours(2));
+        assert_eq!(parse_delay("0m"), None);
+        assert_eq!(parse_delay("10x"), None);
+        assert_eq!(parse_delay("m"), None);
+    }
+
+    #[tokio::test]
+    async fn topic_command_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let input = CreateMessage {
+            content: "/topic release 1.0".to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let ret = state.create_message(input, 1, 1, &ctx).await?;
+        assert!(matches!(ret, MessageOutput::Ephemeral(_)));
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert_eq!(chat.topic.as_deref(), Some("release 1.0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn me_command_should_create_message() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let input = CreateMessage {
+            content: "/me waves".to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let MessageOutput::Message(msg) = state.create_message(input, 1, 1, &ctx).await? else {
+            panic!("expect a message");
+        };
+        assert_eq!(msg.content, "_Tyr Chen waves_");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_command_should_reply_ephemeral() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let input = CreateMessage {
+            content: "/nope".to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let ret = state.create_message(input, 1, 1, &ctx).await?;
+        assert_eq!(ret, MessageOutput::ephemeral(1, "Unknown command /nope"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_and_leave_command_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let input = CreateMessage {
+            content: "/invite daisy@acme.org 4".to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        state.create_message(input, 2, 1, &ctx).await?;
+        let chat = state.get_chat_by_id(2).await?.expect("chat should exist");
+        assert_eq!(chat.members.len(), 5);
+        assert!(chat.members.contains(&4) && chat.members.contains(&5));
+
+        let input = CreateMessage {
+            content: "/leave".to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        state.create_message(input, 2, 1, &ctx).await?;
+        assert!(!state.is_chat_member(2, 1).await?);
+
+        let events = state.list_audit_events(1, Default::default()).await?;
+        let actions: Vec<_> = events.iter().map(|e| e.action).collect();
+        assert_eq!(
+            actions,
+            [
+                AuditAction::ChatMembersRemoved,
+                AuditAction::ChatMembersAdded
+            ]
+        );
+        assert_eq!(events[0].metadata, serde_json::json!({ "user_ids": [1] }));
+        assert_eq!(
+            events[1].metadata,
+            serde_json::json!({ "user_ids": [5, 4] })
+        );
+        Ok(())
+    }
+}
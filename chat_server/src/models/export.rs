@@ -0,0 +1,665 @@
+use std::{
+    io::{Read, Seek},
+    str::FromStr,
+    time::Duration,
+};
+
+use chat_core::{ContentType, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sqlx::{FromRow, PgPool};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    export::{ArchiveFile, ArchiveMessage, ArchiveWriter},
+    AppState,
+};
+
+use super::{
+    audit::insert_audit_event, file::expand_attachments, gc::lock_file_url, AuditAction,
+    AuditContext, ChatFile, NewAuditEvent,
+};
+
+/// Messages are read and written to the archive in pages, progress is saved per page
+const EXPORT_PAGE_SIZE: i64 = 500;
+/// A running job is extended by its worker while it works, it is picked up again once
+/// the lease ran out, e.g. because the server died
+const EXPORT_LEASE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "export_format", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// `messages.json`, an array of messages
+    Json,
+    /// `messages.csv`, one row per message with its plain text
+    Csv,
+    /// `index.html`, a page with inline styles which opens offline
+    Html,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "export_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ExportStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateExport {
+    /// the chat to export, the whole workspace if left out
+    pub chat_id: Option<i64>,
+    pub format: ExportFormat,
+    /// put the attached files in the archive
+    #[serde(default = "default_include_files")]
+    pub include_files: bool,
+}
+
+fn default_include_files() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ExportJob {
+    pub id: i64,
+    pub ws_id: i64,
+    pub requester_id: i64,
+    pub chat_id: Option<i64>,
+    pub format: ExportFormat,
+    pub include_files: bool,
+    pub status: ExportStatus,
+    pub messages_total: i64,
+    pub messages_done: i64,
+    /// file url of the zip archive once done, downloaded like any other file by the
+    /// requester only. Unset again once it expired.
+    pub url: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow)]
+struct ExportRow {
+    id: i64,
+    chat_id: i64,
+    chat_name: Option<String>,
+    sender_id: i64,
+    sender_name: String,
+    content_type: ContentType,
+    content: String,
+    text: String,
+    files: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Queue an export. Members can export their chats, workspace owners any chat or the
+    /// whole workspace.
+    pub async fn create_export(
+        &self,
+        input: CreateExport,
+        user: &User,
+        ctx: &AuditContext,
+    ) -> Result<ExportJob, AppError> {
+        let is_owner = self
+            .is_workspace_owner(user.ws_id as _, user.id as _)
+            .await?;
+        match input.chat_id {
+            Some(chat_id) => {
+                let chat = self.get_chat_by_id(chat_id as _).await?;
+                let allowed = match chat {
+                    Some(chat) if chat.ws_id == user.ws_id => {
+                        is_owner || chat.members.contains(&user.id)
+                    }
+                    _ => false,
+                };
+                if !allowed {
+                    return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+                }
+            }
+            None if !is_owner => {
+                return Err(AppError::PermissionDenied(
+                    "only workspace owners can export the workspace".to_string(),
+                ));
+            }
+            None => {}
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let job: ExportJob = sqlx::query_as(
+            r#"
+            INSERT INTO export_jobs (ws_id, requester_id, chat_id, format, include_files)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, requester_id, chat_id, format, include_files, status,
+                messages_total, messages_done, url, error, created_at, finished_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(input.chat_id)
+        .bind(input.format)
+        .bind(input.include_files)
+        .fetch_one(&mut *tx)
+        .await?;
+        let event = NewAuditEvent::new(user, AuditAction::WorkspaceExportRequested)
+            .target("export", job.id)
+            .metadata(serde_json::json!({ "chat_id": job.chat_id, "format": job.format }));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(job)
+    }
+
+    /// An export of the user, polled for its progress
+    pub async fn get_export(&self, id: u64, user_id: u64) -> Result<ExportJob, AppError> {
+        let job: Option<ExportJob> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requester_id, chat_id, format, include_files, status,
+                messages_total, messages_done, url, error, created_at, finished_at
+            FROM export_jobs
+            WHERE id = $1 AND requester_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        job.ok_or_else(|| AppError::NotFound(format!("export id {}", id)))
+    }
+
+    pub async fn list_exports(&self, user_id: u64) -> Result<Vec<ExportJob>, AppError> {
+        let jobs = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requester_id, chat_id, format, include_files, status,
+                messages_total, messages_done, url, error, created_at, finished_at
+            FROM export_jobs
+            WHERE requester_id = $1
+            ORDER BY id DESC
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    /// Run the next queued export, jobs are claimed with SKIP LOCKED for a lease so
+    /// several servers can share the queue. The lease is extended while the export runs,
+    /// a job whose worker died is picked up again once it ran out. Returns false if
+    /// there was nothing to do.
+    pub async fn process_export_job(&self) -> Result<bool, AppError> {
+        let job: Option<ExportJob> = sqlx::query_as(
+            r#"
+            UPDATE export_jobs
+            SET status = 'running', started_at = now(), messages_done = 0,
+                locked_until = now() + make_interval(secs => $1)
+            WHERE id = (
+                SELECT id
+                FROM export_jobs
+                WHERE status = 'pending'
+                  OR (status = 'running'
+                    AND COALESCE(locked_until, started_at + interval '1 hour') < now())
+                ORDER BY id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, ws_id, requester_id, chat_id, format, include_files, status,
+                messages_total, messages_done, url, error, created_at, finished_at
+            "#,
+        )
+        .bind(EXPORT_LEASE.as_secs_f64())
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(job) = job else {
+            return Ok(false);
+        };
+
+        let lease = JobLease::renew(self.pool.clone(), "export_jobs", job.id, EXPORT_LEASE);
+        let ret = self.run_export(&job).await;
+        drop(lease);
+        let (status, key, url, error) = match ret {
+            Ok(file) => {
+                info!("Export {} done: {}", job.id, file.url());
+                (
+                    ExportStatus::Done,
+                    Some(file.hash_to_path()),
+                    Some(file.url()),
+                    None,
+                )
+            }
+            Err(e) => {
+                warn!("Export {} failed: {}", job.id, e);
+                (ExportStatus::Failed, None, None, Some(e.to_string()))
+            }
+        };
+        sqlx::query(
+            r#"
+            UPDATE export_jobs
+            SET status = $2, archive_key = $3, url = $4, error = $5, finished_at = now(),
+                locked_until = NULL
+            WHERE id = $1
+            "#,
+        )
+        .bind(job.id)
+        .bind(status)
+        .bind(key)
+        .bind(url)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Delete the archives of exports finished more than `ttl_secs` ago, the jobs are
+    /// kept without their url. Returns the number of archives deleted.
+    pub async fn expire_exports(&self, ttl_secs: u64) -> Result<usize, AppError> {
+        let archives: Vec<(String, Option<String>)> = sqlx::query_as(
+            r#"
+            WITH expired AS (
+                SELECT id, archive_key, url
+                FROM export_jobs
+                WHERE archive_key IS NOT NULL
+                  AND finished_at < now() - make_interval(secs => $1)
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE export_jobs e
+            SET archive_key = NULL, url = NULL
+            FROM expired
+            WHERE e.id = expired.id
+            RETURNING expired.archive_key, expired.url
+            "#,
+        )
+        .bind(ttl_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (key, url) in &archives {
+            if let Err(e) = self.delete_archive(key, url.as_deref()).await {
+                warn!("Failed to delete export archive {}: {}", key, e);
+            }
+        }
+        Ok(archives.len())
+    }
+
+    /// Archives are stored by their content like uploads, keep the blob while an upload or
+    /// another export still has the same url
+    async fn delete_archive(&self, key: &str, url: Option<&str>) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(url) = url {
+            lock_file_url(&mut tx, url).await?;
+            let shared: bool = sqlx::query_scalar(
+                r#"
+                SELECT EXISTS (SELECT 1 FROM files WHERE url = $1)
+                    OR EXISTS (SELECT 1 FROM export_jobs WHERE url = $1)
+                "#,
+            )
+            .bind(url)
+            .fetch_one(&mut *tx)
+            .await?;
+            if shared {
+                return Ok(());
+            }
+        }
+        self.store.delete(key).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Write the archive to a temp file and store it by its hash like the workspace files,
+    /// so it is served from its file url. It isn't an upload, so it counts neither against
+    /// the quota nor for the garbage collection.
+    async fn run_export(&self, job: &ExportJob) -> Result<ChatFile, AppError> {
+        let total: i64 = sqlx::query_scalar(
+            r#"
+            SELECT count(*)
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE c.ws_id = $1 AND ($2::bigint IS NULL OR c.id = $2)
+            "#,
+        )
+        .bind(job.ws_id)
+        .bind(job.chat_id)
+        .fetch_one(&self.pool)
+        .await?;
+        sqlx::query("UPDATE export_jobs SET messages_total = $2 WHERE id = $1")
+            .bind(job.id)
+            .bind(total)
+            .execute(&self.pool)
+            .await?;
+
+        let title = match job.chat_id {
+            Some(chat_id) => match self.get_chat_by_id(chat_id as _).await? {
+                Some(chat) => chat.name.unwrap_or_else(|| format!("Chat {}", chat_id)),
+                None => return Err(AppError::NotFound(format!("chat id {}", chat_id))),
+            },
+            None => match self.find_workspace_by_id(job.ws_id as _).await? {
+                Some(ws) => ws.name,
+                None => return Err(AppError::NotFound(format!("workspace id {}", job.ws_id))),
+            },
+        };
+        let mut writer = ArchiveWriter::new(job.format, &title, tempfile::tempfile()?)?;
+
+        let (mut last_chat, mut last_id, mut done) = (0, 0, 0);
+        loop {
+            let rows: Vec<ExportRow> = sqlx::query_as(
+                r#"
+                SELECT m.id, m.chat_id, c.name AS chat_name, m.sender_id,
+                    u.fullname AS sender_name, m.content_type, m.content,
+                    COALESCE(m.plain_text, m.content) AS text, m.files, m.created_at
+                FROM messages m
+                JOIN chats c ON c.id = m.chat_id
+                JOIN users u ON u.id = m.sender_id
+                WHERE c.ws_id = $1 AND ($2::bigint IS NULL OR c.id = $2)
+                  AND (m.chat_id, m.id) > ($3, $4)
+                ORDER BY m.chat_id, m.id
+                LIMIT $5
+                "#,
+            )
+            .bind(job.ws_id)
+            .bind(job.chat_id)
+            .bind(last_chat)
+            .bind(last_id)
+            .bind(EXPORT_PAGE_SIZE)
+            .fetch_all(&self.pool)
+            .await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            (last_chat, last_id) = (last.chat_id, last.id);
+
+            let urls: Vec<String> = rows.iter().flat_map(|r| r.files.iter().cloned()).collect();
+            let found = self.find_attachments(&urls).await?;
+            let mut messages = Vec::with_capacity(rows.len());
+            for row in rows {
+                let mut files = vec![];
+                for attachment in expand_attachments(&found, &row.files, row.sender_id) {
+                    let path = match job.include_files {
+                        true => self.archive_blob(&mut writer, &attachment.url).await?,
+                        false => None,
+                    };
+                    files.push(ArchiveFile {
+                        url: attachment.url,
+                        filename: attachment.filename,
+                        content_type: attachment.content_type,
+                        path,
+                    });
+                }
+                messages.push(ArchiveMessage {
+                    id: row.id,
+                    chat_id: row.chat_id,
+                    chat_name: row.chat_name,
+                    sender_id: row.sender_id,
+                    sender_name: row.sender_name,
+                    content_type: row.content_type,
+                    content: row.content,
+                    text: row.text,
+                    files,
+                    created_at: row.created_at,
+                });
+            }
+            writer.add_messages(&messages)?;
+
+            done += messages.len() as i64;
+            sqlx::query("UPDATE export_jobs SET messages_done = $2 WHERE id = $1")
+                .bind(job.id)
+                .bind(done)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        // copying the document into the archive and hashing it is blocking file io
+        let (mut archive, hash) = tokio::task::spawn_blocking(move || {
+            let mut archive = writer.finish()?;
+            archive.rewind()?;
+            let mut hasher = Sha1::new();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = archive.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+            Ok::<_, AppError>((archive, hex::encode(hasher.finalize())))
+        })
+        .await
+        .map_err(|e| AppError::ExportError(e.to_string()))??;
+        let size = archive.seek(std::io::SeekFrom::End(0))?;
+        archive.rewind()?;
+        let file = ChatFile {
+            ws_id: job.ws_id as _,
+            ext: "zip".to_string(),
+            hash,
+        };
+        let key = file.hash_to_path();
+        self.store
+            .put_file(&key, tokio::fs::File::from_std(archive), size)
+            .await?;
+
+        Ok(file)
+    }
+
+    /// Copy the blob of a file into the archive once, returns its path in the archive or
+    /// None if the blob is gone
+    async fn archive_blob(
+        &self,
+        writer: &mut ArchiveWriter<std::fs::File>,
+        url: &str,
+    ) -> Result<Option<String>, AppError> {
+        let Ok(file) = ChatFile::from_str(url) else {
+            return Ok(None);
+        };
+        let key = file.hash_to_path();
+        let path = format!("files/{}", key);
+        if writer.has_file(&path) {
+            return Ok(Some(path));
+        }
+        match self.store.get(&key).await? {
+            Some(data) => {
+                writer.add_file(&path, &data)?;
+                Ok(Some(path))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Renews the lease of a running job in the background, until dropped. It is dropped
+/// also if the job panicked, so its lease runs out and it is picked up again.
+pub(super) struct JobLease(JoinHandle<()>);
+
+impl JobLease {
+    /// `table` has `status` and `locked_until` columns, e.g. `export_jobs`
+    pub(super) fn renew(pool: PgPool, table: &'static str, id: i64, lease: Duration) -> Self {
+        let sql = format!(
+            "UPDATE {} SET locked_until = now() + make_interval(secs => $2) \
+             WHERE id = $1 AND status = 'running'",
+            table
+        );
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(lease / 3);
+            // the first tick completes immediately, the lease was just taken
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let ret = sqlx::query(&sql)
+                    .bind(id)
+                    .bind(lease.as_secs_f64())
+                    .execute(&pool)
+                    .await;
+                if let Err(e) = ret {
+                    warn!("Failed to renew the lease of {} {}: {}", table, id, e);
+                }
+            }
+        });
+        Self(handle)
+    }
+}
+
+impl Drop for JobLease {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateMessage, utils::random_hex};
+    use anyhow::Result;
+    use axum::body::Bytes;
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    #[tokio::test]
+    async fn export_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("export {}", random_hex(8)));
+        let file = state
+            .upload_file(1, 1, "notes.txt", data.clone(), &ctx)
+            .await?;
+        for content in ["first", "second"] {
+            let input = CreateMessage {
+                content: content.to_string(),
+                files: vec![file.url.clone()],
+                content_type: ContentType::Text,
+            };
+            state.create_message(input, 1, 1, &ctx).await?;
+        }
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let used = state.get_workspace_usage(1).await?.used;
+        let input = CreateExport {
+            chat_id: Some(1),
+            format: ExportFormat::Json,
+            include_files: true,
+        };
+        let job = state.create_export(input, &user, &ctx).await?;
+        assert_eq!(job.status, ExportStatus::Pending);
+        assert!(state.process_export_job().await?);
+        assert!(!state.process_export_job().await?);
+
+        let job = state.get_export(job.id as _, 1).await?;
+        assert_eq!(job.status, ExportStatus::Done);
+        assert_eq!((job.messages_done, job.messages_total), (2, 2));
+        let url = job.url.expect("export should have a url");
+        // the archive is served like a file but isn't an upload
+        assert_eq!(state.get_workspace_usage(1).await?.used, used);
+        assert!(state.can_access_file(&url, 1).await?);
+        assert!(!state.can_access_file(&url, 2).await?);
+
+        let key = ChatFile::from_str(&url)?.hash_to_path();
+        let zip = state
+            .store
+            .get(&key)
+            .await?
+            .expect("archive should be stored");
+        assert_eq!(ChatFile::new(1, "zip", &zip).url(), url);
+        let mut archive = ZipArchive::new(Cursor::new(zip))?;
+        let mut json = String::new();
+        archive
+            .by_name("messages.json")?
+            .read_to_string(&mut json)?;
+        let messages: Vec<serde_json::Value> = serde_json::from_str(&json)?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["sender_name"], "Tyr Chen");
+        let path = messages[0]["files"][0]["path"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let mut blob = vec![];
+        archive.by_name(&path)?.read_to_end(&mut blob)?;
+        assert_eq!(blob, data.to_vec());
+
+        // a requester who left the chat can't download its archive anymore
+        sqlx::query("UPDATE chats SET members = array_remove(members, 1::bigint) WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        assert!(!state.can_access_file(&url, 1).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_permissions_should_be_checked() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(4).await?.expect("user should exist");
+        let ctx = AuditContext::default();
+        let workspace = CreateExport {
+            chat_id: None,
+            format: ExportFormat::Csv,
+            include_files: false,
+        };
+        assert!(state
+            .create_export(workspace.clone(), &user, &ctx)
+            .await
+            .is_err());
+        // user 4 is not in the private channel
+        let private = CreateExport {
+            chat_id: Some(2),
+            ..workspace.clone()
+        };
+        assert!(state.create_export(private, &user, &ctx).await.is_err());
+
+        state.update_workspace_owner(1, 4, &ctx).await?;
+        assert!(state.create_export(workspace, &user, &ctx).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_should_keep_its_lease_while_running() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateExport {
+            chat_id: Some(1),
+            format: ExportFormat::Csv,
+            include_files: false,
+        };
+        let job = state.create_export(input, &user, &ctx).await?;
+        // another server works on it for longer than an hour and still holds the lease
+        sqlx::query(
+            r#"
+            UPDATE export_jobs
+            SET status = 'running', started_at = now() - interval '2 hours',
+                locked_until = now() + interval '1 minute'
+            WHERE id = $1
+            "#,
+        )
+        .bind(job.id)
+        .execute(&state.pool)
+        .await?;
+        assert!(!state.process_export_job().await?);
+
+        // the server died
+        sqlx::query(
+            "UPDATE export_jobs SET locked_until = now() - interval '1 second' WHERE id = $1",
+        )
+        .bind(job.id)
+        .execute(&state.pool)
+        .await?;
+        assert!(state.process_export_job().await?);
+        let job = state.get_export(job.id as _, 1).await?;
+        assert_eq!(job.status, ExportStatus::Done);
+        let url = job.url.expect("export should have a url");
+
+        assert_eq!(state.expire_exports(3600).await?, 0);
+        assert_eq!(state.expire_exports(0).await?, 1);
+        let job = state.get_export(job.id as _, 1).await?;
+        assert_eq!(job.url, None);
+        assert!(!state.can_access_file(&url, 1).await?);
+        let key = ChatFile::from_str(&url)?.hash_to_path();
+        assert!(state.store.stat(&key).await?.is_none());
+        Ok(())
+    }
+}
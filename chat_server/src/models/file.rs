@@ -1,20 +1,387 @@
-use std::{
-    path::{Path, PathBuf},
-    str::FromStr,
-};
+use std::{collections::HashMap, str::FromStr};
 
+use axum::body::Bytes;
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use sqlx::FromRow;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    utils::{image_dimensions, make_thumbnails, thumbnail_ext, ThumbnailSize},
+    AppState,
+};
+
+use super::{
+    audit::insert_audit_event, gc::lock_file_url, AuditAction, AuditContext, ChatFile,
+    NewAuditEvent,
+};
+
+/// Longest filename the files and upload_sessions tables take, in characters
+const MAX_FILENAME_LEN: usize = 255;
+/// Extensions longer than this are cut with the rest of the name
+const MAX_KEPT_EXT_LEN: usize = 16;
+
+/// An uploaded file with the metadata lost by its hash url
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    pub url: String,
+    pub filename: String,
+    pub content_type: String,
+    /// Size in bytes
+    pub size: i64,
+    /// Dimensions of images, thumbnails are served with `?size=small|medium|large`
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub uploader_id: Option<i64>,
+    pub created_at: Option<DateTime<Utc>>,
+}
 
-use crate::error::AppError;
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct SignFile {
+    /// ChatFile url, e.g. `/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.png`
+    pub url: String,
+}
+
+/// A download url which works without a token until it expires
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize, PartialEq)]
+pub struct SignedFileUrl {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Query params of a signed file url
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSignature {
+    /// expiry as a unix timestamp
+    pub exp: i64,
+    /// the user the url was signed for, the file ACL is checked against them
+    pub uid: i64,
+    pub sig: String,
+}
 
-use super::ChatFile;
+impl AppState {
+    /// Store an uploaded file, the type is detected from the content instead of the filename
+    pub async fn upload_file(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        filename: &str,
+        data: Bytes,
+        ctx: &AuditContext,
+    ) -> Result<Attachment, AppError> {
+        let (mime, ext) = sniff_file_type(&data);
+        if !self.config.upload.is_type_allowed(mime) {
+            return Err(AppError::UnsupportedFileType(format!(
+                "{} ({}) is not allowed",
+                filename, mime
+            )));
+        }
+        self.store_file(ws_id, uploader_id, filename, mime, ext, data, ctx)
+            .await
+    }
+
+    /// Store a file of a known type and record its metadata, counted against the quota
+    /// of the workspace like uploads
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn store_file(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        filename: &str,
+        mime: &str,
+        ext: &str,
+        data: Bytes,
+        ctx: &AuditContext,
+    ) -> Result<Attachment, AppError> {
+        let filename = clean_filename(filename);
+        let file = ChatFile::new(ws_id, ext, &data);
+        let key = file.hash_to_path();
+        let size = data.len() as u64;
+        // gc of the same url waits until the row is committed, or the blob is stored again
+        // after gc deleted it
+        let mut tx = self.pool.begin().await?;
+        lock_file_url(&mut tx, &file.url()).await?;
+        let stored = if self.store.exists(&key).await? {
+            info!("File {} already exists: {}", filename, key);
+            false
+        } else {
+            self.reserve_workspace_storage(ws_id, size).await?;
+            if let Err(e) = self.store.put(&key, data.clone()).await {
+                self.release_workspace_storage(ws_id, size).await?;
+                return Err(e);
+            }
+            // identical content is stored once, only new blobs add bytes
+            metrics::counter!("stored_bytes_total").increment(size);
+            self.store_thumbnails(&file, data.clone()).await;
+            true
+        };
+        let (width, height) = match image_dimensions(&data) {
+            Some((w, h)) => (Some(w as i32), Some(h as i32)),
+            None => (None, None),
+        };
+
+        let attachment: Result<Attachment, AppError> = async {
+            let attachment: Attachment = sqlx::query_as(
+                r#"
+                INSERT INTO files (ws_id, url, filename, content_type, size, width, height,
+                    uploader_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING url, filename, content_type, size, width, height, uploader_id,
+                    created_at
+                "#,
+            )
+            .bind(ws_id as i64)
+            .bind(file.url())
+            .bind(&filename)
+            .bind(mime)
+            .bind(size as i64)
+            .bind(width)
+            .bind(height)
+            .bind(uploader_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            let event = NewAuditEvent::in_workspace(
+                ws_id as _,
+                Some(uploader_id as _),
+                AuditAction::FileUploaded,
+            )
+            .target("file", &attachment.url)
+            .metadata(serde_json::json!({
+                "filename": attachment.filename,
+                "content_type": attachment.content_type,
+                "size": attachment.size,
+            }));
+            insert_audit_event(&mut tx, ctx, event).await?;
+            Ok(attachment)
+        }
+        .await;
+        let attachment = match attachment {
+            Ok(attachment) => tx.commit().await.map(|_| attachment).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        let attachment = match attachment {
+            Ok(attachment) => attachment,
+            Err(e) => {
+                // nothing references a blob without metadata, don't keep it nor its bytes
+                if stored {
+                    self.discard_stored_file(ws_id, &file, size).await;
+                }
+                return Err(e);
+            }
+        };
+        metrics::counter!("uploads_total").increment(1);
+        metrics::counter!("upload_bytes_total").increment(size);
+
+        Ok(attachment)
+    }
+
+    /// Undo a store_file which failed after its blob was written
+    async fn discard_stored_file(&self, ws_id: u64, file: &ChatFile, size: u64) {
+        let thumbs = ThumbnailSize::ALL
+            .iter()
+            .filter_map(|size| file.thumbnail_path(*size));
+        for key in std::iter::once(file.hash_to_path()).chain(thumbs) {
+            if let Err(e) = self.store.delete(&key).await {
+                warn!("Failed to delete {}: {}", key, e);
+            }
+        }
+        if let Err(e) = self.release_workspace_storage(ws_id, size).await {
+            warn!("Failed to release {} bytes of workspace {}: {}", size, ws_id, e);
+        }
+    }
+
+    /// Generate and store the thumbnails of an image next to it. A broken image is not
+    /// worth failing the upload for, the original is served instead.
+    async fn store_thumbnails(&self, file: &ChatFile, data: Bytes) {
+        let ext = file.ext.clone();
+        let thumbs = tokio::task::spawn_blocking(move || make_thumbnails(&data, &ext)).await;
+        let thumbs = match thumbs {
+            Ok(Ok(thumbs)) => thumbs,
+            Ok(Err(e)) => {
+                warn!("Failed to make thumbnails of {}: {}", file.url(), e);
+                return;
+            }
+            Err(e) => {
+                warn!("Thumbnail task of {} failed: {}", file.url(), e);
+                return;
+            }
+        };
+        for (size, thumb) in thumbs {
+            let Some(key) = file.thumbnail_path(size) else {
+                continue;
+            };
+            if let Err(e) = self.store.put(&key, thumb).await {
+                warn!("Failed to store thumbnail {}: {}", key, e);
+            }
+        }
+    }
+
+    /// A user may read a file they uploaded, or a file referenced by a message of a chat
+    /// they are a member of. The archive of an export is read by its requester as long as
+    /// they still may export it, i.e. are a member of the chat or own the workspace.
+    pub async fn can_access_file(&self, url: &str, user_id: u64) -> Result<bool, AppError> {
+        let (allowed,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM files WHERE url = $1 AND uploader_id = $2
+            ) OR EXISTS (
+                SELECT 1
+                FROM messages m
+                JOIN chats c ON c.id = m.chat_id
+                WHERE m.files @> ARRAY[$1] AND $2 = ANY(c.members)
+            ) OR EXISTS (
+                SELECT 1
+                FROM export_jobs e
+                JOIN workspaces w ON w.id = e.ws_id
+                LEFT JOIN chats c ON c.id = e.chat_id
+                WHERE e.url = $1 AND e.requester_id = $2
+                  AND (w.owner_id = $2 OR $2 = ANY(c.members))
+            )
+            "#,
+        )
+        .bind(url)
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(allowed)
+    }
+
+    /// Sign a file url for `user_id`, only files the user can read are signed
+    pub async fn sign_file_url(&self, url: &str, user_id: u64) -> Result<SignedFileUrl, AppError> {
+        if ChatFile::from_str(url).is_err() || !self.can_access_file(url, user_id).await? {
+            return Err(AppError::NotFound(
+                "File doesn't exist or you don't have permission to access it".to_string(),
+            ));
+        }
+
+        let expires_at =
+            Utc::now() + chrono::Duration::seconds(self.config.signed_url.ttl_secs as _);
+        let exp = expires_at.timestamp();
+        let sig = hex::encode(
+            self.file_url_mac(url, exp, user_id as _)
+                .finalize()
+                .into_bytes(),
+        );
+        Ok(SignedFileUrl {
+            url: format!("{}?exp={}&uid={}&sig={}", url, exp, user_id, sig),
+            expires_at,
+        })
+    }
+
+    /// Check the signature of a file url, return the user it was signed for
+    pub async fn verify_file_signature(
+        &self,
+        url: &str,
+        signature: &FileSignature,
+    ) -> Result<User, AppError> {
+        let invalid = || AppError::PermissionDenied("invalid or expired file url".to_string());
+        if signature.exp < Utc::now().timestamp() {
+            return Err(invalid());
+        }
+        let sig = hex::decode(&signature.sig).map_err(|_| invalid())?;
+        // verify_slice compares in constant time
+        self.file_url_mac(url, signature.exp, signature.uid)
+            .verify_slice(&sig)
+            .map_err(|_| invalid())?;
+
+        self.find_user_by_id(signature.uid)
+            .await?
+            .ok_or_else(invalid)
+    }
+
+    fn file_url_mac(&self, url: &str, exp: i64, uid: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.file_url_key)
+            .expect("hmac accepts keys of any size");
+        mac.update(format!("{}\n{}\n{}", url, exp, uid).as_bytes());
+        mac
+    }
+
+    /// Look up the metadata of the file urls. The same content may be uploaded several
+    /// times under different names, the upload of `sender_id` wins, then the first one.
+    pub async fn get_attachments(
+        &self,
+        urls: &[String],
+        sender_id: i64,
+    ) -> Result<Vec<Attachment>, AppError> {
+        let found = self.find_attachments(urls).await?;
+        Ok(expand_attachments(&found, urls, sender_id))
+    }
+
+    /// All known uploads of the urls, grouped by url in upload order
+    pub(crate) async fn find_attachments(
+        &self,
+        urls: &[String],
+    ) -> Result<HashMap<String, Vec<Attachment>>, AppError> {
+        let rows: Vec<Attachment> = sqlx::query_as(
+            r#"
+            SELECT url, filename, content_type, size, width, height, uploader_id, created_at
+            FROM files
+            WHERE url = ANY($1)
+            ORDER BY id
+            "#,
+        )
+        .bind(urls)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut ret: HashMap<String, Vec<Attachment>> = HashMap::new();
+        for row in rows {
+            ret.entry(row.url.clone()).or_default().push(row);
+        }
+        Ok(ret)
+    }
+}
+
+pub(crate) fn expand_attachments(
+    found: &HashMap<String, Vec<Attachment>>,
+    urls: &[String],
+    sender_id: i64,
+) -> Vec<Attachment> {
+    urls.iter()
+        .map(|url| {
+            let candidates = found.get(url).map(Vec::as_slice).unwrap_or_default();
+            candidates
+                .iter()
+                .find(|a| a.uploader_id == Some(sender_id))
+                .or_else(|| candidates.first())
+                .cloned()
+                .unwrap_or_else(|| Attachment::from_url(url))
+        })
+        .collect()
+}
+
+impl Attachment {
+    /// Files uploaded before metadata was recorded only have their url
+    fn from_url(url: &str) -> Self {
+        let filename = url.rsplit('/').next().unwrap_or(url).to_string();
+        Self {
+            url: url.to_string(),
+            content_type: mime_guess::from_path(&filename)
+                .first_or_octet_stream()
+                .to_string(),
+            filename,
+            size: 0,
+            width: None,
+            height: None,
+            uploader_id: None,
+            created_at: None,
+        }
+    }
+}
 
 impl ChatFile {
-    pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
+    pub fn new(ws_id: u64, ext: &str, data: &[u8]) -> Self {
         let hash = Sha1::digest(data);
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
+            ext: ext.to_string(),
             hash: hex::encode(hash),
         }
     }
@@ -23,15 +390,28 @@ impl ChatFile {
         format!("/files/{}", self.hash_to_path())
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
-    }
-
+    /// Key of the file in the FileStore
     pub fn hash_to_path(&self) -> String {
         let (part1, part2) = self.hash.split_at(3);
         let (part2, part3) = part2.split_at(3);
         format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
     }
+
+    /// Key of a thumbnail in the FileStore, next to the original. `None` for non images.
+    pub fn thumbnail_path(&self, size: ThumbnailSize) -> Option<String> {
+        let ext = thumbnail_ext(&self.ext)?;
+        let (part1, part2) = self.hash.split_at(3);
+        let (part2, part3) = part2.split_at(3);
+        Some(format!(
+            "{}/{}/{}/{}_{}.{}",
+            self.ws_id,
+            part1,
+            part2,
+            part3,
+            size.as_str(),
+            ext
+        ))
+    }
 }
 
 impl FromStr for ChatFile {
@@ -46,6 +426,8 @@ impl FromStr for ChatFile {
             )));
         };
 
+        // only accept the exact shape produced by hash_to_path, so no `..`, empty or
+        // absolute segments can ever reach the store
         let parts: Vec<&str> = s.split('/').collect();
         if parts.len() != 4 {
             return Err(AppError::ChatFileError(
@@ -56,7 +438,7 @@ impl FromStr for ChatFile {
         let Ok(ws_id) = parts[0].parse::<u64>() else {
             return Err(AppError::ChatFileError(format!(
                 "Invalid workspace id:{}",
-                parts[1]
+                parts[0]
             )));
         };
 
@@ -68,6 +450,20 @@ impl FromStr for ChatFile {
         };
 
         let hash = format!("{}{}{}", parts[1], parts[2], part3);
+        let is_hex =
+            |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_hex(parts[1], 3) || !is_hex(parts[2], 3) || !is_hex(part3, 34) {
+            return Err(AppError::ChatFileError(format!(
+                "Invalid file hash: {}",
+                hash
+            )));
+        }
+        if ext.is_empty() || ext.len() > 10 || !ext.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(AppError::ChatFileError(format!(
+                "Invalid file extension: {}",
+                ext
+            )));
+        }
 
         Ok(Self {
             ws_id,
@@ -77,14 +473,253 @@ impl FromStr for ChatFile {
     }
 }
 
+/// Cut a client supplied filename to what the database takes, keeping a short extension
+pub(crate) fn clean_filename(name: &str) -> String {
+    if name.chars().count() <= MAX_FILENAME_LEN {
+        return name.to_string();
+    }
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty() && ext.chars().count() <= MAX_KEPT_EXT_LEN);
+    match ext {
+        Some(ext) => {
+            let stem: String = name
+                .chars()
+                .take(MAX_FILENAME_LEN - ext.chars().count() - 1)
+                .collect();
+            format!("{}.{}", stem, ext)
+        }
+        None => name.chars().take(MAX_FILENAME_LEN).collect(),
+    }
+}
+
+/// Detect mime type and extension from the magic bytes of the content
+fn sniff_file_type(data: &[u8]) -> (&'static str, &'static str) {
+    match infer::get(data) {
+        Some(t) => (t.mime_type(), t.extension()),
+        None if std::str::from_utf8(data).is_ok() => ("text/plain", "txt"),
+        None => ("application/octet-stream", "bin"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{models::CreateMessage, utils::random_hex};
+    use anyhow::Result;
+    use axum::{extract::Query, http::Uri};
+    use chat_core::ContentType;
 
     #[test]
     fn chat_file_new_should_work() {
-        let file = ChatFile::new(1, "test.txt", b"hello world");
+        let file = ChatFile::new(1, "txt", b"hello world");
         assert_eq!(file.ext, "txt");
         assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
     }
+
+    #[test]
+    fn chat_file_thumbnail_path_should_work() {
+        let file = ChatFile::new(1, "webp", b"hello world");
+        assert_eq!(
+            file.thumbnail_path(ThumbnailSize::Small).unwrap(),
+            "1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed_small.png"
+        );
+        let file = ChatFile::new(1, "txt", b"hello world");
+        assert_eq!(file.thumbnail_path(ThumbnailSize::Small), None);
+    }
+
+    #[test]
+    fn chat_file_from_str_should_reject_bad_paths() {
+        let ok = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+        assert!(ChatFile::from_str(ok).is_ok());
+        for path in [
+            "/files/1/../e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt",
+            "/files/1/2aa/e6c/../../../etc/passwd",
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed./../x",
+            "/files/1/2aa//35c94fcfb415dbe95f408b9ce91ee846ed.txt",
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed_small.png",
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.t/xt",
+        ] {
+            assert!(ChatFile::from_str(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn sniff_file_type_should_work() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(sniff_file_type(png), ("image/png", "png"));
+        assert_eq!(sniff_file_type(b"hello world"), ("text/plain", "txt"));
+        assert_eq!(
+            sniff_file_type(&[0xff, 0xfe, 0x00, 0x01]),
+            ("application/octet-stream", "bin")
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_file_should_count_workspace_usage() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        // files are stored outside of the test db, make the content unique per run
+        let data = Bytes::from(format!("hello {}", random_hex(8)));
+        let file = state
+            .upload_file(1, 1, "hello.exe", data.clone(), &ctx)
+            .await?;
+        assert!(file.url.ends_with(".txt"));
+        assert_eq!(file.filename, "hello.exe");
+        assert_eq!(file.content_type, "text/plain");
+        assert_eq!(file.size, data.len() as i64);
+        // uploading the same content again doesn't count twice
+        state
+            .upload_file(1, 2, "hello.txt", data.clone(), &ctx)
+            .await?;
+
+        let usage = state.get_workspace_usage(1).await?;
+        assert_eq!(usage.used, data.len() as i64);
+        Ok(())
+    }
+
+    #[test]
+    fn clean_filename_should_keep_short_extensions() {
+        assert_eq!(clean_filename("hello.txt"), "hello.txt");
+        let long = format!("{}.tar.gz", "a".repeat(300));
+        let name = clean_filename(&long);
+        assert_eq!(name.chars().count(), MAX_FILENAME_LEN);
+        assert!(name.ends_with("aaa.gz"));
+        let name = clean_filename(&"é".repeat(300));
+        assert_eq!(name.chars().count(), MAX_FILENAME_LEN);
+    }
+
+    #[tokio::test]
+    async fn upload_file_with_long_filename_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("hello {}", random_hex(8)));
+        let filename = format!("{}.txt", "a".repeat(300));
+        let file = state
+            .upload_file(1, 1, &filename, data.clone(), &ctx)
+            .await?;
+        assert_eq!(file.filename.chars().count(), MAX_FILENAME_LEN);
+        assert!(file.filename.ends_with(".txt"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_image_should_make_thumbnails() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        // a unique noise pixel keeps the content unique per run
+        let mut img = image::RgbImage::new(800, 600);
+        let seed = random_hex(3);
+        let seed = hex::decode(seed)?;
+        img.put_pixel(0, 0, image::Rgb([seed[0], seed[1], seed[2]]));
+        let mut buf = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut buf, image::ImageFormat::Png)?;
+
+        let ret = state
+            .upload_file(1, 1, "photo.png", buf.into_inner().into(), &ctx)
+            .await?;
+        assert_eq!(ret.content_type, "image/png");
+        assert_eq!((ret.width, ret.height), (Some(800), Some(600)));
+
+        let file = ChatFile::from_str(&ret.url)?;
+        let small = file.thumbnail_path(ThumbnailSize::Small).unwrap();
+        let large = file.thumbnail_path(ThumbnailSize::Large).unwrap();
+        assert!(state.store.exists(&small).await?);
+        assert!(!state.store.exists(&large).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_access_file_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("secret {}", random_hex(8)));
+        let file = state.upload_file(1, 2, "secret.txt", data, &ctx).await?;
+        assert!(state.can_access_file(&file.url, 2).await?);
+        assert!(!state.can_access_file(&file.url, 3).await?);
+
+        // share it in the private chat of user 1, 2 and 3
+        let input = CreateMessage {
+            content: "look".to_string(),
+            files: vec![file.url.clone()],
+            content_type: ContentType::Text,
+        };
+        state.create_message(input, 2, 2, &ctx).await?;
+        assert!(state.can_access_file(&file.url, 3).await?);
+        assert!(!state.can_access_file(&file.url, 4).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_file_url_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("signed {}", random_hex(8)));
+        let file = state.upload_file(1, 2, "signed.txt", data, &ctx).await?;
+        assert!(state.sign_file_url(&file.url, 3).await.is_err());
+
+        let signed = state.sign_file_url(&file.url, 2).await?;
+        let uri: Uri = signed.url.parse()?;
+        let Query(signature) = Query::<FileSignature>::try_from_uri(&uri)?;
+        let user = state.verify_file_signature(&file.url, &signature).await?;
+        assert_eq!(user.id, 2);
+
+        // the signature is bound to the url, the user and the expiry
+        let other = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+        assert!(state
+            .verify_file_signature(other, &signature)
+            .await
+            .is_err());
+        let forged = FileSignature {
+            uid: 3,
+            ..signature.clone()
+        };
+        assert!(state
+            .verify_file_signature(&file.url, &forged)
+            .await
+            .is_err());
+        let expired = FileSignature {
+            exp: signature.exp - 3600,
+            ..signature
+        };
+        assert!(state
+            .verify_file_signature(&file.url, &expired)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_denied_type_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        // ELF header
+        let data = Bytes::from_static(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0");
+        let ret = state.upload_file(1, 1, "hello.txt", data, &ctx).await;
+        assert!(matches!(ret, Err(AppError::UnsupportedFileType(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_attachments_should_prefer_sender_upload() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("report {}", random_hex(8)));
+        let first = state
+            .upload_file(1, 1, "report.txt", data.clone(), &ctx)
+            .await?;
+        state.upload_file(1, 2, "notes.txt", data, &ctx).await?;
+        let legacy = "/files/1/abc/def/0123456789.png".to_string();
+
+        let urls = vec![first.url.clone(), legacy.clone()];
+        let ret = state.get_attachments(&urls, 2).await?;
+        assert_eq!(ret[0].filename, "notes.txt");
+        assert_eq!(ret[1].filename, "0123456789.png");
+        assert_eq!(ret[1].content_type, "image/png");
+
+        let ret = state.get_attachments(&urls, 3).await?;
+        assert_eq!(ret[0].filename, "report.txt");
+        Ok(())
+    }
 }
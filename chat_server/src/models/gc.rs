@@ -0,0 +1,321 @@
+use std::str::FromStr;
+
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{error::AppError, utils::ThumbnailSize, AppState};
+
+use super::{audit::insert_audit_event, AuditAction, AuditContext, ChatFile, NewAuditEvent};
+
+/// Files are only collected in batches, the next run picks up the rest
+const GC_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct CollectFiles {
+    /// only report what would be deleted
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// urls of the collected files
+    pub files: Vec<String>,
+    /// bytes freed in the store, including thumbnails
+    pub freed_bytes: u64,
+}
+
+#[derive(Debug, FromRow)]
+struct Orphan {
+    url: String,
+    ws_id: i64,
+    size: i64,
+}
+
+impl AppState {
+    /// Delete uploaded files no message or pending scheduled message references, once
+    /// their last upload is older than `grace_secs`. The grace period leaves time to send
+    /// the message after uploading. Each deleted file is audited, `actor` is None when the
+    /// background job collects.
+    pub async fn collect_orphaned_files(
+        &self,
+        ws_id: Option<u64>,
+        grace_secs: u64,
+        dry_run: bool,
+        actor: Option<&User>,
+        ctx: &AuditContext,
+    ) -> Result<GcReport, AppError> {
+        let orphans: Vec<Orphan> = sqlx::query_as(
+            r#"
+            SELECT f.url, f.ws_id, MAX(f.size) AS size
+            FROM files f
+            WHERE $1::bigint IS NULL OR f.ws_id = $1
+            GROUP BY f.url, f.ws_id
+            HAVING MAX(f.created_at) < now() - make_interval(secs => $2)
+              AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.files @> ARRAY[f.url])
+              AND NOT EXISTS (
+                SELECT 1 FROM scheduled_messages s
+                WHERE s.status = 'pending' AND s.files @> ARRAY[f.url]
+              )
+            LIMIT $3
+            "#,
+        )
+        .bind(ws_id.map(|id| id as i64))
+        .bind(grace_secs as f64)
+        .bind(GC_BATCH_SIZE)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut report = GcReport {
+            dry_run,
+            ..Default::default()
+        };
+        for orphan in orphans {
+            let size = if dry_run {
+                let Ok(file) = ChatFile::from_str(&orphan.url) else {
+                    continue;
+                };
+                self.stored_size(&file_keys(&file)).await?
+            } else {
+                match self.delete_orphan(&orphan, grace_secs, actor, ctx).await? {
+                    Some(size) => size,
+                    None => continue,
+                }
+            };
+            report.freed_bytes += size;
+            report.files.push(orphan.url);
+        }
+
+        info!(
+            "Collected {} orphaned files, {} bytes freed (dry run: {})",
+            report.files.len(),
+            report.freed_bytes,
+            dry_run
+        );
+        Ok(report)
+    }
+
+    /// Delete an uploaded file once no message references it anymore, files uploaded
+    /// again within `grace_secs` are kept. Returns the bytes freed in the store, or None
+    /// if the file was kept.
+    pub(crate) async fn delete_unreferenced_file(
+        &self,
+        url: &str,
+        grace_secs: u64,
+        actor: Option<&User>,
+        ctx: &AuditContext,
+    ) -> Result<Option<u64>, AppError> {
+        let orphan: Option<Orphan> = sqlx::query_as(
+            "SELECT url, ws_id, MAX(size) AS size FROM files WHERE url = $1 GROUP BY url, ws_id",
+        )
+        .bind(url)
+        .fetch_optional(&self.pool)
+        .await?;
+        match orphan {
+            Some(orphan) => self.delete_orphan(&orphan, grace_secs, actor, ctx).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_orphan(
+        &self,
+        orphan: &Orphan,
+        grace_secs: u64,
+        actor: Option<&User>,
+        ctx: &AuditContext,
+    ) -> Result<Option<u64>, AppError> {
+        let Ok(file) = ChatFile::from_str(&orphan.url) else {
+            return Ok(None);
+        };
+        let keys = file_keys(&file);
+        // held until the blob is gone, an upload of the same content waits and stores it again
+        let mut tx = self.pool.begin().await?;
+        lock_file_url(&mut tx, &orphan.url).await?;
+        if !forget_orphan(&mut tx, orphan, grace_secs, actor, ctx).await? {
+            // uploaded or shared again in the meantime
+            return Ok(None);
+        }
+        let size = self.stored_size(&keys).await?;
+        // thumbnails first, if the file itself can't be deleted the rollback keeps its row
+        for key in keys.iter().rev() {
+            self.store.delete(key).await?;
+        }
+        tx.commit().await?;
+        self.release_workspace_storage(orphan.ws_id as _, orphan.size as _)
+            .await?;
+        Ok(Some(size))
+    }
+
+    async fn stored_size(&self, keys: &[String]) -> Result<u64, AppError> {
+        let mut size = 0;
+        for key in keys {
+            if let Some(meta) = self.store.stat(key).await? {
+                size += meta.size;
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// Serialize storing and collecting the blob of a file url until the transaction ends, so
+/// a new files row never points to a blob about to be deleted
+pub(super) async fn lock_file_url(conn: &mut PgConnection, url: &str) -> Result<(), AppError> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+        .bind(url)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Drop the metadata of an orphan, checking again it is still one so a file shared since
+/// the scan is kept
+async fn forget_orphan(
+    conn: &mut PgConnection,
+    orphan: &Orphan,
+    grace_secs: u64,
+    actor: Option<&User>,
+    ctx: &AuditContext,
+) -> Result<bool, AppError> {
+    let ret = sqlx::query(
+        r#"
+        DELETE FROM files
+        WHERE url = $1
+          AND NOT EXISTS (
+            SELECT 1 FROM files
+            WHERE url = $1 AND created_at >= now() - make_interval(secs => $2)
+          )
+          AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.files @> ARRAY[$1])
+          AND NOT EXISTS (
+            SELECT 1 FROM scheduled_messages s
+            WHERE s.status = 'pending' AND s.files @> ARRAY[$1]
+          )
+          AND NOT EXISTS (SELECT 1 FROM export_jobs e WHERE e.url = $1)
+        "#,
+    )
+    .bind(&orphan.url)
+    .bind(grace_secs as f64)
+    .execute(&mut *conn)
+    .await?;
+    if ret.rows_affected() == 0 {
+        return Ok(false);
+    }
+    let event = NewAuditEvent::in_workspace(
+        orphan.ws_id,
+        actor.map(|user| user.id),
+        AuditAction::WorkspaceFilesCollected,
+    )
+    .target("file", &orphan.url)
+    .metadata(serde_json::json!({ "size": orphan.size }));
+    insert_audit_event(conn, ctx, event).await?;
+
+    Ok(true)
+}
+
+/// The file and its thumbnails
+fn file_keys(file: &ChatFile) -> Vec<String> {
+    let mut keys = vec![file.hash_to_path()];
+    keys.extend(
+        ThumbnailSize::ALL
+            .iter()
+            .filter_map(|size| file.thumbnail_path(*size)),
+    );
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CreateMessage, CreateScheduledMessage, ListAuditEvents},
+        utils::random_hex,
+    };
+    use anyhow::Result;
+    use axum::body::Bytes;
+    use chat_core::ContentType;
+    use chrono::{Duration, Utc};
+
+    #[tokio::test]
+    async fn collect_orphaned_files_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let orphan = Bytes::from(format!("orphan {}", random_hex(8)));
+        let orphan = state.upload_file(1, 1, "orphan.txt", orphan, &ctx).await?;
+        let shared = Bytes::from(format!("shared {}", random_hex(8)));
+        let shared = state.upload_file(1, 1, "shared.txt", shared, &ctx).await?;
+        let input = CreateMessage {
+            content: "look".to_string(),
+            files: vec![shared.url.clone()],
+            content_type: ContentType::Text,
+        };
+        state.create_message(input, 1, 1, &ctx).await?;
+
+        // everything is still within the grace period
+        let ctx = AuditContext::default();
+        let report = state
+            .collect_orphaned_files(Some(1), 3600, false, None, &ctx)
+            .await?;
+        assert!(report.files.is_empty());
+
+        let report = state
+            .collect_orphaned_files(Some(1), 0, true, None, &ctx)
+            .await?;
+        assert_eq!(report.files, vec![orphan.url.clone()]);
+        assert_eq!(report.freed_bytes, orphan.size as u64);
+        let file = ChatFile::from_str(&orphan.url)?;
+        assert!(state.store.exists(&file.hash_to_path()).await?);
+
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let report = state
+            .collect_orphaned_files(Some(1), 0, false, Some(&owner), &ctx)
+            .await?;
+        assert_eq!(report.files, vec![orphan.url.clone()]);
+        assert!(!state.store.exists(&file.hash_to_path()).await?);
+        assert_eq!(state.get_workspace_usage(1).await?.used, shared.size);
+        let input = ListAuditEvents {
+            action: Some(AuditAction::WorkspaceFilesCollected),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(1, input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].actor_id, Some(1));
+        assert_eq!(events[0].target_id, Some(orphan.url));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_of_pending_scheduled_messages_should_be_kept() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("later {}", random_hex(8)));
+        let file = state.upload_file(1, 1, "later.txt", data, &ctx).await?;
+        let input = CreateScheduledMessage {
+            content: "for tomorrow".to_string(),
+            files: vec![file.url.clone()],
+            content_type: ContentType::Text,
+            send_at: Utc::now() + Duration::hours(1),
+        };
+        let scheduled = state.schedule_message(input, 1, 1).await?;
+
+        let ctx = AuditContext::default();
+        let report = state
+            .collect_orphaned_files(Some(1), 0, false, None, &ctx)
+            .await?;
+        assert!(report.files.is_empty());
+        let ret = state
+            .delete_unreferenced_file(&file.url, 0, None, &ctx)
+            .await?;
+        assert_eq!(ret, None);
+
+        // once cancelled nothing references the file anymore
+        state.cancel_scheduled_message(scheduled.id as _, 1).await?;
+        let report = state
+            .collect_orphaned_files(Some(1), 0, false, None, &ctx)
+            .await?;
+        assert_eq!(report.files, vec![file.url]);
+        Ok(())
+    }
+}
@@ -0,0 +1,851 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use chat_core::{ChatType, ContentType, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    import::{
+        parse_ts, slack_to_text, ConversationKind, SlackArchive, SlackConversation, SlackFile,
+    },
+    utils::random_hex,
+    AppState,
+};
+
+use super::{
+    audit::insert_audit_event, export::JobLease, AuditAction, AuditContext, CreateUser,
+    NewAuditEvent,
+};
+
+/// Message subtypes which are conversation, joins, leaves, topic changes and the like
+/// are left out
+const IMPORTED_SUBTYPES: [&str; 3] = ["file_share", "thread_broadcast", "me_message"];
+/// A running import is renewed by its worker, it is picked up again once the lease ran
+/// out. Importing again only adds what is missing.
+const IMPORT_LEASE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "import_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ImportJob {
+    pub id: i64,
+    pub ws_id: i64,
+    pub requester_id: i64,
+    pub status: ImportStatus,
+    /// what the import did, once done
+    #[schema(value_type = Option<ImportReport>)]
+    pub report: Option<Json<ImportReport>>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// What a worker needs of a job it claimed
+#[derive(Debug, FromRow)]
+struct ClaimedImport {
+    id: i64,
+    ws_id: i64,
+    requester_id: i64,
+    archive_key: Option<String>,
+    slack_token: Option<String>,
+}
+
+/// What an import created, and what it found from an earlier run of the same archive
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ImportReport {
+    pub users_created: u64,
+    /// mapped to a user of the workspace with the same email, or imported before
+    pub users_existing: u64,
+    /// bots, users without an email or with an account in another workspace
+    pub users_skipped: u64,
+    pub chats_created: u64,
+    pub chats_existing: u64,
+    /// direct messages with less than two known members
+    pub chats_skipped: u64,
+    pub messages_imported: u64,
+    pub messages_existing: u64,
+    /// empty messages and events like joins
+    pub messages_skipped: u64,
+    pub files_uploaded: u64,
+    /// files missing from the archive which couldn't be downloaded or were rejected
+    pub files_failed: u64,
+}
+
+/// Sender of an imported message, messages of users who weren't imported are posted by
+/// the admin running the import with the original name in front
+struct Sender {
+    id: i64,
+    prefix: Option<String>,
+}
+
+impl AppState {
+    /// Queue an import of a Slack export into the workspace of `user`. The archive is
+    /// checked and stored here, the import itself runs in the background.
+    pub async fn create_slack_import(
+        &self,
+        user: &User,
+        data: Bytes,
+        slack_token: Option<&str>,
+    ) -> Result<ImportJob, AppError> {
+        // reject archives which can't be read right away
+        SlackArchive::open(data.clone(), self.config.upload.max_file_size)?;
+        let key = format!("imports/{}/{}.zip", user.ws_id, random_hex(16));
+        self.store.put(&key, data).await?;
+
+        let job = sqlx::query_as(
+            r#"
+            INSERT INTO import_jobs (ws_id, requester_id, archive_key, slack_token)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, ws_id, requester_id, status, report, error, created_at, finished_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(&key)
+        .bind(slack_token)
+        .fetch_one(&self.pool)
+        .await;
+        match job {
+            Ok(job) => Ok(job),
+            Err(e) => {
+                if let Err(e) = self.store.delete(&key).await {
+                    warn!("Failed to delete import archive {}: {}", key, e);
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    /// An import of the user, polled for its report
+    pub async fn get_import(&self, id: u64, user_id: u64) -> Result<ImportJob, AppError> {
+        let job: Option<ImportJob> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requester_id, status, report, error, created_at, finished_at
+            FROM import_jobs
+            WHERE id = $1 AND requester_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        job.ok_or_else(|| AppError::NotFound(format!("import id {}", id)))
+    }
+
+    /// Run the next queued import, claimed like exports. Returns false if there was
+    /// nothing to do.
+    pub async fn process_import_job(&self) -> Result<bool, AppError> {
+        match self.claim_import(None).await? {
+            Some(job) => {
+                self.finish_import(job).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Run a queued import right away instead of leaving it to the worker, e.g. in
+    /// chat-admin
+    pub async fn run_import_now(&self, id: u64) -> Result<ImportJob, AppError> {
+        let Some(job) = self.claim_import(Some(id)).await? else {
+            return Err(AppError::NotFound(format!("pending import id {}", id)));
+        };
+        let requester_id = job.requester_id;
+        self.finish_import(job).await?;
+        self.get_import(id, requester_id as _).await
+    }
+
+    async fn claim_import(&self, id: Option<u64>) -> Result<Option<ClaimedImport>, AppError> {
+        let job = sqlx::query_as(
+            r#"
+            UPDATE import_jobs
+            SET status = 'running', started_at = now(),
+                locked_until = now() + make_interval(secs => $1)
+            WHERE id = (
+                SELECT id
+                FROM import_jobs
+                WHERE ($2::bigint IS NULL OR id = $2)
+                  AND (status = 'pending' OR (status = 'running' AND locked_until < now()))
+                ORDER BY id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, ws_id, requester_id, archive_key, slack_token
+            "#,
+        )
+        .bind(IMPORT_LEASE.as_secs_f64())
+        .bind(id.map(|id| id as i64))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    /// Import the archive of a claimed job and record the outcome, the audit event goes
+    /// with the report. The archive and the Slack token are dropped once done.
+    async fn finish_import(&self, job: ClaimedImport) -> Result<(), AppError> {
+        let lease = JobLease::renew(self.pool.clone(), "import_jobs", job.id, IMPORT_LEASE);
+        let ret = self.run_import(&job).await;
+        drop(lease);
+        let (status, report, error) = match ret {
+            Ok(report) => (ImportStatus::Done, Some(Json(report)), None),
+            Err(e) => {
+                warn!("Import {} failed: {}", job.id, e);
+                (ImportStatus::Failed, None, Some(e.to_string()))
+            }
+        };
+        let mut tx = self.pool.begin().await?;
+        if let Some(Json(report)) = &report {
+            let event = NewAuditEvent::in_workspace(
+                job.ws_id,
+                Some(job.requester_id),
+                AuditAction::WorkspaceImported,
+            )
+            .target("import", job.id)
+            .metadata(serde_json::json!(report));
+            insert_audit_event(&mut tx, &AuditContext::default(), event).await?;
+        }
+        sqlx::query(
+            r#"
+            UPDATE import_jobs
+            SET status = $2, report = $3, error = $4, archive_key = NULL, slack_token = NULL,
+                finished_at = now(), locked_until = NULL
+            WHERE id = $1
+            "#,
+        )
+        .bind(job.id)
+        .bind(status)
+        .bind(report)
+        .bind(error)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        if let Some(key) = &job.archive_key {
+            if let Err(e) = self.store.delete(key).await {
+                warn!("Failed to delete import archive {}: {}", key, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn run_import(&self, job: &ClaimedImport) -> Result<ImportReport, AppError> {
+        let Some(admin) = self.find_user_by_id(job.requester_id as _).await? else {
+            return Err(AppError::NotFound(format!("user id {}", job.requester_id)));
+        };
+        let data = match &job.archive_key {
+            Some(key) => self.store.get(key).await?,
+            None => None,
+        };
+        let data = data.ok_or_else(|| AppError::ImportError("the archive is gone".to_string()))?;
+
+        self.import_slack(&admin, data, job.slack_token.as_deref())
+            .await
+    }
+
+    /// Import a Slack export into the workspace. Users are created with a random password
+    /// and get an email to set their own, channels and direct messages become chats and
+    /// messages keep their original time. Everything imported is recorded, so importing
+    /// the same archive again only adds what is new.
+    pub async fn import_slack(
+        &self,
+        admin: &User,
+        data: Bytes,
+        slack_token: Option<&str>,
+    ) -> Result<ImportReport, AppError> {
+        let ws_id = admin.ws_id as u64;
+        let mut archive = SlackArchive::open(data, self.config.upload.max_file_size)?;
+        let mut report = ImportReport::default();
+
+        let users = self
+            .import_slack_users(ws_id, &archive, &mut report)
+            .await?;
+        let names: HashMap<String, String> = archive
+            .users
+            .iter()
+            .map(|u| (u.id.clone(), u.display_name().to_string()))
+            .collect();
+
+        let conversations = archive.conversations.clone();
+        for conversation in &conversations {
+            let Some(chat_id) = self
+                .import_slack_chat(ws_id, conversation, &users, &mut report)
+                .await?
+            else {
+                continue;
+            };
+            self.import_slack_messages(
+                admin,
+                &mut archive,
+                conversation,
+                chat_id,
+                &users,
+                &names,
+                slack_token,
+                &mut report,
+            )
+            .await?;
+        }
+
+        info!(
+            "Imported Slack archive into workspace {}: {:?}",
+            ws_id, report
+        );
+        Ok(report)
+    }
+
+    async fn import_slack_users(
+        &self,
+        ws_id: u64,
+        archive: &SlackArchive,
+        report: &mut ImportReport,
+    ) -> Result<HashMap<String, i64>, AppError> {
+        let ws = self
+            .find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id {}", ws_id)))?;
+        let mut refs = self.find_import_refs(ws_id, "user", "").await?;
+
+        for slack_user in &archive.users {
+            if refs.contains_key(&slack_user.id) {
+                report.users_existing += 1;
+                continue;
+            }
+            let email = match &slack_user.profile.email {
+                Some(email) if !slack_user.is_bot && slack_user.id != "USLACKBOT" => email,
+                _ => {
+                    report.users_skipped += 1;
+                    continue;
+                }
+            };
+
+            let user = match self.find_user_by_email(email).await? {
+                Some(user) if user.ws_id == ws.id => {
+                    report.users_existing += 1;
+                    user
+                }
+                Some(_) => {
+                    warn!(
+                        "Skipped Slack user {}, {} is in another workspace",
+                        slack_user.id, email
+                    );
+                    report.users_skipped += 1;
+                    continue;
+                }
+                None => {
+                    let input = CreateUser {
+                        fullname: slack_user.display_name().to_string(),
+                        email: email.clone(),
+                        workspace: ws.name.clone(),
+                        password: random_hex(32),
+                    };
+                    let user = self.create_user(&input, &AuditContext::default()).await?;
+                    report.users_created += 1;
+                    if !slack_user.deleted {
+                        if let Err(e) = self.send_password_reset(&user).await {
+                            warn!("Failed to send password reset to {}: {}", user.email, e);
+                        }
+                    }
+                    user
+                }
+            };
+
+            sqlx::query(
+                "INSERT INTO import_refs (ws_id, kind, external_id, local_id) VALUES ($1, 'user', $2, $3)",
+            )
+            .bind(ws_id as i64)
+            .bind(&slack_user.id)
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+            refs.insert(slack_user.id.clone(), user.id);
+        }
+
+        Ok(refs)
+    }
+
+    /// Returns the chat of the conversation, None if it can't be imported
+    async fn import_slack_chat(
+        &self,
+        ws_id: u64,
+        conversation: &SlackConversation,
+        users: &HashMap<String, i64>,
+        report: &mut ImportReport,
+    ) -> Result<Option<i64>, AppError> {
+        let refs = self
+            .find_import_refs(ws_id, "chat", &conversation.id)
+            .await?;
+        if let Some(chat_id) = refs.get(&conversation.id) {
+            report.chats_existing += 1;
+            return Ok(Some(*chat_id));
+        }
+
+        let mut members = vec![];
+        let mut seen = HashSet::new();
+        for id in conversation.members.iter().filter_map(|m| users.get(m)) {
+            if seen.insert(*id) {
+                members.push(*id);
+            }
+        }
+        let chat_type = match conversation.kind {
+            ConversationKind::Channel => ChatType::PublicChannel,
+            ConversationKind::Group => ChatType::PrivateChannel,
+            ConversationKind::Dm => ChatType::Single,
+            ConversationKind::Mpim => ChatType::Group,
+        };
+        if matches!(chat_type, ChatType::Single | ChatType::Group) && members.len() < 2 {
+            report.chats_skipped += 1;
+            return Ok(None);
+        }
+        let name = match &conversation.name {
+            Some(name) => Some(self.unique_chat_name(name).await?),
+            None => None,
+        };
+
+        let mut tx = self.pool.begin().await?;
+        let (chat_id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, name, type, members, topic, created_at)
+            VALUES ($1, $2, $3, $4, $5, COALESCE($6, now()))
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(chat_type)
+        .bind(&members)
+        .bind(&conversation.topic)
+        .bind(conversation.created)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO import_refs (ws_id, kind, external_id, local_id) VALUES ($1, 'chat', $2, $3)",
+        )
+        .bind(ws_id as i64)
+        .bind(&conversation.id)
+        .bind(chat_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        report.chats_created += 1;
+        Ok(Some(chat_id))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn import_slack_messages(
+        &self,
+        admin: &User,
+        archive: &mut SlackArchive,
+        conversation: &SlackConversation,
+        chat_id: i64,
+        users: &HashMap<String, i64>,
+        names: &HashMap<String, String>,
+        slack_token: Option<&str>,
+        report: &mut ImportReport,
+    ) -> Result<(), AppError> {
+        let ws_id = admin.ws_id as u64;
+        let prefix = format!("{}:", conversation.id);
+        let existing = self.find_import_refs(ws_id, "message", &prefix).await?;
+
+        for message in archive.messages(conversation)? {
+            let external_id = format!("{}{}", prefix, message.ts);
+            if existing.contains_key(&external_id) {
+                report.messages_existing += 1;
+                continue;
+            }
+            let created_at = parse_ts(&message.ts);
+            let subtype_ok = message
+                .subtype
+                .as_deref()
+                .map_or(true, |s| IMPORTED_SUBTYPES.contains(&s));
+            if !subtype_ok || created_at.is_none() {
+                report.messages_skipped += 1;
+                continue;
+            }
+
+            let sender = match message.user.as_ref().and_then(|u| users.get(u)) {
+                Some(id) => Sender {
+                    id: *id,
+                    prefix: None,
+                },
+                None => {
+                    let name = message
+                        .user
+                        .as_ref()
+                        .and_then(|u| names.get(u))
+                        .cloned()
+                        .or_else(|| {
+                            message
+                                .user_profile
+                                .as_ref()
+                                .and_then(|p| p.real_name.clone())
+                        })
+                        .unwrap_or_else(|| "unknown".to_string());
+                    Sender {
+                        id: admin.id,
+                        prefix: Some(name),
+                    }
+                }
+            };
+
+            let mut files = vec![];
+            for file in &message.files {
+                match self
+                    .import_slack_file(ws_id, sender.id as _, archive, file, slack_token)
+                    .await
+                {
+                    Ok(url) => {
+                        report.files_uploaded += 1;
+                        files.push(url);
+                    }
+                    Err(e) => {
+                        warn!("Failed to import Slack file {}: {}", file.id, e);
+                        report.files_failed += 1;
+                    }
+                }
+            }
+
+            let mut text = slack_to_text(&message.text, names);
+            if text.trim().is_empty() {
+                text = message
+                    .files
+                    .iter()
+                    .map(|f| f.filename())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
+            if text.trim().is_empty() {
+                report.messages_skipped += 1;
+                continue;
+            }
+            if let Some(name) = &sender.prefix {
+                text = format!("{}: {}", name, text);
+            }
+
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("SET LOCAL chat.importing = 'on'")
+                .execute(&mut *tx)
+                .await?;
+            let (message_id,): (i64,) = sqlx::query_as(
+                r#"
+                INSERT INTO messages (chat_id, sender_id, content_type, content, plain_text, files,
+                    created_at)
+                VALUES ($1, $2, $3, $4, $4, $5, $6)
+                RETURNING id
+                "#,
+            )
+            .bind(chat_id)
+            .bind(sender.id)
+            .bind(ContentType::Text)
+            .bind(&text)
+            .bind(&files)
+            .bind(created_at)
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query(
+                "INSERT INTO import_refs (ws_id, kind, external_id, local_id) VALUES ($1, 'message', $2, $3)",
+            )
+            .bind(ws_id as i64)
+            .bind(&external_id)
+            .bind(message_id)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            report.messages_imported += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Store a shared file from the archive, or download it from Slack if the export
+    /// doesn't include it. Returns the url of the stored file.
+    async fn import_slack_file(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        archive: &mut SlackArchive,
+        file: &SlackFile,
+        slack_token: Option<&str>,
+    ) -> Result<String, AppError> {
+        let data = match archive.file(file)? {
+            Some(data) => Bytes::from(data),
+            None => self.download_slack_file(file, slack_token).await?,
+        };
+        let attachment = self
+            .upload_file(
+                ws_id,
+                uploader_id,
+                file.filename(),
+                data,
+                &AuditContext::default(),
+            )
+            .await?;
+        Ok(attachment.url)
+    }
+
+    async fn download_slack_file(
+        &self,
+        file: &SlackFile,
+        slack_token: Option<&str>,
+    ) -> Result<Bytes, AppError> {
+        let (Some(url), Some(token)) = (&file.url_private_download, slack_token) else {
+            return Err(AppError::ImportError(
+                "file is not in the archive and no Slack token was given".to_string(),
+            ));
+        };
+        // the token is only ever sent to Slack
+        let is_slack = reqwest::Url::parse(url).is_ok_and(|url| {
+            url.scheme() == "https"
+                && url
+                    .host_str()
+                    .is_some_and(|host| host == "slack.com" || host.ends_with(".slack.com"))
+        });
+        if !is_slack {
+            return Err(AppError::ImportError(format!("{} is not a Slack url", url)));
+        }
+
+        let max_size = self.config.upload.max_file_size;
+        let res = self
+            .http
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| AppError::ImportError(e.to_string()))?;
+        if res.content_length().is_some_and(|len| len > max_size) {
+            return Err(AppError::FileTooLarge(file.filename().to_string()));
+        }
+        let data = res
+            .bytes()
+            .await
+            .map_err(|e| AppError::ImportError(e.to_string()))?;
+        if data.len() as u64 > max_size {
+            return Err(AppError::FileTooLarge(file.filename().to_string()));
+        }
+        Ok(data)
+    }
+
+    /// Imported ids of a kind whose external id starts with `prefix`
+    async fn find_import_refs(
+        &self,
+        ws_id: u64,
+        kind: &str,
+        prefix: &str,
+    ) -> Result<HashMap<String, i64>, AppError> {
+        let refs: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT external_id, local_id
+            FROM import_refs
+            WHERE ws_id = $1 AND kind = $2 AND starts_with(external_id, $3)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(kind)
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(refs.into_iter().collect())
+    }
+
+    /// Chat names are unique across workspaces, a taken name gets a numbered suffix
+    async fn unique_chat_name(&self, name: &str) -> Result<String, AppError> {
+        let taken: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM chats WHERE name = $1 OR starts_with(name, $1 || '-')",
+        )
+        .bind(name)
+        .fetch_all(&self.pool)
+        .await?;
+        let taken: HashSet<String> = taken.into_iter().collect();
+
+        let name = name.to_string();
+        if !taken.contains(&name) {
+            return Ok(name);
+        }
+        let mut n = 2;
+        while taken.contains(&format!("{}-{}", name, n)) {
+            n += 1;
+        }
+        Ok(format!("{}-{}", name, n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ListAuditEvents;
+    use anyhow::Result;
+    use std::io::{Cursor, Write};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    fn slack_export() -> Result<Bytes> {
+        let files = [
+            (
+                "users.json",
+                r#"[
+                    {"id": "U1", "name": "alice", "real_name": "Alice", "profile": {"email": "alice@acme.org"}},
+                    {"id": "U2", "name": "eve", "profile": {"email": "eve@acme.org", "real_name": "Eve Smith"}},
+                    {"id": "U3", "name": "frank", "deleted": true, "profile": {"email": "frank@acme.org"}},
+                    {"id": "B1", "name": "bot", "is_bot": true, "profile": {}}
+                ]"#,
+            ),
+            (
+                "channels.json",
+                r#"[{"id": "C1", "name": "general", "created": 1600000000, "members": ["U1", "U2"],
+                     "topic": {"value": "all hands"}}]"#,
+            ),
+            (
+                "dms.json",
+                r#"[{"id": "D1", "members": ["U1", "U3"]}, {"id": "D2", "members": ["U1", "B1"]}]"#,
+            ),
+            (
+                "general/2020-09-13.json",
+                r#"[
+                    {"type": "message", "user": "U1", "text": "hi &lt;3 <@U2>", "ts": "1600000100.000200"},
+                    {"type": "message", "subtype": "channel_join", "user": "U3", "text": "joined", "ts": "1600000050.000100"},
+                    {"type": "message", "user": "B1", "text": "beep", "ts": "1600000200.000300"},
+                    {"type": "message", "subtype": "file_share", "user": "U1", "text": "", "ts": "1600000300.000400",
+                     "files": [{"id": "F1", "name": "notes.txt"}]}
+                ]"#,
+            ),
+            (
+                "D1/2020-09-13.json",
+                r#"[{"type": "message", "user": "U3", "text": "bye", "ts": "1600000400.000500"}]"#,
+            ),
+            ("__uploads/F1/notes.txt", "imported notes"),
+        ];
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in files {
+            zip.start_file(name, SimpleFileOptions::default())?;
+            zip.write_all(content.as_bytes())?;
+        }
+        Ok(Bytes::from(zip.finish()?.into_inner()))
+    }
+
+    #[tokio::test]
+    async fn import_slack_should_work_and_be_idempotent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let admin = state.find_user_by_id(1).await?.expect("user should exist");
+        let data = slack_export()?;
+
+        let report = state.import_slack(&admin, data.clone(), None).await?;
+        assert_eq!(
+            report,
+            ImportReport {
+                users_created: 2,
+                users_existing: 1,
+                users_skipped: 1,
+                chats_created: 2,
+                chats_skipped: 1,
+                messages_imported: 4,
+                messages_skipped: 1,
+                files_uploaded: 1,
+                ..Default::default()
+            }
+        );
+        let resets: i64 = sqlx::query_scalar("SELECT count(*) FROM password_resets")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(resets, 1);
+
+        let chat_id: i64 = sqlx::query_scalar(
+            "SELECT local_id FROM import_refs WHERE kind = 'chat' AND external_id = 'C1'",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let chat = state
+            .get_chat_by_id(chat_id as _)
+            .await?
+            .expect("chat should exist");
+        assert_eq!(chat.name.as_deref(), Some("general-2"));
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        assert_eq!(chat.topic.as_deref(), Some("all hands"));
+        assert_eq!(chat.created_at.timestamp(), 1600000000);
+
+        let messages: Vec<(String, Vec<String>, i64)> = sqlx::query_as(
+            "SELECT content, files, extract(epoch FROM created_at)::bigint FROM messages WHERE chat_id = $1 ORDER BY created_at",
+        )
+        .bind(chat_id)
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].0, "hi <3 @Eve Smith");
+        assert_eq!(messages[0].2, 1600000100);
+        assert_eq!(messages[1].0, "bot: beep");
+        assert_eq!(messages[2].0, "notes.txt");
+        assert_eq!(messages[2].1.len(), 1);
+
+        let report = state.import_slack(&admin, data, None).await?;
+        assert_eq!(
+            report,
+            ImportReport {
+                users_existing: 3,
+                users_skipped: 1,
+                chats_existing: 2,
+                chats_skipped: 1,
+                messages_existing: 4,
+                messages_skipped: 1,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queued_import_should_run_in_the_background() -> Result<()> {
+        // the file in the archive is too large to import
+        let (_tdb, state) = AppState::new_for_test_with(|c| c.upload.max_file_size = 8).await?;
+        let admin = state.find_user_by_id(1).await?.expect("user should exist");
+        assert!(state
+            .create_slack_import(&admin, Bytes::from_static(b"not a zip"), None)
+            .await
+            .is_err());
+
+        let job = state
+            .create_slack_import(&admin, slack_export()?, Some("xoxp-secret"))
+            .await?;
+        assert_eq!(job.status, ImportStatus::Pending);
+        assert!(state.process_import_job().await?);
+        assert!(!state.process_import_job().await?);
+
+        let job = state.get_import(job.id as _, 1).await?;
+        assert_eq!(job.status, ImportStatus::Done);
+        let report = job.report.expect("import should have a report").0;
+        assert_eq!(report.messages_imported, 4);
+        assert_eq!((report.files_uploaded, report.files_failed), (0, 1));
+        let (key, token): (Option<String>, Option<String>) =
+            sqlx::query_as("SELECT archive_key, slack_token FROM import_jobs WHERE id = $1")
+                .bind(job.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!((key, token), (None, None));
+        assert!(state.get_import(job.id as _, 2).await.is_err());
+        let input = ListAuditEvents {
+            action: Some(AuditAction::WorkspaceImported),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(1, input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].target_id, Some(job.id.to_string()));
+        Ok(())
+    }
+}
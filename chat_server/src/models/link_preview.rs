@@ -0,0 +1,357 @@
+use std::{collections::HashMap, time::Duration};
+
+use chat_core::LinkPreview;
+use reqwest::Url;
+use sqlx::PgConnection;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    unfurl::{extract_urls, parse_oembed, parse_page, LinkFetcher, PageMeta},
+    AppState,
+};
+
+/// Messages unfurled per poll of the worker
+const UNFURL_BATCH_SIZE: i64 = 10;
+/// How long a claimed job is left to its worker before another one retries it
+const UNFURL_LEASE: Duration = Duration::from_secs(300);
+const MAX_UNFURL_ATTEMPTS: i32 = 3;
+
+impl AppState {
+    /// Queue the urls of a new message for the unfurl worker, within its transaction
+    pub(crate) async fn enqueue_unfurl(
+        &self,
+        conn: &mut PgConnection,
+        message_id: i64,
+        text: &str,
+    ) -> Result<(), AppError> {
+        if !self.config.unfurl.enabled {
+            return Ok(());
+        }
+        let urls = extract_urls(text, self.config.unfurl.max_links);
+        if urls.is_empty() {
+            return Ok(());
+        }
+        sqlx::query("INSERT INTO unfurl_jobs (message_id, urls) VALUES ($1, $2)")
+            .bind(message_id)
+            .bind(urls)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Unfurl a batch of queued messages, jobs are claimed with SKIP LOCKED for a lease
+    /// so several servers can share the queue. A job is deleted once its previews are
+    /// stored, one whose worker failed or died is retried after the lease, at most
+    /// `MAX_UNFURL_ATTEMPTS` times. Returns the number of messages processed.
+    pub async fn process_unfurl_jobs(&self, fetcher: &dyn LinkFetcher) -> Result<usize, AppError> {
+        let jobs: Vec<(i64, Vec<String>, i32)> = sqlx::query_as(
+            r#"
+            UPDATE unfurl_jobs
+            SET attempts = attempts + 1, locked_until = now() + make_interval(secs => $2)
+            WHERE message_id IN (
+                SELECT message_id
+                FROM unfurl_jobs
+                WHERE locked_until IS NULL OR locked_until < now()
+                ORDER BY created_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING message_id, urls, attempts
+            "#,
+        )
+        .bind(UNFURL_BATCH_SIZE)
+        .bind(UNFURL_LEASE.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (message_id, urls, attempts) in &jobs {
+            if *attempts > MAX_UNFURL_ATTEMPTS {
+                warn!("Give up unfurling message {}", message_id);
+                self.delete_unfurl_job(*message_id).await?;
+                continue;
+            }
+            if let Err(e) = self.unfurl_message(fetcher, *message_id, urls).await {
+                warn!("Failed to unfurl message {}: {}", message_id, e);
+            }
+        }
+        Ok(jobs.len())
+    }
+
+    /// Store the previews of a message and finish its job in one go, so a retry never
+    /// adds them twice
+    async fn unfurl_message(
+        &self,
+        fetcher: &dyn LinkFetcher,
+        message_id: i64,
+        urls: &[String],
+    ) -> Result<(), AppError> {
+        let mut metas = vec![];
+        for url in urls {
+            let meta = match self.cached_preview(url).await? {
+                Some(meta) => meta,
+                None => match self.unfurl(fetcher, url).await {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        info!("Skip preview of {}: {}", url, e);
+                        continue;
+                    }
+                },
+            };
+            if !meta.is_empty() {
+                metas.push((url, meta));
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut previews = vec![];
+        for (url, meta) in metas {
+            previews.push(insert_link_preview(&mut *tx, message_id, url, meta).await?);
+        }
+        sqlx::query("DELETE FROM unfurl_jobs WHERE message_id = $1")
+            .bind(message_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        if !previews.is_empty() {
+            self.notify_message_updated(message_id, &previews).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_unfurl_job(&self, message_id: i64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM unfurl_jobs WHERE message_id = $1")
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// A preview of the url made within the cache ttl, by any message
+    async fn cached_preview(&self, url: &str) -> Result<Option<PageMeta>, AppError> {
+        let row: Option<LinkPreview> = sqlx::query_as(
+            r#"
+            SELECT id, message_id, url, title, description, image, site_name, created_at
+            FROM link_previews
+            WHERE url = $1 AND created_at > now() - make_interval(secs => $2)
+            ORDER BY created_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(url)
+        .bind(self.config.unfurl.cache_ttl_secs as f64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|p| PageMeta {
+            title: p.title,
+            description: p.description,
+            image: p.image,
+            site_name: p.site_name,
+            oembed: None,
+        }))
+    }
+
+    async fn unfurl(&self, fetcher: &dyn LinkFetcher, url: &str) -> anyhow::Result<PageMeta> {
+        let timeout = Duration::from_secs(self.config.unfurl.timeout_secs);
+        let url = Url::parse(url)?;
+        let page = tokio::time::timeout(timeout, fetcher.fetch(&url)).await??;
+
+        if page.content_type.starts_with("image/") {
+            return Ok(PageMeta {
+                image: Some(page.url.to_string()),
+                ..Default::default()
+            });
+        }
+        let mut meta = parse_page(&page.body, &page.url);
+        if let Some(oembed) = meta.oembed.take() {
+            if meta.title.is_none() || meta.image.is_none() {
+                match tokio::time::timeout(timeout, fetcher.fetch(&oembed)).await {
+                    Ok(Ok(doc)) => parse_oembed(&doc.body, &mut meta)?,
+                    Ok(Err(e)) => warn!("Failed to fetch oembed {}: {}", oembed, e),
+                    Err(_) => warn!("Fetching oembed {} timed out", oembed),
+                }
+            }
+        }
+        Ok(meta)
+    }
+
+    /// Tell the chat members to re-render the message with its previews
+    async fn notify_message_updated(
+        &self,
+        message_id: i64,
+        previews: &[LinkPreview],
+    ) -> Result<(), AppError> {
+        let previews = serde_json::to_string(previews).map_err(anyhow::Error::from)?;
+        sqlx::query(
+            r#"
+            SELECT pg_notify('message_updated', json_build_object(
+                'message_id', m.id,
+                'chat_id', m.chat_id,
+                'link_previews', $2::json,
+                'members', c.members)::text)
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE m.id = $1
+            "#,
+        )
+        .bind(message_id)
+        .bind(previews)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn list_link_previews(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<LinkPreview>>, AppError> {
+        let rows: Vec<LinkPreview> = sqlx::query_as(
+            r#"
+            SELECT id, message_id, url, title, description, image, site_name, created_at
+            FROM link_previews
+            WHERE message_id = ANY($1)
+            ORDER BY id
+            "#,
+        )
+        .bind(message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut ret: HashMap<i64, Vec<LinkPreview>> = HashMap::new();
+        for row in rows {
+            ret.entry(row.message_id).or_default().push(row);
+        }
+        Ok(ret)
+    }
+}
+
+async fn insert_link_preview(
+    conn: &mut PgConnection,
+    message_id: i64,
+    url: &str,
+    meta: PageMeta,
+) -> Result<LinkPreview, AppError> {
+    let preview = sqlx::query_as(
+        r#"
+        INSERT INTO link_previews (message_id, url, title, description, image, site_name)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, message_id, url, title, description, image, site_name, created_at
+        "#,
+    )
+    .bind(message_id)
+    .bind(url)
+    .bind(meta.title)
+    .bind(meta.description)
+    .bind(meta.image)
+    .bind(meta.site_name)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(preview)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        models::{AuditContext, CreateMessage, ListMessages, MessageOutput},
+        unfurl::FetchedPage,
+    };
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use chat_core::ContentType;
+
+    /// Serves a fixed page for every url and counts the requests
+    #[derive(Default)]
+    struct StandIn {
+        hits: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LinkFetcher for StandIn {
+        async fn fetch(&self, url: &Url) -> anyhow::Result<FetchedPage> {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            Ok(FetchedPage {
+                url: url.clone(),
+                content_type: "text/html".to_string(),
+                body: r#"<meta property="og:title" content="Example"><meta property="og:image" content="/a.png">"#.to_string(),
+            })
+        }
+    }
+
+    async fn send(state: &AppState, content: &str) -> Result<i64> {
+        let input = CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        match state
+            .create_message(input, 1, 1, &AuditContext::default())
+            .await?
+        {
+            MessageOutput::Message(msg) => Ok(msg.id),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn unfurl_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|c| c.unfurl.enabled = true).await?;
+        let fetcher = StandIn::default();
+        let id = send(&state, "look at https://example.com/post").await?;
+        send(&state, "no links").await?;
+
+        assert_eq!(state.process_unfurl_jobs(&fetcher).await?, 1);
+        assert_eq!(state.process_unfurl_jobs(&fetcher).await?, 0);
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 10,
+        };
+        let messages = state.list_messages(input, 1).await?;
+        let msg = messages.iter().find(|m| m.id == id).unwrap();
+        assert_eq!(msg.link_previews.len(), 1);
+        assert_eq!(msg.link_previews[0].title.as_deref(), Some("Example"));
+        assert_eq!(
+            msg.link_previews[0].image.as_deref(),
+            Some("https://example.com/a.png")
+        );
+
+        // the same url again comes from the cache
+        send(&state, "again https://example.com/post").await?;
+        state.process_unfurl_jobs(&fetcher).await?;
+        assert_eq!(fetcher.hits.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unfurl_job_of_a_dead_worker_should_be_retried() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|c| c.unfurl.enabled = true).await?;
+        let fetcher = StandIn::default();
+        let id = send(&state, "look at https://example.com/post").await?;
+        // claimed by a worker which died before finishing
+        sqlx::query(
+            "UPDATE unfurl_jobs SET attempts = 1, locked_until = now() + interval '1 hour'",
+        )
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.process_unfurl_jobs(&fetcher).await?, 0);
+
+        sqlx::query("UPDATE unfurl_jobs SET locked_until = now() - interval '1 second'")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.process_unfurl_jobs(&fetcher).await?, 1);
+        let previews = state.list_link_previews(&[id]).await?;
+        assert_eq!(previews[&id].len(), 1);
+        assert_eq!(state.process_unfurl_jobs(&fetcher).await?, 0);
+        Ok(())
+    }
+}
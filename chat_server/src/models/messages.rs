@@ -1,16 +1,30 @@
-use chat_core::Message;
+use chat_core::{telemetry::current_traceparent, ContentType, LinkPreview, Message};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 
 use crate::{error::AppError, AppState};
 
-use super::ChatFile;
+use super::{
+    file::{expand_attachments, Attachment},
+    rich_text::RichText,
+    system_message::SystemEvent,
+    AuditContext, ChatFile, MessageOutput,
+};
 use std::str::FromStr;
 use utoipa::{ToSchema, IntoParams};
 
+/// Max size of the stored content, json escaped as it goes into the pg_notify payload
+/// which Postgres limits to 8000 bytes together with the members of the chat
+const MAX_CONTENT_SIZE: usize = 6 * 1024;
+
 #[derive(Debug, Clone, Serialize, ToSchema,Deserialize)]
 pub struct CreateMessage {
+    /// Plain text, or a json RichText document if content_type is rich
     pub content: String,
     pub files: Vec<String>,
+    #[serde(default)]
+    pub content_type: ContentType,
 }
 
 #[derive(Debug, Clone, Serialize, IntoParams,ToSchema, Deserialize)]
@@ -19,45 +33,131 @@ pub struct ListMessages {
     pub limit: u64,
 }
 
+/// A message with its files expanded into attachments
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize, PartialEq)]
+pub struct MessageDetail {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content_type: ContentType,
+    pub content: String,
+    pub files: Vec<Attachment>,
+    pub link_previews: Vec<LinkPreview>,
+    /// The structured payload of a system message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<SystemEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
 impl AppState {
+    /// Create a message, messages starting with `/` are slash commands and `//` escapes the slash.
+    /// Commands changing the chat record audit events with `ctx`.
     pub async fn create_message(
         &self,
+        mut input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<MessageOutput, AppError> {
+        if input.content_type == ContentType::Text {
+            if input.content.starts_with("//") {
+                input.content.remove(0);
+            } else if input.content.starts_with('/') {
+                return self.run_command(input, chat_id, user_id, ctx).await;
+            }
+        }
+
+        let message = self.insert_message(input, chat_id, user_id).await?;
+        Ok(MessageOutput::Message(message))
+    }
+
+    /// Insert a message as is, without running slash commands
+    pub(crate) async fn insert_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let message = self.insert_message_in(&mut tx, input, chat_id, user_id).await?;
+        tx.commit().await?;
+        Ok(message)
+    }
+
+    /// Insert a message within the caller's transaction, so it is only created if the rest
+    /// of the transaction commits
+    pub(crate) async fn insert_message_in(
+        &self,
+        conn: &mut PgConnection,
         input: CreateMessage,
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.config.server.base_dir;
         // verify content - not empty
         if input.content.is_empty() {
             return Err(AppError::CreateMessageError(
                 "Content cannot be empty".to_string(),
             ));
         }
+        // rich text is stored sanitized and in canonical form
+        let (content, plain_text) = match input.content_type {
+            ContentType::Text => (input.content.clone(), input.content),
+            ContentType::Rich => {
+                let doc = RichText::parse(&input.content)?;
+                (doc.to_json(), doc.plain_text())
+            }
+            ContentType::System => {
+                return Err(AppError::CreateMessageError(
+                    "System messages are written by the server".to_string(),
+                ))
+            }
+        };
+        if json_size(&content) > MAX_CONTENT_SIZE {
+            return Err(AppError::CreateMessageError(format!(
+                "Content is longer than {} bytes",
+                MAX_CONTENT_SIZE
+            )));
+        }
 
-        // verify files exists
+        // verify files exist and the sender may read them, otherwise sharing a guessed url
+        // into a chat would hand the file to all of its members
         for s in &input.files {
             let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+            if !self.can_access_file(s, user_id).await?
+                || !self.store.exists(&file.hash_to_path()).await?
+            {
                 return Err(AppError::CreateMessageError(format!(
                     "File {} doesn't exist",
                     s
                 )));
             }
         }
+        // notify_server continues the trace of this request when it pushes the message,
+        // the trigger puts the setting into the pg_notify payload
+        if let Some(traceparent) = current_traceparent() {
+            sqlx::query("SELECT set_config('chat.traceparent', $1, true)")
+                .bind(traceparent)
+                .execute(&mut *conn)
+                .await?;
+        }
         // create mesasge
         let message: Message = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content, files)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
+            INSERT INTO messages (chat_id, sender_id, content_type, content, plain_text, files)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, chat_id, sender_id, content_type, content, files, created_at
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
-        .bind(input.content)
+        .bind(input.content_type)
+        .bind(content)
+        .bind(&plain_text)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *conn)
         .await?;
+        self.enqueue_unfurl(conn, message.id, &plain_text).await?;
+        metrics::counter!("messages_created_total").increment(1);
 
         Ok(message)
     }
@@ -66,12 +166,12 @@ impl AppState {
         &self,
         input: ListMessages,
         chat_id: u64,
-    ) -> Result<Vec<Message>, AppError> {
+    ) -> Result<Vec<MessageDetail>, AppError> {
         let last_id = input.last_id.unwrap_or(i64::MAX as _);
 
         let messages: Vec<Message> = sqlx::query_as(
             r#"
-        SELECT id, chat_id, sender_id, content, files, created_at
+        SELECT id, chat_id, sender_id, content_type, content, files, created_at
         FROM messages
         WHERE chat_id = $1
         AND id < $2
@@ -85,6 +185,64 @@ impl AppState {
         .fetch_all(&self.pool)
         .await?;
 
+        let urls: Vec<String> = messages
+            .iter()
+            .flat_map(|m| m.files.iter().cloned())
+            .collect();
+        let found = self.find_attachments(&urls).await?;
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        let mut previews = self.list_link_previews(&ids).await?;
+        let messages = messages
+            .into_iter()
+            .map(|m| MessageDetail {
+                files: expand_attachments(&found, &m.files, m.sender_id),
+                link_previews: previews.remove(&m.id).unwrap_or_default(),
+                event: match m.content_type {
+                    ContentType::System => serde_json::from_str(&m.content).ok(),
+                    _ => None,
+                },
+                id: m.id,
+                chat_id: m.chat_id,
+                sender_id: m.sender_id,
+                content_type: m.content_type,
+                content: m.content,
+                created_at: m.created_at,
+            })
+            .collect();
+
         Ok(messages)
     }
 }
+
+fn json_size(s: &str) -> usize {
+    serde_json::to_string(s).map_or(usize::MAX, |s| s.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn long_message_should_fit_in_the_notification() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let content = "a long message ".repeat(5 * 1024 / 15);
+        let input = CreateMessage {
+            content: content.clone(),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let msg = state.insert_message(input, 1, 1).await?;
+        assert_eq!(msg.content, content);
+
+        // quotes take twice the room once escaped
+        let input = CreateMessage {
+            content: "\"".repeat(MAX_CONTENT_SIZE / 2),
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        let ret = state.insert_message(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+}
@@ -1,14 +1,44 @@
+mod audit;
 mod chat;
+mod command;
+mod export;
 mod file;
+mod gc;
+mod import;
+mod link_preview;
 mod messages;
+mod password_reset;
+mod reminder;
+mod retention;
+mod rich_text;
+mod scheduled;
+mod system_message;
+mod upload;
 mod user;
+mod webhook;
 mod workspace;
 
-pub use chat::CreateChat;
+pub use audit::{AuditAction, AuditContext, AuditEvent, ListAuditEvents, NewAuditEvent};
+pub use chat::{CreateChat, UpdateChat};
+pub use command::{Command, CreateCommand, EphemeralReply, MessageOutput};
 use chat_core::User;
-pub use messages::{CreateMessage, ListMessages};
+pub use export::{CreateExport, ExportFormat, ExportJob, ExportStatus};
+pub use file::{Attachment, FileSignature, SignFile, SignedFileUrl};
+pub use gc::{CollectFiles, GcReport};
+pub use import::{ImportJob, ImportReport, ImportStatus};
+pub use messages::{CreateMessage, ListMessages, MessageDetail};
+pub use password_reset::ResetPassword;
+pub use retention::{Retention, RetentionPolicy, RetentionPurge};
+pub use rich_text::{Block, Inline, Mark, RichText};
+pub use scheduled::{
+    CreateScheduledMessage, ScheduledMessage, ScheduledStatus, UpdateScheduledMessage,
+};
+pub use system_message::SystemEvent;
 use serde::{Deserialize, Serialize};
+pub use upload::{CreateUpload, UploadProgress, UploadSession};
 pub use user::{CreateUser, SigninUser};
+pub use webhook::{CreateWebhook, Webhook, WebhookMessage};
+pub use workspace::WorkspaceUsage;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatFile {
@@ -0,0 +1,133 @@
+use chat_core::User;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{error::AppError, mail::Email, utils::random_hex, AppState};
+
+use super::{
+    audit::insert_audit_event, user::hash_password, AuditAction, AuditContext, NewAuditEvent,
+};
+
+/// Set a new password with the token of a reset email
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+impl AppState {
+    /// Email the user a single use link to set their password
+    pub async fn send_password_reset(&self, user: &User) -> Result<(), AppError> {
+        let config = &self.config.mail;
+        let token = random_hex(32);
+        let expires_at = Utc::now() + Duration::seconds(config.reset_ttl_secs as _);
+        sqlx::query(
+            "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind(token_hash(&token))
+        .bind(user.id)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        let email = Email {
+            from: config.from.clone(),
+            to: user.email.clone(),
+            subject: "Set your password".to_string(),
+            text: format!(
+                "Hi {},\n\nan account was created for you. Set your password at {}?token={}\n\n\
+                 The link expires at {}.",
+                user.fullname,
+                config.reset_url,
+                token,
+                expires_at.format("%Y-%m-%d %H:%M UTC")
+            ),
+        };
+        self.mailer.send(email).await
+    }
+
+    /// Returns the user whose password was changed
+    pub async fn reset_password(
+        &self,
+        input: ResetPassword,
+        ctx: &AuditContext,
+    ) -> Result<User, AppError> {
+        if input.password.is_empty() {
+            return Err(AppError::PasswordResetError(
+                "password cannot be empty".to_string(),
+            ));
+        }
+        let password_hash = hash_password(&input.password)?;
+
+        let mut tx = self.pool.begin().await?;
+        let user_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE password_resets SET used_at = now()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id
+            "#,
+        )
+        .bind(token_hash(&input.token))
+        .fetch_optional(&mut *tx)
+        .await?;
+        let user_id = user_id
+            .ok_or_else(|| AppError::PasswordResetError("invalid or expired token".to_string()))?;
+
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET password_hash = $2 WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(password_hash)
+        .fetch_one(&mut *tx)
+        .await?;
+        let event =
+            NewAuditEvent::new(&user, AuditAction::UserPasswordReset).target("user", user.id);
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(user)
+    }
+}
+
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SigninUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.send_password_reset(&user).await?;
+
+        // the token itself only went out by email, plant a known one
+        let token = random_hex(32);
+        sqlx::query("UPDATE password_resets SET token_hash = $1")
+            .bind(token_hash(&token))
+            .execute(&state.pool)
+            .await?;
+
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "new-secret".to_string(),
+        };
+        let ctx = AuditContext::default();
+        state.reset_password(input.clone(), &ctx).await?;
+        let signin = SigninUser::new(&user.email, "new-secret");
+        assert!(state.verify_user(&signin).await?.is_some());
+
+        // tokens are single use
+        assert!(state.reset_password(input, &ctx).await.is_err());
+        Ok(())
+    }
+}
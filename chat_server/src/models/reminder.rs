@@ -0,0 +1,79 @@
+use chat_core::Reminder;
+use chrono::{DateTime, Utc};
+
+use crate::{error::AppError, AppState};
+
+impl AppState {
+    pub async fn create_reminder(
+        &self,
+        user_id: u64,
+        chat_id: u64,
+        content: &str,
+        remind_at: DateTime<Utc>,
+    ) -> Result<Reminder, AppError> {
+        let reminder = sqlx::query_as(
+            r#"
+            INSERT INTO reminders (user_id, chat_id, content, remind_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, user_id, chat_id, content, remind_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(content)
+        .bind(remind_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(reminder)
+    }
+
+    /// Mark due reminders as delivered, the reminder_due trigger pushes them to their users.
+    /// Locked rows are skipped so several servers can run this at the same time.
+    pub async fn deliver_due_reminders(&self) -> Result<Vec<Reminder>, AppError> {
+        let reminders = sqlx::query_as(
+            r#"
+            UPDATE reminders
+            SET delivered_at = now()
+            WHERE id IN (
+                SELECT id
+                FROM reminders
+                WHERE delivered_at IS NULL AND remind_at <= now()
+                ORDER BY remind_at
+                LIMIT 100
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, chat_id, content, remind_at
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reminders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn deliver_due_reminders_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .create_reminder(1, 1, "stand up", Utc::now() - chrono::Duration::seconds(1))
+            .await?;
+        state
+            .create_reminder(1, 1, "go home", Utc::now() + chrono::Duration::hours(1))
+            .await?;
+
+        let reminders = state.deliver_due_reminders().await?;
+        assert_eq!(reminders.len(), 1);
+        assert_eq!(reminders[0].content, "stand up");
+
+        let reminders = state.deliver_due_reminders().await?;
+        assert!(reminders.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashSet;
+
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{error::AppError, AppState};
+
+use super::{audit::insert_audit_event, AuditAction, AuditContext, NewAuditEvent};
+
+/// Messages are deleted in batches so a large purge doesn't hold long locks
+const PURGE_BATCH_SIZE: i64 = 1000;
+/// About a hundred years, longer is as good as forever
+const MAX_RETENTION_DAYS: u32 = 36500;
+
+/// How long the messages of a workspace or chat are kept
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Retention {
+    /// follow the workspace, chats only
+    Inherit,
+    Forever,
+    /// delete messages older than this many days
+    Days {
+        days: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct RetentionPolicy {
+    pub retention: Retention,
+    /// block any purge, e.g. while the history is needed for litigation
+    #[serde(default)]
+    pub legal_hold: bool,
+}
+
+/// An entry of the purge audit trail
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct RetentionPurge {
+    pub id: i64,
+    pub ws_id: i64,
+    pub chat_id: i64,
+    pub retention_days: i32,
+    /// messages created before this were purged
+    pub purged_before: DateTime<Utc>,
+    pub messages: i64,
+    /// attachments only the purged messages referenced
+    pub files: i64,
+    pub freed_bytes: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Retention {
+    /// Stored as days: NULL follows the workspace, 0 keeps messages forever
+    fn from_days(days: Option<i32>) -> Self {
+        match days {
+            None => Self::Inherit,
+            Some(0) => Self::Forever,
+            Some(days) => Self::Days { days: days as _ },
+        }
+    }
+
+    fn to_days(self) -> Result<Option<i32>, AppError> {
+        match self {
+            Self::Inherit => Ok(None),
+            Self::Forever => Ok(Some(0)),
+            Self::Days { days: 0 } => Err(AppError::RetentionError(
+                "retention must be at least one day".to_string(),
+            )),
+            Self::Days { days } if days > MAX_RETENTION_DAYS => Err(AppError::RetentionError(
+                format!("retention must be at most {} days", MAX_RETENTION_DAYS),
+            )),
+            Self::Days { days } => Ok(Some(days as _)),
+        }
+    }
+}
+
+impl AppState {
+    pub async fn get_workspace_retention(&self, ws_id: u64) -> Result<RetentionPolicy, AppError> {
+        let row: Option<(Option<i32>, bool)> =
+            sqlx::query_as("SELECT retention_days, legal_hold FROM workspaces WHERE id = $1")
+                .bind(ws_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        let (days, legal_hold) =
+            row.ok_or_else(|| AppError::NotFound(format!("workspace id {}", ws_id)))?;
+        let retention = match Retention::from_days(days) {
+            Retention::Inherit => Retention::Forever,
+            retention => retention,
+        };
+        Ok(RetentionPolicy {
+            retention,
+            legal_hold,
+        })
+    }
+
+    /// Set the policy of the workspace of `user`
+    pub async fn set_workspace_retention(
+        &self,
+        user: &User,
+        input: RetentionPolicy,
+        ctx: &AuditContext,
+    ) -> Result<RetentionPolicy, AppError> {
+        if input.retention == Retention::Inherit {
+            return Err(AppError::RetentionError(
+                "a workspace has nothing to inherit from".to_string(),
+            ));
+        }
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE workspaces SET retention_days = $2, legal_hold = $3 WHERE id = $1")
+            .bind(user.ws_id)
+            .bind(input.retention.to_days()?)
+            .bind(input.legal_hold)
+            .execute(&mut *tx)
+            .await?;
+        let event = NewAuditEvent::new(user, AuditAction::WorkspaceRetentionUpdated)
+            .metadata(serde_json::json!(input));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(input)
+    }
+
+    pub async fn get_chat_retention(&self, chat_id: u64) -> Result<RetentionPolicy, AppError> {
+        let row: Option<(Option<i32>, bool)> =
+            sqlx::query_as("SELECT retention_days, legal_hold FROM chats WHERE id = $1")
+                .bind(chat_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        let (days, legal_hold) =
+            row.ok_or_else(|| AppError::NotFound(format!("chat id {}", chat_id)))?;
+        Ok(RetentionPolicy {
+            retention: Retention::from_days(days),
+            legal_hold,
+        })
+    }
+
+    /// Set the policy of a chat in the workspace of `user`
+    pub async fn set_chat_retention(
+        &self,
+        chat_id: u64,
+        user: &User,
+        input: RetentionPolicy,
+        ctx: &AuditContext,
+    ) -> Result<RetentionPolicy, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            "UPDATE chats SET retention_days = $2, legal_hold = $3 WHERE id = $1 AND ws_id = $4",
+        )
+        .bind(chat_id as i64)
+        .bind(input.retention.to_days()?)
+        .bind(input.legal_hold)
+        .bind(user.ws_id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+        }
+        let event = NewAuditEvent::new(user, AuditAction::ChatRetentionUpdated)
+            .target("chat", chat_id)
+            .metadata(serde_json::json!(input));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(input)
+    }
+
+    /// Delete messages older than the retention of their chat, and the attachments only
+    /// they referenced. Chats or workspaces under legal hold are skipped, the hold is
+    /// checked again by every delete so a hold set during a purge takes effect at once.
+    /// Each purge is recorded in the purge trail and the audit log, `actor` is None when
+    /// the background job purges.
+    pub async fn purge_expired_messages(
+        &self,
+        ws_id: Option<u64>,
+        actor: Option<&User>,
+        ctx: &AuditContext,
+    ) -> Result<Vec<RetentionPurge>, AppError> {
+        let chats: Vec<(i64, i64, i32)> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, COALESCE(c.retention_days, w.retention_days)
+            FROM chats c
+            JOIN workspaces w ON w.id = c.ws_id
+            WHERE ($1::bigint IS NULL OR c.ws_id = $1)
+              AND NOT c.legal_hold AND NOT w.legal_hold
+              AND COALESCE(c.retention_days, w.retention_days, 0) > 0
+            "#,
+        )
+        .bind(ws_id.map(|id| id as i64))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut purges = vec![];
+        for (chat_id, ws_id, days) in chats {
+            // a day count stored before the cap may not fit in a date
+            let Some(before) = Utc::now().checked_sub_signed(Duration::days(days as _)) else {
+                warn!("Skip purging chat {}, retention of {} days", chat_id, days);
+                continue;
+            };
+            let (messages, urls) = self.delete_messages_before(chat_id, before).await?;
+            if messages == 0 {
+                continue;
+            }
+
+            let (mut files, mut freed_bytes) = (0, 0);
+            for url in &urls {
+                if let Some(size) = self
+                    .delete_unreferenced_file(url, self.config.gc.grace_secs, actor, ctx)
+                    .await?
+                {
+                    files += 1;
+                    freed_bytes += size;
+                }
+            }
+
+            let mut tx = self.pool.begin().await?;
+            let purge: RetentionPurge = sqlx::query_as(
+                r#"
+                INSERT INTO retention_purges (ws_id, chat_id, retention_days, purged_before,
+                    messages, files, freed_bytes)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                RETURNING id, ws_id, chat_id, retention_days, purged_before, messages, files,
+                    freed_bytes, created_at
+                "#,
+            )
+            .bind(ws_id)
+            .bind(chat_id)
+            .bind(days)
+            .bind(before)
+            .bind(messages)
+            .bind(files)
+            .bind(freed_bytes as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            let event = NewAuditEvent::in_workspace(
+                ws_id,
+                actor.map(|user| user.id),
+                AuditAction::WorkspaceMessagesPurged,
+            )
+            .target("chat", chat_id)
+            .metadata(serde_json::json!({
+                "purge_id": purge.id,
+                "messages": messages,
+                "files": files,
+                "freed_bytes": freed_bytes,
+            }));
+            insert_audit_event(&mut tx, ctx, event).await?;
+            tx.commit().await?;
+            info!(
+                "Purged {} messages and {} files of chat {}",
+                messages, files, chat_id
+            );
+            purges.push(purge);
+        }
+
+        Ok(purges)
+    }
+
+    /// Latest purges of the workspace
+    pub async fn list_retention_purges(&self, ws_id: u64) -> Result<Vec<RetentionPurge>, AppError> {
+        let purges = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, chat_id, retention_days, purged_before, messages, files,
+                freed_bytes, created_at
+            FROM retention_purges
+            WHERE ws_id = $1
+            ORDER BY created_at DESC
+            LIMIT 100
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(purges)
+    }
+
+    /// Returns the number of messages deleted and the files they referenced
+    async fn delete_messages_before(
+        &self,
+        chat_id: i64,
+        before: DateTime<Utc>,
+    ) -> Result<(i64, HashSet<String>), AppError> {
+        let mut messages = 0;
+        let mut urls = HashSet::new();
+        loop {
+            let deleted: Vec<(Vec<String>,)> = sqlx::query_as(
+                r#"
+                DELETE FROM messages
+                WHERE id IN (
+                    SELECT m.id
+                    FROM messages m
+                    JOIN chats c ON c.id = m.chat_id
+                    JOIN workspaces w ON w.id = c.ws_id
+                    WHERE m.chat_id = $1 AND m.created_at < $2
+                      AND NOT c.legal_hold AND NOT w.legal_hold
+                    LIMIT $3
+                )
+                RETURNING files
+                "#,
+            )
+            .bind(chat_id)
+            .bind(before)
+            .bind(PURGE_BATCH_SIZE)
+            .fetch_all(&self.pool)
+            .await?;
+
+            let n = deleted.len() as i64;
+            messages += n;
+            urls.extend(deleted.into_iter().flat_map(|(files,)| files));
+            if n < PURGE_BATCH_SIZE {
+                break;
+            }
+        }
+
+        Ok((messages, urls))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CreateMessage, ListAuditEvents},
+        utils::random_hex,
+    };
+    use anyhow::Result;
+    use axum::body::Bytes;
+    use chat_core::ContentType;
+
+    async fn send(state: &AppState, chat_id: u64, files: Vec<String>) -> Result<i64> {
+        let input = CreateMessage {
+            content: "old news".to_string(),
+            files,
+            content_type: ContentType::Text,
+        };
+        let message = state.insert_message(input, chat_id, 1).await?;
+        Ok(message.id)
+    }
+
+    #[test]
+    fn retention_should_map_to_days() -> Result<()> {
+        for retention in [
+            Retention::Inherit,
+            Retention::Forever,
+            Retention::Days { days: 30 },
+        ] {
+            assert_eq!(Retention::from_days(retention.to_days()?), retention);
+        }
+        assert!(Retention::Days { days: 0 }.to_days().is_err());
+        assert!(Retention::Days { days: 36500 }.to_days().is_ok());
+        assert!(Retention::Days { days: 36501 }.to_days().is_err());
+        assert!(Retention::Days { days: u32::MAX }.to_days().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_should_skip_a_retention_past_the_calendar() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        send(&state, 1, vec![]).await?;
+        sqlx::query("UPDATE chats SET retention_days = $1 WHERE id = 1")
+            .bind(i32::MAX)
+            .execute(&state.pool)
+            .await?;
+
+        let ctx = AuditContext::default();
+        let purges = state.purge_expired_messages(Some(1), None, &ctx).await?;
+        assert!(purges.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_expired_messages_should_respect_policies() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = Bytes::from(format!("purged {}", random_hex(8)));
+        let file = state.upload_file(1, 1, "purged.txt", data, &ctx).await?;
+        let purged = send(&state, 1, vec![file.url.clone()]).await?;
+        let held = send(&state, 2, vec![]).await?;
+        let kept = send(&state, 3, vec![]).await?;
+        let fresh = send(&state, 1, vec![]).await?;
+        sqlx::query(
+            "UPDATE messages SET created_at = now() - interval '10 days' WHERE id = ANY($1)",
+        )
+        .bind(vec![purged, held, kept])
+        .execute(&state.pool)
+        .await?;
+        sqlx::query("UPDATE files SET created_at = now() - interval '10 days'")
+            .execute(&state.pool)
+            .await?;
+
+        let days = |days| RetentionPolicy {
+            retention: Retention::Days { days },
+            legal_hold: false,
+        };
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let ctx = AuditContext::default();
+        state.set_workspace_retention(&owner, days(7), &ctx).await?;
+        let hold = RetentionPolicy {
+            retention: Retention::Inherit,
+            legal_hold: true,
+        };
+        state.set_chat_retention(2, &owner, hold, &ctx).await?;
+        let forever = RetentionPolicy {
+            retention: Retention::Forever,
+            legal_hold: false,
+        };
+        state.set_chat_retention(3, &owner, forever, &ctx).await?;
+
+        let purges = state.purge_expired_messages(Some(1), None, &ctx).await?;
+        assert_eq!(purges.len(), 1);
+        assert_eq!(purges[0].chat_id, 1);
+        assert_eq!(purges[0].messages, 1);
+        assert_eq!(purges[0].files, 1);
+        assert_eq!(state.list_retention_purges(1).await?, purges);
+        let input = ListAuditEvents {
+            action: Some(AuditAction::WorkspaceMessagesPurged),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(1, input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].actor_id, None);
+        assert_eq!(events[0].target_id.as_deref(), Some("1"));
+
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages ORDER BY id")
+            .fetch_all(&state.pool)
+            .await?;
+        assert_eq!(ids, vec![held, kept, fresh]);
+        assert_eq!(state.get_workspace_usage(1).await?.used, 0);
+
+        // a workspace hold stops everything
+        let mut policy = days(1);
+        policy.legal_hold = true;
+        state.set_workspace_retention(&owner, policy, &ctx).await?;
+        sqlx::query("UPDATE messages SET created_at = now() - interval '10 days'")
+            .execute(&state.pool)
+            .await?;
+        let purges = state
+            .purge_expired_messages(Some(1), Some(&owner), &ctx)
+            .await?;
+        assert!(purges.is_empty());
+        Ok(())
+    }
+}
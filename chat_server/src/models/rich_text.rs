@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+
+/// Max nesting of quotes and lists
+const MAX_DEPTH: usize = 4;
+/// Max number of nodes in a document
+const MAX_NODES: usize = 2000;
+const MAX_LANGUAGE_LEN: usize = 32;
+
+/// A rich text message, sent as the json `content` of a message with `content_type: rich`.
+/// The format has no raw html, so clients can render it without sanitizing again.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RichText {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Block {
+    Paragraph {
+        children: Vec<Inline>,
+    },
+    Quote {
+        children: Vec<Block>,
+    },
+    Code {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+        text: String,
+    },
+    List {
+        #[serde(default)]
+        ordered: bool,
+        items: Vec<Vec<Block>>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Inline {
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        marks: Vec<Mark>,
+    },
+    Code {
+        text: String,
+    },
+    Link {
+        url: String,
+        children: Vec<Inline>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Mark {
+    Bold,
+    Italic,
+    Strike,
+}
+
+impl RichText {
+    /// Parse and sanitize a json document, returns the document in canonical form
+    pub fn parse(content: &str) -> Result<Self, AppError> {
+        let doc: RichText = serde_json::from_str(content)
+            .map_err(|e| AppError::CreateMessageError(format!("invalid rich text: {}", e)))?;
+        let mut nodes = 0;
+        let blocks = sanitize_blocks(doc.blocks, 0, &mut nodes)?;
+        if blocks.is_empty() {
+            return Err(AppError::CreateMessageError(
+                "Content cannot be empty".to_string(),
+            ));
+        }
+        Ok(Self { blocks })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("rich text always serializes")
+    }
+
+    /// Plain text projection for search and notification previews, links keep their url
+    pub fn plain_text(&self) -> String {
+        let mut out = String::new();
+        blocks_text(&self.blocks, &mut out);
+        out.trim_end().to_string()
+    }
+}
+
+fn sanitize_blocks(
+    blocks: Vec<Block>,
+    depth: usize,
+    nodes: &mut usize,
+) -> Result<Vec<Block>, AppError> {
+    if depth > MAX_DEPTH {
+        return Err(AppError::CreateMessageError(
+            "rich text is nested too deep".to_string(),
+        ));
+    }
+    let mut ret = vec![];
+    for block in blocks {
+        count(nodes)?;
+        let block = match block {
+            Block::Paragraph { children } => {
+                let children = sanitize_inlines(children, nodes)?;
+                if children.is_empty() {
+                    continue;
+                }
+                Block::Paragraph { children }
+            }
+            Block::Quote { children } => {
+                let children = sanitize_blocks(children, depth + 1, nodes)?;
+                if children.is_empty() {
+                    continue;
+                }
+                Block::Quote { children }
+            }
+            Block::Code { language, text } => {
+                let text = clean_text(&text);
+                if text.trim().is_empty() {
+                    continue;
+                }
+                // the language ends up in a css class name, keep it boring
+                let language = language.filter(|l| {
+                    !l.is_empty()
+                        && l.len() <= MAX_LANGUAGE_LEN
+                        && l.chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "+-#_.".contains(c))
+                });
+                Block::Code { language, text }
+            }
+            Block::List { ordered, items } => {
+                let mut clean = vec![];
+                for item in items {
+                    let item = sanitize_blocks(item, depth + 1, nodes)?;
+                    if !item.is_empty() {
+                        clean.push(item);
+                    }
+                }
+                if clean.is_empty() {
+                    continue;
+                }
+                Block::List {
+                    ordered,
+                    items: clean,
+                }
+            }
+        };
+        ret.push(block);
+    }
+    Ok(ret)
+}
+
+fn sanitize_inlines(inlines: Vec<Inline>, nodes: &mut usize) -> Result<Vec<Inline>, AppError> {
+    let mut ret: Vec<Inline> = vec![];
+    for inline in inlines {
+        count(nodes)?;
+        let inline = match inline {
+            Inline::Text { text, mut marks } => {
+                let text = clean_text(&text);
+                if text.is_empty() {
+                    continue;
+                }
+                marks.sort();
+                marks.dedup();
+                // merge with the previous text if it has the same marks
+                if let Some(Inline::Text {
+                    text: prev,
+                    marks: prev_marks,
+                }) = ret.last_mut()
+                {
+                    if *prev_marks == marks {
+                        prev.push_str(&text);
+                        continue;
+                    }
+                }
+                Inline::Text { text, marks }
+            }
+            Inline::Code { text } => {
+                let text = clean_text(&text);
+                if text.is_empty() {
+                    continue;
+                }
+                Inline::Code { text }
+            }
+            Inline::Link { url, children } => {
+                let children = sanitize_inlines(children, nodes)?;
+                if children.iter().any(|c| matches!(c, Inline::Link { .. })) {
+                    return Err(AppError::CreateMessageError(
+                        "links can't contain links".to_string(),
+                    ));
+                }
+                match safe_url(&url) {
+                    Some(url) => {
+                        let children = if children.is_empty() {
+                            vec![Inline::Text {
+                                text: url.clone(),
+                                marks: vec![],
+                            }]
+                        } else {
+                            children
+                        };
+                        Inline::Link { url, children }
+                    }
+                    // drop the link but keep its text
+                    None => {
+                        ret.extend(children);
+                        continue;
+                    }
+                }
+            }
+        };
+        ret.push(inline);
+    }
+    Ok(ret)
+}
+
+fn count(nodes: &mut usize) -> Result<(), AppError> {
+    *nodes += 1;
+    if *nodes > MAX_NODES {
+        return Err(AppError::CreateMessageError(
+            "rich text has too many nodes".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Only web and mail links, `javascript:` and friends are dropped
+fn safe_url(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url.trim()).ok()?;
+    match url.scheme() {
+        "http" | "https" if url.host_str().is_some() => Some(url.to_string()),
+        "mailto" => Some(url.to_string()),
+        _ => None,
+    }
+}
+
+/// Drop control characters except newlines and tabs
+fn clean_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+fn blocks_text(blocks: &[Block], out: &mut String) {
+    for block in blocks {
+        match block {
+            Block::Paragraph { children } => {
+                inlines_text(children, out);
+                out.push('\n');
+            }
+            Block::Quote { children } => blocks_text(children, out),
+            Block::Code { text, .. } => {
+                out.push_str(text);
+                out.push('\n');
+            }
+            Block::List { items, .. } => {
+                for item in items {
+                    blocks_text(item, out);
+                }
+            }
+        }
+    }
+}
+
+fn inlines_text(inlines: &[Inline], out: &mut String) {
+    for inline in inlines {
+        match inline {
+            Inline::Text { text, .. } | Inline::Code { text } => out.push_str(text),
+            Inline::Link { url, children } => {
+                let start = out.len();
+                inlines_text(children, out);
+                if out[start..] != *url {
+                    out.push_str(&format!(" ({})", url));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateMessage, AppState};
+    use chat_core::ContentType;
+
+    #[test]
+    fn rich_text_should_sanitize() -> anyhow::Result<()> {
+        let content = r#"{"blocks":[
+            {"type":"paragraph","children":[
+                {"type":"text","text":"hello ","marks":["bold","bold"]},
+                {"type":"text","text":"world","marks":["bold"]},
+                {"type":"link","url":"javascript:alert(1)","children":[{"type":"text","text":" click"}]},
+                {"type":"link","url":"https://example.com","children":[{"type":"text","text":" site"}]}
+            ]},
+            {"type":"paragraph","children":[{"type":"text","text":""}]},
+            {"type":"code","language":"rust\" onload=\"x","text":"fn main() {}"}
+        ]}"#;
+        let doc = RichText::parse(content)?;
+        assert_eq!(
+            doc.blocks,
+            vec![
+                Block::Paragraph {
+                    children: vec![
+                        Inline::Text {
+                            text: "hello world".to_string(),
+                            marks: vec![Mark::Bold],
+                        },
+                        Inline::Text {
+                            text: " click".to_string(),
+                            marks: vec![],
+                        },
+                        Inline::Link {
+                            url: "https://example.com/".to_string(),
+                            children: vec![Inline::Text {
+                                text: " site".to_string(),
+                                marks: vec![],
+                            }],
+                        },
+                    ],
+                },
+                Block::Code {
+                    language: None,
+                    text: "fn main() {}".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            doc.plain_text(),
+            "hello world click site (https://example.com/)\nfn main() {}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rich_text_should_reject_bad_documents() {
+        for content in [
+            "not json",
+            r#"{"blocks":[]}"#,
+            r#"{"blocks":[{"type":"html","text":"<script></script>"}]}"#,
+            r#"{"blocks":[{"type":"paragraph","children":[{"type":"text","text":"x","style":"color:red"}]}]}"#,
+        ] {
+            assert!(RichText::parse(content).is_err(), "{}", content);
+        }
+
+        let mut nested =
+            r#"{"type":"paragraph","children":[{"type":"text","text":"deep"}]}"#.to_string();
+        for _ in 0..=MAX_DEPTH + 1 {
+            nested = format!(r#"{{"type":"quote","children":[{}]}}"#, nested);
+        }
+        let content = format!(r#"{{"blocks":[{}]}}"#, nested);
+        assert!(RichText::parse(&content).is_err());
+    }
+
+    #[tokio::test]
+    async fn rich_message_should_store_canonical_content() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let content = r#"{"blocks":[{"type":"paragraph","children":[
+            {"type":"text","text":"/not a command","marks":["bold","bold"]}
+        ]}]}"#;
+        let input = CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+            content_type: ContentType::Rich,
+        };
+        let message = state.insert_message(input, 1, 1).await?;
+        assert_eq!(message.content_type, ContentType::Rich);
+        assert_eq!(
+            message.content,
+            r#"{"blocks":[{"type":"paragraph","children":[{"type":"text","text":"/not a command","marks":["bold"]}]}]}"#
+        );
+
+        let plain_text: String =
+            sqlx::query_scalar("SELECT plain_text FROM messages WHERE id = $1")
+                .bind(message.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(plain_text, "/not a command");
+
+        let input = CreateMessage {
+            content: "<b>nope</b>".to_string(),
+            files: vec![],
+            content_type: ContentType::Rich,
+        };
+        assert!(state.insert_message(input, 1, 1).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+use chat_core::ContentType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, FromRow, PgConnection};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, AppState};
+
+use super::{rich_text::RichText, ChatFile, CreateMessage};
+
+const SCHEDULE_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "scheduled_message_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content_type: ContentType,
+    pub content: String,
+    pub files: Vec<String>,
+    pub send_at: DateTime<Utc>,
+    pub status: ScheduledStatus,
+    /// The message created on delivery
+    pub message_id: Option<i64>,
+    /// Why the delivery failed
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateScheduledMessage {
+    pub content: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+    #[serde(default)]
+    pub content_type: ContentType,
+    pub send_at: DateTime<Utc>,
+}
+
+/// Changes to a pending scheduled message, fields left out are kept as they are
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateScheduledMessage {
+    pub content: Option<String>,
+    pub files: Option<Vec<String>>,
+    pub content_type: Option<ContentType>,
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    pub async fn schedule_message(
+        &self,
+        input: CreateScheduledMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage, AppError> {
+        self.verify_scheduled(
+            input.content_type,
+            &input.content,
+            &input.files,
+            input.send_at,
+            user_id,
+        )
+        .await?;
+
+        let scheduled = sqlx::query_as(
+            r#"
+            INSERT INTO scheduled_messages (chat_id, sender_id, content_type, content, files, send_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, chat_id, sender_id, content_type, content, files, send_at, status,
+                message_id, error, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.content_type)
+        .bind(input.content)
+        .bind(input.files)
+        .bind(input.send_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    /// Scheduled messages of the user, pending ones first and the soonest first
+    pub async fn list_scheduled_messages(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<ScheduledMessage>, AppError> {
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content_type, content, files, send_at, status,
+                message_id, error, created_at
+            FROM scheduled_messages
+            WHERE sender_id = $1
+            ORDER BY status <> 'pending', send_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    /// Edit a pending message of the user. The update waits for a delivery holding the row,
+    /// so a message can't be edited once it's on its way.
+    pub async fn update_scheduled_message(
+        &self,
+        id: u64,
+        user_id: u64,
+        input: UpdateScheduledMessage,
+    ) -> Result<ScheduledMessage, AppError> {
+        let current = self.get_pending_scheduled(id, user_id).await?;
+        let content_type = input.content_type.unwrap_or(current.content_type);
+        let content = input.content.unwrap_or(current.content);
+        let files = input.files.unwrap_or(current.files);
+        let send_at = input.send_at.unwrap_or(current.send_at);
+        self.verify_scheduled(content_type, &content, &files, send_at, user_id)
+            .await?;
+
+        let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+            r#"
+            UPDATE scheduled_messages
+            SET content_type = $3, content = $4, files = $5, send_at = $6
+            WHERE id = $1 AND sender_id = $2 AND status = 'pending'
+            RETURNING id, chat_id, sender_id, content_type, content, files, send_at, status,
+                message_id, error, created_at
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .bind(content_type)
+        .bind(content)
+        .bind(files)
+        .bind(send_at)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        scheduled.ok_or_else(|| scheduled_not_found(id))
+    }
+
+    /// Cancel a pending message of the user
+    pub async fn cancel_scheduled_message(&self, id: u64, user_id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM scheduled_messages WHERE id = $1 AND sender_id = $2 AND status = 'pending'",
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(scheduled_not_found(id));
+        }
+
+        Ok(())
+    }
+
+    /// Send due messages. Each message is claimed with `FOR UPDATE SKIP LOCKED` and
+    /// inserted in the same transaction which marks it sent, so it is delivered exactly
+    /// once, even by servers sharing the database or one dying halfway.
+    /// Returns the number of messages processed.
+    pub async fn deliver_scheduled_messages(&self) -> Result<usize, AppError> {
+        let mut processed = 0;
+        while processed < SCHEDULE_BATCH_SIZE {
+            let mut tx = self.pool.begin().await?;
+            let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+                r#"
+                SELECT id, chat_id, sender_id, content_type, content, files, send_at, status,
+                    message_id, error, created_at
+                FROM scheduled_messages
+                WHERE status = 'pending' AND send_at <= now()
+                ORDER BY send_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+                "#,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(scheduled) = scheduled else {
+                break;
+            };
+
+            // a failed delivery is rolled back to the savepoint, the row is still marked
+            let mut sp = tx.begin().await?;
+            let (status, message_id, error) = match self.send_scheduled(&mut sp, &scheduled).await
+            {
+                Ok(message_id) => {
+                    sp.commit().await?;
+                    (ScheduledStatus::Sent, Some(message_id), None)
+                }
+                Err(e) => {
+                    sp.rollback().await?;
+                    (ScheduledStatus::Failed, None, Some(e.to_string()))
+                }
+            };
+            sqlx::query(
+                "UPDATE scheduled_messages SET status = $2, message_id = $3, error = $4 WHERE id = $1",
+            )
+            .bind(scheduled.id)
+            .bind(status)
+            .bind(message_id)
+            .bind(error)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            processed += 1;
+        }
+
+        Ok(processed)
+    }
+
+    async fn send_scheduled(
+        &self,
+        conn: &mut PgConnection,
+        scheduled: &ScheduledMessage,
+    ) -> Result<i64, AppError> {
+        let (chat_id, user_id) = (scheduled.chat_id as u64, scheduled.sender_id as u64);
+        // the sender may have left the chat since
+        if !self.is_chat_member(chat_id, user_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+        // slash commands can't be scheduled, only the escape of a leading slash is left
+        let mut content = scheduled.content.clone();
+        if scheduled.content_type == ContentType::Text && content.starts_with("//") {
+            content.remove(0);
+        }
+        let input = CreateMessage {
+            content,
+            files: scheduled.files.clone(),
+            content_type: scheduled.content_type,
+        };
+        let message = self.insert_message_in(conn, input, chat_id, user_id).await?;
+        Ok(message.id)
+    }
+
+    async fn get_pending_scheduled(
+        &self,
+        id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage, AppError> {
+        let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content_type, content, files, send_at, status,
+                message_id, error, created_at
+            FROM scheduled_messages
+            WHERE id = $1 AND sender_id = $2 AND status = 'pending'
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        scheduled.ok_or_else(|| scheduled_not_found(id))
+    }
+
+    /// Catch what would fail at delivery early, while the sender can still fix it
+    async fn verify_scheduled(
+        &self,
+        content_type: ContentType,
+        content: &str,
+        files: &[String],
+        send_at: DateTime<Utc>,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        if send_at <= Utc::now() {
+            return Err(AppError::ScheduleMessageError(
+                "send_at must be in the future".to_string(),
+            ));
+        }
+        if content.is_empty() {
+            return Err(AppError::ScheduleMessageError(
+                "Content cannot be empty".to_string(),
+            ));
+        }
+        match content_type {
+            ContentType::Text if content.starts_with('/') && !content.starts_with("//") => {
+                return Err(AppError::ScheduleMessageError(
+                    "Slash commands can't be scheduled".to_string(),
+                ));
+            }
+            ContentType::Text => {}
+            ContentType::Rich => {
+                RichText::parse(content)?;
+            }
+            ContentType::System => {
+                return Err(AppError::ScheduleMessageError(
+                    "System messages are written by the server".to_string(),
+                ));
+            }
+        }
+        for s in files {
+            ChatFile::from_str(s)?;
+            if !self.can_access_file(s, user_id).await? {
+                return Err(AppError::ScheduleMessageError(format!(
+                    "File {} doesn't exist",
+                    s
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn scheduled_not_found(id: u64) -> AppError {
+    AppError::NotFound(format!("pending scheduled message id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::AuditContext;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    fn scheduled(content: &str, send_at: DateTime<Utc>) -> CreateScheduledMessage {
+        CreateScheduledMessage {
+            content: content.to_string(),
+            files: vec![],
+            content_type: ContentType::Text,
+            send_at,
+        }
+    }
+
+    async fn make_due(state: &AppState, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE scheduled_messages SET send_at = now() - interval '1 second' WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn schedule_edit_and_cancel_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        let first = state
+            .schedule_message(scheduled("hello", later), 1, 1)
+            .await?;
+        let second = state
+            .schedule_message(scheduled("bye", later), 1, 1)
+            .await?;
+
+        let input = UpdateScheduledMessage {
+            content: Some("hello world".to_string()),
+            ..Default::default()
+        };
+        let first = state
+            .update_scheduled_message(first.id as _, 1, input.clone())
+            .await?;
+        assert_eq!(first.content, "hello world");
+        // only the sender can edit it
+        assert!(state
+            .update_scheduled_message(first.id as _, 2, input)
+            .await
+            .is_err());
+
+        state.cancel_scheduled_message(second.id as _, 1).await?;
+        let list = state.list_scheduled_messages(1).await?;
+        assert_eq!(list, vec![first]);
+
+        let past = Utc::now() - Duration::minutes(1);
+        assert!(state
+            .schedule_message(scheduled("late", past), 1, 1)
+            .await
+            .is_err());
+        assert!(state
+            .schedule_message(scheduled("/leave", later), 1, 1)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_scheduled_messages_should_send_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        let due = state
+            .schedule_message(scheduled("due", later), 1, 1)
+            .await?;
+        let left = state
+            .schedule_message(scheduled("gone", later), 2, 3)
+            .await?;
+        state
+            .schedule_message(scheduled("not yet", later), 1, 1)
+            .await?;
+        make_due(&state, due.id).await?;
+        make_due(&state, left.id).await?;
+        // the sender left before delivery
+        state
+            .remove_chat_members(2, 3, &[3], &AuditContext::default())
+            .await?;
+
+        let (a, b) = tokio::join!(
+            state.deliver_scheduled_messages(),
+            state.deliver_scheduled_messages()
+        );
+        assert_eq!(a? + b?, 2);
+        assert_eq!(state.deliver_scheduled_messages().await?, 0);
+
+        let list = state.list_scheduled_messages(1).await?;
+        let due = list.iter().find(|s| s.id == due.id).unwrap();
+        assert_eq!(due.status, ScheduledStatus::Sent);
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM messages WHERE content = 'due'")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(count, 1);
+
+        let list = state.list_scheduled_messages(3).await?;
+        assert_eq!(list[0].status, ScheduledStatus::Failed);
+        assert!(list[0].error.is_some());
+        Ok(())
+    }
+}
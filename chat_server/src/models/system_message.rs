@@ -0,0 +1,156 @@
+use chat_core::{ContentType, Message};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+
+/// Payload of a system message, stored as json in the message content
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SystemEvent {
+    MembersAdded {
+        user_ids: Vec<i64>,
+    },
+    MembersRemoved {
+        user_ids: Vec<i64>,
+    },
+    ChatRenamed {
+        old_name: Option<String>,
+        new_name: Option<String>,
+    },
+    TopicChanged {
+        old_topic: Option<String>,
+        new_topic: Option<String>,
+    },
+}
+
+impl SystemEvent {
+    pub fn plain_text(&self) -> String {
+        let ids = |ids: &[i64]| {
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Self::MembersAdded { user_ids } => format!("added user(s) {}", ids(user_ids)),
+            Self::MembersRemoved { user_ids } => format!("removed user(s) {}", ids(user_ids)),
+            Self::ChatRenamed { new_name, .. } => match new_name {
+                Some(name) => format!("renamed the chat to {}", name),
+                None => "removed the chat name".to_string(),
+            },
+            Self::TopicChanged { new_topic, .. } => match new_topic {
+                Some(topic) => format!("set the topic to {}", topic),
+                None => "cleared the topic".to_string(),
+            },
+        }
+    }
+}
+
+/// Write a system message within the transaction that made the change, `actor_id` is the
+/// user who made it
+pub(crate) async fn insert_system_message(
+    conn: &mut PgConnection,
+    chat_id: u64,
+    actor_id: u64,
+    event: &SystemEvent,
+) -> Result<Message, AppError> {
+    let content = serde_json::to_string(event).expect("system event always serializes");
+    let message = sqlx::query_as(
+        r#"
+        INSERT INTO messages (chat_id, sender_id, content_type, content, plain_text, files)
+        VALUES ($1, $2, $3, $4, $5, '{}')
+        RETURNING id, chat_id, sender_id, content_type, content, files, created_at
+        "#,
+    )
+    .bind(chat_id as i64)
+    .bind(actor_id as i64)
+    .bind(ContentType::System)
+    .bind(content)
+    .bind(event.plain_text())
+    .fetch_one(conn)
+    .await?;
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{AuditContext, ListMessages, UpdateChat},
+        AppState,
+    };
+    use anyhow::Result;
+
+    #[test]
+    fn system_event_should_serialize_with_tag() -> Result<()> {
+        let event = SystemEvent::TopicChanged {
+            old_topic: None,
+            new_topic: Some("release".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&event)?,
+            r#"{"type":"topic_changed","old_topic":null,"new_topic":"release"}"#
+        );
+        assert_eq!(event.plain_text(), "set the topic to release");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_changes_should_write_system_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChat {
+            name: Some("secret".to_string()),
+            topic: Some("plans".to_string()),
+            add_members: vec![4, 2],
+            remove_members: vec![3],
+        };
+        let chat = state
+            .update_chat(2, 1, input, &AuditContext::default())
+            .await?;
+        assert_eq!(chat.name.as_deref(), Some("secret"));
+        assert_eq!(chat.members, vec![1, 2, 4]);
+
+        // nothing changed, nothing written
+        let input = UpdateChat {
+            topic: Some("plans".to_string()),
+            ..Default::default()
+        };
+        state
+            .update_chat(2, 1, input, &AuditContext::default())
+            .await?;
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 10,
+        };
+        let messages = state.list_messages(input, 2).await?;
+        let events: Vec<_> = messages
+            .into_iter()
+            .rev()
+            .map(|m| {
+                assert_eq!(m.content_type, ContentType::System);
+                assert_eq!(m.sender_id, 1);
+                m.event.expect("system message should have an event")
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                SystemEvent::ChatRenamed {
+                    old_name: Some("private".to_string()),
+                    new_name: Some("secret".to_string()),
+                },
+                SystemEvent::TopicChanged {
+                    old_topic: None,
+                    new_topic: Some("plans".to_string()),
+                },
+                SystemEvent::MembersAdded { user_ids: vec![4] },
+                SystemEvent::MembersRemoved { user_ids: vec![3] },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sqlx::{FromRow, PgConnection};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, utils::random_hex, AppState};
+
+use super::{file::clean_filename, Attachment, AuditContext};
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct CreateUpload {
+    pub filename: String,
+    /// Total size in bytes
+    pub size: u64,
+    /// Hex sha1 of the whole file, the upload fails if the received content differs
+    pub sha1: Option<String>,
+}
+
+/// A resumable upload, PATCH chunks to `/api/uploads/{id}` starting at `offset`
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct UploadSession {
+    pub id: String,
+    pub filename: String,
+    pub size: i64,
+    pub offset: i64,
+    pub sha1: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct UploadProgress {
+    pub offset: i64,
+    pub size: i64,
+    /// Set once the last chunk arrived, the url is accepted by create_message
+    pub file: Option<Attachment>,
+}
+
+impl AppState {
+    pub async fn create_upload(
+        &self,
+        input: CreateUpload,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<UploadSession, AppError> {
+        if input.size == 0 {
+            return Err(AppError::UploadError("file is empty".to_string()));
+        }
+        if input.size > self.config.upload.max_file_size {
+            return Err(AppError::FileTooLarge(format!(
+                "{} exceeds {} bytes",
+                input.filename, self.config.upload.max_file_size
+            )));
+        }
+        let sha1 = input.sha1.map(|s| s.to_ascii_lowercase());
+        if let Some(sha1) = &sha1 {
+            if sha1.len() != 40 || !sha1.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(AppError::UploadError(format!("invalid sha1: {}", sha1)));
+            }
+        }
+
+        // the chunks take space before they become a file, hold the whole size against the
+        // quota for as long as the session is open
+        self.reserve_workspace_storage(ws_id, input.size).await?;
+        let ret = sqlx::query_as(
+            r#"
+            INSERT INTO upload_sessions (id, ws_id, user_id, filename, size, sha1, reserved)
+            VALUES ($1, $2, $3, $4, $5, $6, $5)
+            RETURNING id, filename, size, "offset", sha1, created_at
+            "#,
+        )
+        .bind(random_hex(16))
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(clean_filename(&input.filename))
+        .bind(input.size as i64)
+        .bind(sha1)
+        .fetch_one(&self.pool)
+        .await;
+
+        match ret {
+            Ok(session) => Ok(session),
+            Err(e) => {
+                self.release_workspace_storage(ws_id, input.size).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Sessions are private to the user who created them
+    pub async fn get_upload(&self, id: &str, user_id: u64) -> Result<UploadSession, AppError> {
+        let session: Option<UploadSession> = sqlx::query_as(
+            r#"
+            SELECT id, filename, size, "offset", sha1, created_at
+            FROM upload_sessions
+            WHERE id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        session.ok_or_else(|| AppError::NotFound(format!("upload {}", id)))
+    }
+
+    /// Append a chunk at `offset`, which must be the current offset of the session. The
+    /// chunk completing the file turns it into a regular upload. If completing failed on
+    /// the server side, an empty chunk at the final offset retries it.
+    pub async fn append_upload(
+        &self,
+        id: &str,
+        ws_id: u64,
+        user_id: u64,
+        offset: u64,
+        chunk: Bytes,
+        ctx: &AuditContext,
+    ) -> Result<UploadProgress, AppError> {
+        // the row lock makes a concurrent chunk for the same session wait, it then sees
+        // the new offset and conflicts
+        let mut tx = self.pool.begin().await?;
+        let session: Option<UploadSession> = sqlx::query_as(
+            r#"
+            SELECT id, filename, size, "offset", sha1, created_at
+            FROM upload_sessions
+            WHERE id = $1 AND user_id = $2
+            FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(session) = session else {
+            return Err(AppError::NotFound(format!("upload {}", id)));
+        };
+        let end = offset + chunk.len() as u64;
+        if end > session.size as u64 {
+            return Err(AppError::UploadError(format!(
+                "chunk ends at {} past the size {}",
+                end, session.size
+            )));
+        }
+        if session.offset as u64 != offset {
+            return Err(AppError::UploadConflict(format!(
+                "upload {} is at offset {}, not {}",
+                id, session.offset, offset
+            )));
+        }
+
+        // store the chunk before moving the offset past it, so a failed put or a crash
+        // leaves the session at the last chunk actually stored
+        if !chunk.is_empty() {
+            self.store.put(&chunk_key(id, offset), chunk).await?;
+            sqlx::query(
+                r#"
+                UPDATE upload_sessions
+                SET "offset" = $3, updated_at = now()
+                WHERE id = $1 AND "offset" = $2
+                "#,
+            )
+            .bind(id)
+            .bind(offset as i64)
+            .bind(end as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let mut progress = UploadProgress {
+            offset: end as i64,
+            size: session.size,
+            file: None,
+        };
+        if end < session.size as u64 {
+            tx.commit().await?;
+            return Ok(progress);
+        }
+
+        // complete while holding the row lock, a concurrent final chunk then finds the
+        // session gone or conflicts instead of completing it a second time
+        let ret = self
+            .complete_upload(&mut tx, &session, ws_id, user_id, ctx)
+            .await;
+        // a rejected file can't be fixed by resending chunks, start over. Errors of the
+        // store or the database may pass, keep the session to retry.
+        let done = match &ret {
+            Ok(_) => true,
+            Err(e) => matches!(
+                e,
+                AppError::UploadError(_)
+                    | AppError::UnsupportedFileType(_)
+                    | AppError::FileTooLarge(_)
+            ),
+        };
+        if done {
+            sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        if done {
+            self.delete_chunks(id).await;
+        }
+        progress.file = Some(ret?);
+        Ok(progress)
+    }
+
+    /// Assemble the chunks, check the hash and store the file like a multipart upload.
+    /// The session reservation is handed back first, storing the file reserves its size
+    /// again unless the content is already stored.
+    async fn complete_upload(
+        &self,
+        conn: &mut PgConnection,
+        session: &UploadSession,
+        ws_id: u64,
+        user_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<Attachment, AppError> {
+        let (session_ws_id, reserved): (i64, i64) = sqlx::query_as(
+            r#"
+            UPDATE upload_sessions s
+            SET reserved = 0
+            FROM (SELECT id, reserved FROM upload_sessions WHERE id = $1) old
+            WHERE s.id = old.id
+            RETURNING s.ws_id, old.reserved
+            "#,
+        )
+        .bind(&session.id)
+        .fetch_one(&mut *conn)
+        .await?;
+        if reserved > 0 {
+            self.release_workspace_storage(session_ws_id as _, reserved as _)
+                .await?;
+        }
+
+        let mut data = Vec::with_capacity(session.size as usize);
+        while (data.len() as i64) < session.size {
+            let key = chunk_key(&session.id, data.len() as u64);
+            let Some(chunk) = self.store.get(&key).await? else {
+                return Err(AppError::StorageError(format!("missing chunk {}", key)));
+            };
+            data.extend_from_slice(&chunk);
+        }
+
+        match &session.sha1 {
+            Some(expected) if *expected != hex::encode(Sha1::digest(&data)) => Err(
+                AppError::UploadError(format!("sha1 mismatch, expected {}", expected)),
+            ),
+            _ => {
+                self.upload_file(ws_id, user_id, &session.filename, data.into(), ctx)
+                    .await
+            }
+        }
+    }
+
+    pub async fn delete_upload(&self, id: &str, user_id: u64) -> Result<(), AppError> {
+        let session: Option<(String, i64, i64)> = sqlx::query_as(
+            r#"
+            DELETE FROM upload_sessions
+            WHERE id = $1 AND user_id = $2
+            RETURNING id, ws_id, reserved
+            "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((_, ws_id, reserved)) = session else {
+            return Err(AppError::NotFound(format!("upload {}", id)));
+        };
+        if reserved > 0 {
+            self.release_workspace_storage(ws_id as _, reserved as _)
+                .await?;
+        }
+        self.delete_chunks(id).await;
+        Ok(())
+    }
+
+    /// Drop sessions which got no chunk for longer than `max_age_secs` with their chunks
+    pub async fn expire_uploads(&self, max_age_secs: u64) -> Result<usize, AppError> {
+        let sessions: Vec<(String, i64, i64)> = sqlx::query_as(
+            r#"
+            DELETE FROM upload_sessions
+            WHERE updated_at < now() - make_interval(secs => $1)
+            RETURNING id, ws_id, reserved
+            "#,
+        )
+        .bind(max_age_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (id, ws_id, reserved) in &sessions {
+            if *reserved > 0 {
+                if let Err(e) = self
+                    .release_workspace_storage(*ws_id as _, *reserved as _)
+                    .await
+                {
+                    warn!("Failed to release upload {} storage: {}", id, e);
+                }
+            }
+            self.delete_chunks(id).await;
+        }
+        Ok(sessions.len())
+    }
+
+    /// Chunk sizes are not recorded, walk them by their offsets up to the first missing
+    /// one. This includes a chunk stored right before a crash, past the session offset.
+    async fn delete_chunks(&self, id: &str) {
+        let mut offset = 0;
+        loop {
+            let key = chunk_key(id, offset);
+            let size = match self.store.stat(&key).await {
+                Ok(Some(meta)) if meta.size > 0 => meta.size,
+                Ok(_) => break,
+                Err(e) => {
+                    warn!("Failed to stat upload chunk {}: {}", key, e);
+                    break;
+                }
+            };
+            if let Err(e) = self.store.delete(&key).await {
+                warn!("Failed to delete upload chunk {}: {}", key, e);
+            }
+            offset += size;
+        }
+    }
+}
+
+fn chunk_key(id: &str, offset: u64) -> String {
+    format!("uploads/{}/{}", id, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ChatFile;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn resumable_upload_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = format!("hello resumable upload {}", random_hex(8));
+        let input = CreateUpload {
+            filename: "hello.txt".to_string(),
+            size: data.len() as u64,
+            sha1: Some(hex::encode(Sha1::digest(data.as_bytes()))),
+        };
+        let used = state.get_workspace_usage(1).await?.used;
+        let session = state.create_upload(input, 1, 1).await?;
+        let size = data.len() as i64;
+        assert_eq!(state.get_workspace_usage(1).await?.used, used + size);
+        let (first, rest) = data.as_bytes().split_at(10);
+
+        let progress = state
+            .append_upload(&session.id, 1, 1, 0, Bytes::copy_from_slice(first), &ctx)
+            .await?;
+        assert_eq!(progress.offset, 10);
+        assert_eq!(progress.file, None);
+
+        // resending the first chunk after a dropped connection is rejected
+        let ret = state
+            .append_upload(&session.id, 1, 1, 0, Bytes::copy_from_slice(first), &ctx)
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadConflict(_))));
+        assert_eq!(state.get_upload(&session.id, 1).await?.offset, 10);
+        assert!(state.get_upload(&session.id, 2).await.is_err());
+
+        let progress = state
+            .append_upload(&session.id, 1, 1, 10, Bytes::copy_from_slice(rest), &ctx)
+            .await?;
+        let file = progress.file.unwrap();
+        let expected = ChatFile::new(1, "txt", data.as_bytes());
+        assert_eq!(file.url, expected.url());
+        assert_eq!(file.filename, "hello.txt");
+        assert!(state.get_upload(&session.id, 1).await.is_err());
+        // the session reservation went over to the file
+        assert_eq!(state.get_workspace_usage(1).await?.used, used + size);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_uploads_should_count_against_the_quota() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let used = state.get_workspace_usage(1).await?.used;
+        sqlx::query("UPDATE workspaces SET storage_quota = $1 WHERE id = 1")
+            .bind(used + 10)
+            .execute(&state.pool)
+            .await?;
+        let input = CreateUpload {
+            filename: "hello.txt".to_string(),
+            size: 8,
+            sha1: None,
+        };
+        let session = state.create_upload(input.clone(), 1, 1).await?;
+        let ret = state.create_upload(input.clone(), 1, 1).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+
+        state.delete_upload(&session.id, 1).await?;
+        assert_eq!(state.get_workspace_usage(1).await?.used, used);
+        state.create_upload(input, 1, 1).await?;
+        assert_eq!(state.expire_uploads(3600).await?, 0);
+        sqlx::query("UPDATE upload_sessions SET updated_at = now() - interval '2 hours'")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.expire_uploads(3600).await?, 1);
+        assert_eq!(state.get_workspace_usage(1).await?.used, used);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumable_upload_should_survive_a_failed_completion() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let data = format!("hello retried upload {}", random_hex(8));
+        let input = CreateUpload {
+            filename: "hello.txt".to_string(),
+            size: data.len() as u64,
+            sha1: None,
+        };
+        let session = state.create_upload(input, 1, 1).await?;
+        sqlx::query("UPDATE workspaces SET storage_quota = 0 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let chunk = Bytes::from(data.clone());
+        let ret = state.append_upload(&session.id, 1, 1, 0, chunk, &ctx).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+        let session = state.get_upload(&session.id, 1).await?;
+        assert_eq!(session.offset, data.len() as i64);
+
+        sqlx::query("UPDATE workspaces SET storage_quota = NULL WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let progress = state
+            .append_upload(&session.id, 1, 1, data.len() as u64, Bytes::new(), &ctx)
+            .await?;
+        assert!(progress.file.is_some());
+        assert!(state.get_upload(&session.id, 1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumable_upload_with_bad_hash_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        let input = CreateUpload {
+            filename: "hello.txt".to_string(),
+            size: 5,
+            sha1: Some("0".repeat(40)),
+        };
+        let session = state.create_upload(input, 1, 1).await?;
+        let ret = state
+            .append_upload(&session.id, 1, 1, 0, Bytes::from_static(b"hello"), &ctx)
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadError(_))));
+        assert!(state.get_upload(&session.id, 1).await.is_err());
+        Ok(())
+    }
+}
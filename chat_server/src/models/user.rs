@@ -1,13 +1,20 @@
 use crate::error::AppError;
 use crate::models::User;
+use crate::utils::random_hex;
 use crate::AppState;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use chat_core::ChatUser;
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 use utoipa::ToSchema;
 
+use super::{
+    audit::insert_audit_event, chat::leave_workspace_chats, workspace::set_workspace_owner,
+    AuditAction, AuditContext, NewAuditEvent,
+};
+
 /// create a user with email and password
 #[derive(Debug, Clone, Serialize, ToSchema,Deserialize)]
 pub struct CreateUser {
@@ -41,7 +48,11 @@ impl AppState {
     }
 
     /// Create a new user
-    pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
+    pub async fn create_user(
+        &self,
+        input: &CreateUser,
+        ctx: &AuditContext,
+    ) -> Result<User, AppError> {
         let password_hash = hash_password(&input.password)?;
         // check if email exists
         let user = self.find_user_by_email(&input.email).await?;
@@ -52,10 +63,11 @@ impl AppState {
         // check if workspace exists, if not create one
         let ws = match self.find_workspace_by_name(&input.workspace).await? {
             Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+            None => self.create_workspace(&input.workspace, 0, ctx).await?,
         };
         let password_hash = hash_password(&input.password)?;
 
+        let mut tx = self.pool.begin().await?;
         let user: User = sqlx::query_as(
             r#"
             INSERT INTO users (ws_id, email, fullname, password_hash)
@@ -67,15 +79,14 @@ impl AppState {
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
-
+        let event = NewAuditEvent::new(&user, AuditAction::UserSignup).target("user", user.id);
+        insert_audit_event(&mut tx, ctx, event).await?;
         if ws.owner_id == 0 {
-            self.update_workspace_owner(
-                ws.id as _,
-                user.id as _
-            ).await?;
+            set_workspace_owner(&mut tx, ws.id as _, user.id as _, Some(user.id), ctx).await?;
         }
+        tx.commit().await?;
 
         Ok(user)
     }
@@ -124,7 +135,7 @@ impl AppState {
             r#"
                     SELECT id, fullname, email
                     FROM users
-                    WHERE ws_id = $1
+                    WHERE ws_id = $1 AND disabled_at IS NULL
             "#,
         )
         .bind(ws_id as i64)
@@ -138,7 +149,7 @@ impl AppState {
             r#"
         SELECT id, fullname, email
         FROM users
-        WHERE id = ANY($1)
+        WHERE id = ANY($1) AND disabled_at IS NULL
         "#,
         )
         .bind(ids)
@@ -158,9 +169,126 @@ impl AppState {
 
         Ok(user)
     }
+
+    /// Set the password of a user, e.g. by an admin for a locked out user
+    pub async fn set_user_password(
+        &self,
+        id: i64,
+        password: &str,
+        ctx: &AuditContext,
+    ) -> Result<User, AppError> {
+        let password_hash = hash_password(password)?;
+        let mut tx = self.pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE users SET password_hash = $2 WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(password_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let user = user.ok_or_else(|| AppError::NotFound(format!("user id {}", id)))?;
+        let event = NewAuditEvent::in_workspace(user.ws_id, None, AuditAction::UserPasswordReset)
+            .target("user", user.id);
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    /// Move a user to another workspace, they leave the chats of their old workspace and
+    /// their single chats there are deleted. Owners have to hand their workspace over first.
+    ///
+    /// Tokens carry the workspace and stay valid until they expire, so until then a token
+    /// issued before the move still lists the users and chats of the old workspace. It can't
+    /// read or post to the chats the user left.
+    pub async fn move_user_to_workspace(
+        &self,
+        id: i64,
+        ws_id: u64,
+        ctx: &AuditContext,
+    ) -> Result<User, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let row: Option<(i64, i64)> = sqlx::query_as(
+            r#"
+            SELECT u.ws_id, w.owner_id
+            FROM users u
+            JOIN workspaces w ON w.id = u.ws_id
+            WHERE u.id = $1
+            FOR UPDATE OF u
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let (old_ws_id, owner_id) =
+            row.ok_or_else(|| AppError::NotFound(format!("user id {}", id)))?;
+        if old_ws_id == ws_id as i64 {
+            let user = self.find_user_by_id(id).await?;
+            return user.ok_or_else(|| AppError::NotFound(format!("user id {}", id)));
+        }
+        if owner_id == id {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} owns workspace {}, set another owner first",
+                id, old_ws_id
+            )));
+        }
+
+        let (left, deleted) = leave_workspace_chats(&mut tx, old_ws_id, id, ctx).await?;
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET ws_id = $2 WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        let metadata = serde_json::json!({
+            "old_ws_id": old_ws_id,
+            "ws_id": user.ws_id,
+            "left_chats": left,
+            "deleted_chats": deleted,
+        });
+        // both workspaces see the move in their audit log
+        for event_ws_id in [old_ws_id, user.ws_id] {
+            let event = NewAuditEvent::in_workspace(event_ws_id, None, AuditAction::UserMoved)
+                .target("user", user.id)
+                .metadata(metadata.clone());
+            insert_audit_event(&mut tx, ctx, event).await?;
+        }
+        tx.commit().await?;
+
+        Ok(user)
+    }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+/// Insert a bot user into the workspace, bots have no password so they can't sign in
+pub(crate) async fn insert_bot_user(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    name: &str,
+) -> Result<i64, AppError> {
+    let (id,): (i64,) = sqlx::query_as(
+        r#"
+        INSERT INTO users (ws_id, email, fullname, password_hash)
+        VALUES ($1, $2, $3, '')
+        RETURNING id
+        "#,
+    )
+    .bind(ws_id)
+    .bind(format!("bot-{}@bot.local", random_hex(8)))
+    .bind(name)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(id)
+}
+
+pub(super) fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
     // Argon2 with default params (Argon2id v19)
     let argon2 = Argon2::default();
@@ -207,6 +335,7 @@ impl SigninUser {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::ListAuditEvents;
     use anyhow::Result;
 
     #[test]
@@ -222,7 +351,7 @@ mod tests {
     async fn create_and_verify_user_should_work() -> Result<()> {
         let (tdb, state) = AppState::new_for_test().await?;
         let input = CreateUser::new("none", "Tyr Chen", "tchen@acme.org", "hunter42");
-        let user = state.create_user(&input).await?;
+        let user = state.create_user(&input, &AuditContext::default()).await?;
         assert_eq!(user.email, input.email);
         assert_eq!(user.fullname, input.fullname);
         assert!(user.id > 0);
@@ -247,4 +376,60 @@ mod tests {
         assert_eq!(user.id, 1);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn set_user_password_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        state.set_user_password(2, "new-secret", &ctx).await?;
+        let input = SigninUser::new("alice@acme.org", "new-secret");
+        assert!(state.verify_user(&input).await?.is_some());
+        let events = state.list_audit_events(1, Default::default()).await?;
+        assert_eq!(events[0].action, AuditAction::UserPasswordReset);
+        assert_eq!(events[0].actor_id, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn move_user_to_workspace_should_leave_old_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        state.update_workspace_owner(1, 1, &ctx).await?;
+        let user = state.move_user_to_workspace(2, 2, &ctx).await?;
+        assert_eq!(user.ws_id, 2);
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert!(!chat.members.contains(&2));
+        // the single chat with user 1 is gone
+        assert!(state.get_chat_by_id(3).await?.is_none());
+
+        let input = ListAuditEvents {
+            action: Some(AuditAction::UserMoved),
+            ..Default::default()
+        };
+        for ws_id in [1, 2] {
+            let events = state.list_audit_events(ws_id, input.clone()).await?;
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].metadata["left_chats"], serde_json::json!([1, 2]));
+            assert_eq!(events[0].metadata["deleted_chats"], serde_json::json!([3]));
+        }
+
+        // the owner stays until the workspace has another one
+        assert!(state.move_user_to_workspace(1, 2, &ctx).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn move_user_to_workspace_should_keep_single_chats_on_legal_hold() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE chats SET legal_hold = TRUE WHERE id = 3")
+            .execute(&state.pool)
+            .await?;
+        let ret = state
+            .move_user_to_workspace(2, 2, &AuditContext::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        assert_eq!(user.ws_id, 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,331 @@
+use chat_core::{Chat, ContentType, Message, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, utils::random_hex, AppState};
+
+use super::{
+    audit::insert_audit_event, user::insert_bot_user, AuditAction, AuditContext, CreateMessage,
+    NewAuditEvent,
+};
+
+/// An incoming webhook, POST to `/api/hooks/{secret}` to post into the chat
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Webhook {
+    pub id: i64,
+    pub chat_id: i64,
+    pub bot_id: i64,
+    pub creator_id: i64,
+    pub name: String,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct CreateWebhook {
+    /// Display name of the bot posting the messages
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema, Deserialize)]
+pub struct WebhookMessage {
+    pub content: String,
+}
+
+impl AppState {
+    /// Create a webhook for the chat, only workspace owners are allowed to do so
+    pub async fn create_webhook(
+        &self,
+        input: CreateWebhook,
+        chat_id: u64,
+        user: &User,
+        ctx: &AuditContext,
+    ) -> Result<Webhook, AppError> {
+        if input.name.is_empty() || input.name.len() > 64 {
+            return Err(AppError::WebhookError(
+                "Webhook name must be 1 to 64 characters".to_string(),
+            ));
+        }
+
+        let chat = self.ensure_webhook_admin(chat_id, user).await?;
+
+        // every webhook posts as its own bot user
+        let mut tx = self.pool.begin().await?;
+        let bot_id = insert_bot_user(&mut *tx, chat.ws_id, &input.name).await?;
+
+        let webhook: Webhook = sqlx::query_as(
+            r#"
+            INSERT INTO webhooks (chat_id, bot_id, creator_id, name, secret)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, chat_id, bot_id, creator_id, name, secret, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(bot_id)
+        .bind(user.id)
+        .bind(&input.name)
+        .bind(random_hex(32))
+        .fetch_one(&mut *tx)
+        .await?;
+        let event = NewAuditEvent::new(user, AuditAction::WebhookCreated)
+            .target("webhook", webhook.id)
+            .metadata(serde_json::json!({ "chat_id": webhook.chat_id, "name": webhook.name }));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(webhook)
+    }
+
+    pub async fn list_webhooks(&self, chat_id: u64, user: &User) -> Result<Vec<Webhook>, AppError> {
+        self.ensure_webhook_admin(chat_id, user).await?;
+
+        let webhooks = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, bot_id, creator_id, name, secret, created_at
+            FROM webhooks
+            WHERE chat_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    /// Delete the webhook and its bot user, a bot which already posted is disabled instead
+    /// so its messages keep their sender
+    pub async fn delete_webhook(
+        &self,
+        id: u64,
+        user: &User,
+        ctx: &AuditContext,
+    ) -> Result<(), AppError> {
+        let webhook: Option<Webhook> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, bot_id, creator_id, name, secret, created_at
+            FROM webhooks
+            WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(webhook) = webhook else {
+            return Err(AppError::NotFound(format!("webhook id {}", id)));
+        };
+
+        self.ensure_webhook_admin(webhook.chat_id as _, user)
+            .await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM users
+            WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM messages WHERE sender_id = $1)
+            "#,
+        )
+        .bind(webhook.bot_id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            sqlx::query("UPDATE users SET disabled_at = now() WHERE id = $1")
+                .bind(webhook.bot_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let event = NewAuditEvent::new(user, AuditAction::WebhookDeleted).target("webhook", id);
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn find_webhook_by_secret(&self, secret: &str) -> Result<Option<Webhook>, AppError> {
+        let webhook = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, bot_id, creator_id, name, secret, created_at
+            FROM webhooks
+            WHERE secret = $1
+            "#,
+        )
+        .bind(secret)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    /// Post a message into the webhook's chat as its bot user. The content is posted as
+    /// is, slash commands are for members only.
+    pub async fn deliver_webhook(
+        &self,
+        secret: &str,
+        input: WebhookMessage,
+    ) -> Result<Message, AppError> {
+        let Some(webhook) = self.find_webhook_by_secret(secret).await? else {
+            return Err(AppError::NotFound("webhook".to_string()));
+        };
+
+        if !self.webhook_limiter.check(&webhook.id.to_string()) {
+            return Err(AppError::TooManyRequests(format!("webhook {}", webhook.id)));
+        }
+
+        let input = CreateMessage {
+            content: input.content,
+            files: vec![],
+            content_type: ContentType::Text,
+        };
+        self.insert_message(input, webhook.chat_id as _, webhook.bot_id as _)
+            .await
+    }
+
+    async fn ensure_webhook_admin(&self, chat_id: u64, user: &User) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {}", chat_id)));
+        };
+
+        if chat.ws_id != user.ws_id
+            || !self
+                .is_workspace_owner(chat.ws_id as _, user.id as _)
+                .await?
+        {
+            return Err(AppError::PermissionDenied(
+                "only workspace owners can manage webhooks".to_string(),
+            ));
+        }
+
+        Ok(chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ListAuditEvents;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_and_deliver_webhook_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .update_workspace_owner(1, 1, &AuditContext::default())
+            .await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+
+        let input = CreateWebhook {
+            name: "alerts".to_string(),
+        };
+        let webhook = state
+            .create_webhook(input, 1, &user, &AuditContext::default())
+            .await?;
+        assert_eq!(webhook.chat_id, 1);
+        assert_eq!(webhook.secret.len(), 64);
+        let input = ListAuditEvents {
+            target_type: Some("webhook".to_string()),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(1, input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::WebhookCreated);
+        assert_eq!(events[0].target_id, Some(webhook.id.to_string()));
+
+        let msg = state
+            .deliver_webhook(
+                &webhook.secret,
+                WebhookMessage {
+                    content: "disk is full".to_string(),
+                },
+            )
+            .await?;
+        assert_eq!(msg.sender_id, webhook.bot_id);
+        assert_eq!(msg.chat_id, 1);
+
+        let webhooks = state.list_webhooks(1, &user).await?;
+        assert_eq!(webhooks.len(), 1);
+
+        // the bot posted, so it stays as the sender but isn't listed anymore
+        state
+            .delete_webhook(webhook.id as _, &user, &AuditContext::default())
+            .await?;
+        assert!(state.list_webhooks(1, &user).await?.is_empty());
+        assert!(state.find_user_by_id(webhook.bot_id).await?.is_some());
+        let users = state.fetch_chat_users(1).await?;
+        assert!(users.iter().all(|u| u.id != webhook.bot_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_unused_webhook_should_delete_its_bot() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = AuditContext::default();
+        state.update_workspace_owner(1, 1, &ctx).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateWebhook {
+            name: "alerts".to_string(),
+        };
+        let webhook = state.create_webhook(input, 1, &user, &ctx).await?;
+
+        state.delete_webhook(webhook.id as _, &user, &ctx).await?;
+        assert!(state.find_user_by_id(webhook.bot_id).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_content_should_not_run_commands() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .update_workspace_owner(1, 1, &AuditContext::default())
+            .await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateWebhook {
+            name: "alerts".to_string(),
+        };
+        let webhook = state
+            .create_webhook(input, 2, &user, &AuditContext::default())
+            .await?;
+        let members = state.get_chat_by_id(2).await?.expect("chat should exist").members;
+
+        let input = WebhookMessage {
+            content: "/invite 5".to_string(),
+        };
+        let msg = state.deliver_webhook(&webhook.secret, input).await?;
+        assert_eq!(msg.content, "/invite 5");
+        assert_eq!(msg.sender_id, webhook.bot_id);
+        let chat = state.get_chat_by_id(2).await?.expect("chat should exist");
+        assert_eq!(chat.members, members);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_webhook_by_non_owner_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let input = CreateWebhook {
+            name: "alerts".to_string(),
+        };
+        let ret = state
+            .create_webhook(input, 1, &user, &AuditContext::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_unknown_webhook_should_404() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = WebhookMessage {
+            content: "hello".to_string(),
+        };
+        let ret = state.deliver_webhook("bad-secret", input).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
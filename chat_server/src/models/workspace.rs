@@ -1,11 +1,29 @@
 use chat_core::Workspace;
-use sqlx::PgPool;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use utoipa::ToSchema;
 
 use crate::{error::AppError, AppState};
 
+use super::{audit::insert_audit_event, AuditAction, AuditContext, NewAuditEvent};
+
+/// Storage used by the uploaded files of a workspace, in bytes
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceUsage {
+    pub ws_id: i64,
+    pub used: i64,
+    pub quota: i64,
+}
+
 impl AppState {
-    pub async fn create_workspace(&self, name: &str, user_id: i32) -> Result<Workspace, AppError> {
-        let ws = sqlx::query_as(
+    pub async fn create_workspace(
+        &self,
+        name: &str,
+        user_id: i32,
+        ctx: &AuditContext,
+    ) -> Result<Workspace, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ws: Workspace = sqlx::query_as(
             r#"
                INSERT INTO workspaces (name, owner_id)
                 VALUES ($1, $2)
@@ -14,35 +32,31 @@ impl AppState {
         )
         .bind(name)
         .bind(user_id as i64)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        let event = NewAuditEvent::in_workspace(ws.id, None, AuditAction::WorkspaceCreated)
+            .target("workspace", ws.id)
+            .metadata(serde_json::json!({ "name": ws.name }));
+        insert_audit_event(&mut tx, ctx, event).await?;
+        tx.commit().await?;
 
         Ok(ws)
     }
 
+    /// Hand the workspace over to one of its users, e.g. by chat-admin
     pub async fn update_workspace_owner(
         &self,
         id: u64,
         owner_id: u64,
+        ctx: &AuditContext,
     ) -> Result<Workspace, AppError> {
-        // update owner_id in two cases 1) owner_id = 0 2) owner's ws_id = id
-        let ws = sqlx::query_as(
-            r#"
-        UPDATE workspaces
-        SET owner_id = $1
-        WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-        RETURNING id, name, owner_id, created_at
-        "#,
-        )
-        .bind(owner_id as i64)
-        .bind(id as i64)
-        .fetch_one(&self.pool)
-        .await?;
+        let mut tx = self.pool.begin().await?;
+        let ws = set_workspace_owner(&mut tx, id, owner_id, None, ctx).await?;
+        tx.commit().await?;
 
-        
         Ok(ws)
-
     }
+
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -56,7 +70,6 @@ impl AppState {
         .await?;
         Ok(ws)
     }
-    #[allow(dead_code)]
     pub async fn find_workspace_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -70,6 +83,82 @@ impl AppState {
         .await?;
         Ok(ws)
     }
+
+    pub async fn list_workspaces(&self) -> Result<Vec<Workspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+        SELECT id, name, owner_id, created_at
+        FROM workspaces
+        ORDER BY id
+        "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(workspaces)
+    }
+
+    /// Check the user owns the workspace, workspace owners act as admins
+    pub async fn is_workspace_owner(&self, ws_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let ws = self.find_workspace_by_id(ws_id).await?;
+        Ok(ws.map(|ws| ws.owner_id == user_id as i64).unwrap_or_default())
+    }
+
+    pub async fn get_workspace_usage(&self, ws_id: u64) -> Result<WorkspaceUsage, AppError> {
+        let usage: Option<WorkspaceUsage> = sqlx::query_as(
+            r#"
+        SELECT id AS ws_id, storage_used AS used, COALESCE(storage_quota, $2) AS quota
+        FROM workspaces
+        WHERE id = $1
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(self.config.upload.workspace_quota as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        usage.ok_or_else(|| AppError::NotFound(format!("workspace id {}", ws_id)))
+    }
+
+    /// Add `size` bytes to the storage used by the workspace, fail if it would exceed the quota
+    pub async fn reserve_workspace_storage(&self, ws_id: u64, size: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+        UPDATE workspaces
+        SET storage_used = storage_used + $2
+        WHERE id = $1 AND storage_used + $2 <= COALESCE(storage_quota, $3)
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(size as i64)
+        .bind(self.config.upload.workspace_quota as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::QuotaExceeded(format!(
+                "workspace {} is out of storage",
+                ws_id
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn release_workspace_storage(&self, ws_id: u64, size: u64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+        UPDATE workspaces
+        SET storage_used = GREATEST(storage_used - $2, 0)
+        WHERE id = $1
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(size as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
     // #[allow(dead_code)]
     // pub async fn fetch_all_chat_users(id: u64, pool: &PgPool) -> Result<Vec<ChatUser>, AppError> {
     //     let users = sqlx::query_as(
@@ -86,4 +175,36 @@ impl AppState {
     // }
 }
 
+/// Set the owner of the workspace, `actor_id` is None if chat-admin sets it
+pub(super) async fn set_workspace_owner(
+    conn: &mut PgConnection,
+    id: u64,
+    owner_id: u64,
+    actor_id: Option<i64>,
+    ctx: &AuditContext,
+) -> Result<Workspace, AppError> {
+    let old_owner_id: i64 =
+        sqlx::query_scalar("SELECT owner_id FROM workspaces WHERE id = $1 FOR UPDATE")
+            .bind(id as i64)
+            .fetch_one(&mut *conn)
+            .await?;
+    // update owner_id in two cases 1) owner_id = 0 2) owner's ws_id = id
+    let ws: Workspace = sqlx::query_as(
+        r#"
+        UPDATE workspaces
+        SET owner_id = $1
+        WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
+        RETURNING id, name, owner_id, created_at
+        "#,
+    )
+    .bind(owner_id as i64)
+    .bind(id as i64)
+    .fetch_one(&mut *conn)
+    .await?;
 
+    let event = NewAuditEvent::in_workspace(ws.id, actor_id, AuditAction::WorkspaceOwnerChanged)
+        .target("workspace", ws.id)
+        .metadata(serde_json::json!({ "owner_id": ws.owner_id, "old_owner_id": old_owner_id }));
+    insert_audit_event(conn, ctx, event).await?;
+    Ok(ws)
+}
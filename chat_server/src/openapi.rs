@@ -1,6 +1,6 @@
-use crate::{error::ErrorOutput, handlers::*, models::{CreateChat, CreateMessage, CreateUser, ListMessages, SigninUser}, AppState};
+use crate::{error::ErrorOutput, handlers::*, models::{Attachment, AuditAction, AuditEvent, ListAuditEvents, Block, CollectFiles, CreateExport, ExportFormat, ExportJob, ExportStatus, Command, CreateUpload, CreateChat, CreateCommand, CreateMessage, CreateUser, CreateWebhook, EphemeralReply, GcReport, ImportJob, ImportReport, ImportStatus, Inline, ListMessages, Mark, MessageDetail, RichText, CreateScheduledMessage, ScheduledMessage, ScheduledStatus, UpdateScheduledMessage, Retention, RetentionPolicy, RetentionPurge, ResetPassword, SignFile, SignedFileUrl, SigninUser, SystemEvent, UpdateChat, UploadProgress, UploadSession, Webhook, WebhookMessage, WorkspaceUsage}, AppState};
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{health::Readiness, Chat, ChatType, ChatUser, ContentType, LinkPreview, Message, User, Workspace};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -19,13 +19,48 @@ pub(crate) trait OpenApiRouter {
         paths(
             signup_handler,
             signin_handler,
+            reset_password_handler,
             list_chat_handler,
             create_chat_handler,
             get_chat_handler,
+            update_chat_handler,
             list_message_handler,
+            send_message_handler,
+            schedule_message_handler,
+            list_scheduled_handler,
+            update_scheduled_handler,
+            cancel_scheduled_handler,
+            get_workspace_retention_handler,
+            set_workspace_retention_handler,
+            get_chat_retention_handler,
+            set_chat_retention_handler,
+            purge_messages_handler,
+            list_purges_handler,
+            create_export_handler,
+            list_exports_handler,
+            get_export_handler,
+            import_slack_handler,
+            get_import_handler,
+            upload_handler,
+            sign_file_handler,
+            create_upload_handler,
+            get_upload_handler,
+            append_upload_handler,
+            delete_upload_handler,
+            create_command_handler,
+            list_command_handler,
+            create_webhook_handler,
+            list_webhook_handler,
+            delete_webhook_handler,
+            incoming_webhook_handler,
+            workspace_usage_handler,
+            collect_files_handler,
+            list_audit_events_handler,
+            healthz_handler,
+            readyz_handler,
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput),
+            schemas(User, Chat, ChatType, ChatUser, Message, ContentType, LinkPreview, Workspace, SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, EphemeralReply, Command, CreateCommand, Webhook, CreateWebhook, WebhookMessage, WorkspaceUsage, Attachment, MessageDetail, SignFile, SignedFileUrl, CollectFiles, GcReport, CreateUpload, UploadSession, UploadProgress, RichText, Block, Inline, Mark, SystemEvent, UpdateChat, CreateScheduledMessage, ScheduledMessage, ScheduledStatus, UpdateScheduledMessage, Retention, RetentionPolicy, RetentionPurge, CreateExport, ExportFormat, ExportJob, ExportStatus, ImportJob, ImportReport, ImportStatus, ResetPassword, AuditAction, AuditEvent, ListAuditEvents, Readiness, AuthOutput, ErrorOutput),
         ),
         modifiers(&SecurityAddon),
         tags(
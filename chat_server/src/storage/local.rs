@@ -0,0 +1,161 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::StreamExt;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{check_key, ByteStream, FileMeta, FileStore};
+use crate::error::AppError;
+
+/// Store files on the local filesystem under `base_dir`
+pub struct LocalStore {
+    base_dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(base_dir: PathBuf) -> Self {
+        Self { base_dir }
+    }
+
+    /// Resolve the key under base_dir
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        Ok(self.base_dir.join(check_key(key)?))
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalStore {
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(fs::try_exists(self.path(key)?).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, AppError> {
+        match fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<FileMeta>, AppError> {
+        match fs::metadata(self.path(key)?).await {
+            Ok(meta) => Ok(Some(FileMeta {
+                size: meta.len(),
+                modified: meta.modified().ok(),
+            })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<ByteStream>, AppError> {
+        let mut file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let stream = match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                ReaderStream::new(file.take(end - start + 1)).boxed()
+            }
+            None => ReaderStream::new(file).boxed(),
+        };
+        Ok(Some(stream))
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, mut file: fs::File, _size: u64) -> Result<(), AppError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut out = fs::File::create(path).await?;
+        tokio::io::copy(&mut file, &mut out).await?;
+        out.flush().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::random_hex;
+    use anyhow::Result;
+    use futures::TryStreamExt;
+    use std::io::{Seek, Write};
+
+    #[tokio::test]
+    async fn local_store_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(random_hex(8));
+        let store = LocalStore::new(base_dir.clone());
+        let key = "1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+
+        assert!(!store.exists(key).await?);
+        assert_eq!(store.get(key).await?, None);
+        store.put(key, Bytes::from_static(b"hello world")).await?;
+        assert!(store.exists(key).await?);
+        assert_eq!(
+            store.get(key).await?,
+            Some(Bytes::from_static(b"hello world"))
+        );
+        assert_eq!(store.stat(key).await?.map(|m| m.size), Some(11));
+        let stream = store.stream(key, Some((6, 9))).await?.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        assert_eq!(chunks.concat(), b"worl");
+
+        store.delete(key).await?;
+        assert!(!store.exists(key).await?);
+        assert_eq!(store.stat(key).await?, None);
+        store.delete(key).await?;
+
+        let mut tmp = tempfile::tempfile()?;
+        tmp.write_all(b"from disk")?;
+        tmp.rewind()?;
+        store
+            .put_file("exports/1/1.zip", fs::File::from_std(tmp), 9)
+            .await?;
+        assert_eq!(
+            store.get("exports/1/1.zip").await?,
+            Some(Bytes::from_static(b"from disk"))
+        );
+
+        fs::remove_dir_all(base_dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_store_should_reject_escaping_keys() {
+        let store = LocalStore::new(std::env::temp_dir().join(random_hex(8)));
+        for key in ["../etc/passwd", "1/../../etc/passwd", "/etc/passwd", ""] {
+            assert!(store.get(key).await.is_err(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+mod local;
+mod s3;
+
+use std::{
+    io,
+    path::{Component, Path},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use tokio::fs::File;
+
+use crate::{config::StorageConfig, error::AppError, AppConfig};
+
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+/// Body of a file read from the store
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Size and modification time of a stored file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage backend of uploaded files.
+///
+/// Keys are ChatFile paths relative to the workspace root, e.g. `1/339/807/e635afbeab088ce33206fdf4223a6bb156.png`.
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    async fn exists(&self, key: &str) -> Result<bool, AppError>;
+    /// Read the whole file, return None if it doesn't exist
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, AppError>;
+    async fn stat(&self, key: &str) -> Result<Option<FileMeta>, AppError>;
+    /// Stream the file without buffering it, `range` is an inclusive byte range
+    async fn stream(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<ByteStream>, AppError>;
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError>;
+    /// Store a local file of `size` bytes without reading it into memory
+    async fn put_file(&self, key: &str, file: File, size: u64) -> Result<(), AppError>;
+    /// Delete the file, deleting a file which doesn't exist is not an error
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+}
+
+/// Keys with `..`, root or prefix components are rejected so they can't escape the base
+/// dir or bucket of the store
+fn check_key(key: &str) -> Result<&Path, AppError> {
+    let path = Path::new(key);
+    if path.as_os_str().is_empty()
+        || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(AppError::StorageError(format!("invalid key: {}", key)));
+    }
+    Ok(path)
+}
+
+pub fn from_config(config: &AppConfig) -> Arc<dyn FileStore> {
+    match &config.storage {
+        StorageConfig::Local => Arc::new(LocalStore::new(config.server.base_dir.clone())),
+        StorageConfig::S3(s3) => Arc::new(S3Store::new(s3.clone())),
+    }
+}
@@ -0,0 +1,513 @@
+use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, LAST_MODIFIED, RANGE},
+    Body, Method, Response, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{check_key, ByteStream, FileMeta, FileStore};
+use crate::{config::S3Config, error::AppError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Store files in an S3 compatible bucket, requests are signed with AWS signature v4
+/// and use path style urls (`{endpoint}/{bucket}/{key}`) so MinIO works out of the box.
+pub struct S3Store {
+    config: S3Config,
+    client: reqwest::Client,
+}
+
+impl S3Store {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn send(&self, method: Method, key: &str, body: Bytes) -> Result<Response, AppError> {
+        self.send_with(method, key, body, HeaderMap::new()).await
+    }
+
+    /// Send a request with extra headers, they are not part of the signature
+    async fn send_with(
+        &self,
+        method: Method,
+        key: &str,
+        body: Bytes,
+        extra: HeaderMap,
+    ) -> Result<Response, AppError> {
+        let url = self.url(key)?;
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let mut headers = self.sign(&method, &url, &payload_hash, Utc::now())?;
+        headers.extend(extra);
+
+        self.client
+            .request(method, url)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| AppError::StorageError(e.to_string()))
+    }
+
+    /// Url of the key in the bucket. Besides `..` segments, url parsing turns `\` into `/`
+    /// and treats `%2e%2e` as `..`, so those are rejected as well to stay in the bucket.
+    fn url(&self, key: &str) -> Result<Url, AppError> {
+        check_key(key)?;
+        if key.contains(['\\', '%', '?', '#']) {
+            return Err(AppError::StorageError(format!("invalid key: {}", key)));
+        }
+        let url = format!(
+            "{}/{}/{}",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.bucket,
+            key
+        );
+        Url::parse(&url).map_err(|e| AppError::StorageError(e.to_string()))
+    }
+
+    /// Build the x-amz-* and authorization headers of the request, `payload_hash` is the
+    /// hex encoded sha256 of the body
+    fn sign(
+        &self,
+        method: &Method,
+        url: &Url,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<HeaderMap, AppError> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            url.path(),
+            url.query().unwrap_or_default(),
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.config.secret_key, date, &self.config.region, "s3");
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key, scope, SIGNED_HEADERS, signature
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-date", HeaderValue::from_str(&amz_date)?);
+        headers.insert("x-amz-content-sha256", HeaderValue::from_str(payload_hash)?);
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&authorization)?);
+        Ok(headers)
+    }
+}
+
+#[async_trait]
+impl FileStore for S3Store {
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        let res = self.send(Method::HEAD, key, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(AppError::StorageError(format!(
+                "head {} failed: {}",
+                key, status
+            ))),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, AppError> {
+        let res = self.send(Method::GET, key, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let data = res
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::StorageError(e.to_string()))?;
+                Ok(Some(data))
+            }
+            status => Err(AppError::StorageError(format!(
+                "get {} failed: {}",
+                key, status
+            ))),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<FileMeta>, AppError> {
+        let res = self.send(Method::HEAD, key, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let headers = res.headers();
+                let size = headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_default();
+                let modified = headers
+                    .get(LAST_MODIFIED)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| httpdate::parse_http_date(v).ok());
+                Ok(Some(FileMeta { size, modified }))
+            }
+            status => Err(AppError::StorageError(format!(
+                "head {} failed: {}",
+                key, status
+            ))),
+        }
+    }
+
+    async fn stream(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<ByteStream>, AppError> {
+        let mut extra = HeaderMap::new();
+        if let Some((start, end)) = range {
+            extra.insert(
+                RANGE,
+                HeaderValue::from_str(&format!("bytes={}-{}", start, end))?,
+            );
+        }
+        let res = self
+            .send_with(Method::GET, key, Bytes::new(), extra)
+            .await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let stream = res.bytes_stream().map_err(std::io::Error::other).boxed();
+                Ok(Some(stream))
+            }
+            status => Err(AppError::StorageError(format!(
+                "get {} failed: {}",
+                key, status
+            ))),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        let res = self.send(Method::PUT, key, data).await?;
+        if !res.status().is_success() {
+            return Err(AppError::StorageError(format!(
+                "put {} failed: {}",
+                key,
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, mut file: File, size: u64) -> Result<(), AppError> {
+        // the payload is signed, hash it in a first pass and stream it in a second one
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        file.rewind().await?;
+
+        let url = self.url(key)?;
+        let payload_hash = hex::encode(hasher.finalize());
+        let mut headers = self.sign(&Method::PUT, &url, &payload_hash, Utc::now())?;
+        // S3 doesn't take chunked bodies
+        headers.insert(CONTENT_LENGTH, size.into());
+        let res = self
+            .client
+            .put(url)
+            .headers(headers)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .send()
+            .await
+            .map_err(|e| AppError::StorageError(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(AppError::StorageError(format!(
+                "put {} failed: {}",
+                key,
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        let res = self.send(Method::DELETE, key, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(AppError::StorageError(format!(
+                "delete {} failed: {}",
+                key, status
+            ))),
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Seek, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        extract::{Path, State},
+        http::{HeaderMap, Uri},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use tokio::net::TcpListener;
+
+    type Objects = Arc<Mutex<HashMap<String, Bytes>>>;
+
+    const SECRET_KEY: &str = "minio123";
+
+    #[test]
+    fn signing_key_should_match_aws_example() {
+        // https://docs.aws.amazon.com/general/latest/gr/signature-v4-examples.html
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    fn s3_store(endpoint: String, secret_key: &str) -> S3Store {
+        S3Store::new(S3Config {
+            endpoint,
+            bucket: "chat".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "minio".to_string(),
+            secret_key: secret_key.to_string(),
+        })
+    }
+
+    #[test]
+    fn s3_keys_should_stay_in_the_bucket() {
+        let store = s3_store("http://localhost:9000".to_string(), SECRET_KEY);
+        for key in [
+            "../other-bucket/x",
+            "1/../../other-bucket/x",
+            "%2e%2e/other-bucket/x",
+            "..\\other-bucket\\x",
+            "/x",
+            "",
+        ] {
+            assert!(store.url(key).is_err(), "{} should be rejected", key);
+        }
+        let url = store.url("1/2aa/e6c/35c9.txt").unwrap();
+        assert_eq!(url.path(), "/chat/1/2aa/e6c/35c9.txt");
+    }
+
+    #[tokio::test]
+    async fn s3_store_with_a_wrong_secret_should_be_rejected() -> Result<()> {
+        let store = s3_store(start_s3_stand_in().await?, "wrong");
+        let ret = store.put("1/a.txt", Bytes::from_static(b"hello")).await;
+        assert!(matches!(ret, Err(AppError::StorageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn s3_store_should_work() -> Result<()> {
+        let store = s3_store(start_s3_stand_in().await?, SECRET_KEY);
+        let key = "1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+
+        assert!(!store.exists(key).await?);
+        assert_eq!(store.get(key).await?, None);
+        store.put(key, Bytes::from_static(b"hello world")).await?;
+        assert!(store.exists(key).await?);
+        assert_eq!(
+            store.get(key).await?,
+            Some(Bytes::from_static(b"hello world"))
+        );
+        assert_eq!(store.stat(key).await?.map(|m| m.size), Some(11));
+        let stream = store.stream(key, Some((6, 9))).await?.unwrap();
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        assert_eq!(chunks.concat(), b"worl");
+        store.delete(key).await?;
+        assert!(!store.exists(key).await?);
+
+        let mut tmp = tempfile::tempfile()?;
+        tmp.write_all(b"from disk")?;
+        tmp.rewind()?;
+        store
+            .put_file("exports/1/1.zip", File::from_std(tmp), 9)
+            .await?;
+        assert_eq!(
+            store.get("exports/1/1.zip").await?,
+            Some(Bytes::from_static(b"from disk"))
+        );
+        Ok(())
+    }
+
+    /// A tiny in-memory stand-in of a MinIO server, it only accepts requests signed with
+    /// `SECRET_KEY`
+    async fn start_s3_stand_in() -> Result<String> {
+        let objects = Objects::default();
+        let app = Router::new()
+            .route(
+                "/*key",
+                get(get_object).put(put_object).delete(delete_object),
+            )
+            .with_state(objects);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+        Ok(format!("http://{}", addr))
+    }
+
+    /// Recompute the signature from the request as received, the way S3 checks it
+    fn authorized(method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> bool {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let (Some(authorization), Some(amz_date), Some(payload_hash), Some(host)) = (
+            header("authorization"),
+            header("x-amz-date"),
+            header("x-amz-content-sha256"),
+            header("host"),
+        ) else {
+            return false;
+        };
+        let Some((scope, signature)) = authorization
+            .strip_prefix("AWS4-HMAC-SHA256 Credential=minio/")
+            .and_then(|v| {
+                v.split_once(", SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature=")
+            })
+        else {
+            return false;
+        };
+        if payload_hash != hex::encode(Sha256::digest(body)) {
+            return false;
+        }
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            uri.path(),
+            uri.query().unwrap_or_default(),
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let date = &amz_date[..8.min(amz_date.len())];
+        let key = signing_key(SECRET_KEY, date, "us-east-1", "s3");
+        scope == format!("{}/us-east-1/s3/aws4_request", date)
+            && signature == hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    }
+
+    async fn get_object(
+        State(objects): State<Objects>,
+        Path(key): Path<String>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        if !authorized(&method, &uri, &headers, &[]) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        let Some(data) = objects.lock().unwrap().get(&key).cloned() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let range = headers
+            .get("range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'))
+            .and_then(|(start, end)| Some((start.parse().ok()?, end.parse::<usize>().ok()?)));
+        match range {
+            Some((start, end)) => {
+                (StatusCode::PARTIAL_CONTENT, data.slice(start..=end)).into_response()
+            }
+            None => data.into_response(),
+        }
+    }
+
+    async fn put_object(
+        State(objects): State<Objects>,
+        Path(key): Path<String>,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        if !authorized(&Method::PUT, &uri, &headers, &body) {
+            return StatusCode::FORBIDDEN;
+        }
+        objects.lock().unwrap().insert(key, body);
+        StatusCode::OK
+    }
+
+    async fn delete_object(
+        State(objects): State<Objects>,
+        Path(key): Path<String>,
+        uri: Uri,
+        headers: HeaderMap,
+    ) -> StatusCode {
+        if !authorized(&Method::DELETE, &uri, &headers, &[]) {
+            return StatusCode::FORBIDDEN;
+        }
+        objects.lock().unwrap().remove(&key);
+        StatusCode::NO_CONTENT
+    }
+}
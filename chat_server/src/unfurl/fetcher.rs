@@ -0,0 +1,190 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use reqwest::{header, redirect::Policy, Url};
+
+use super::{FetchedPage, LinkFetcher};
+
+const MAX_REDIRECTS: usize = 3;
+
+/// Fetch pages over http(s), refusing anything that resolves to a private address.
+/// Redirects are followed by hand so every hop goes through the same check, and the
+/// connection is pinned to the checked address so DNS can't be rebound in between.
+pub struct HttpFetcher {
+    timeout: Duration,
+    max_body_size: usize,
+}
+
+impl HttpFetcher {
+    pub fn new(timeout: Duration, max_body_size: usize) -> Self {
+        Self {
+            timeout,
+            max_body_size,
+        }
+    }
+
+    async fn resolve(url: &Url) -> anyhow::Result<SocketAddr> {
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("unsupported scheme: {}", url.scheme());
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("url without host"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow!("url without port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if addrs.is_empty() {
+            bail!("{} doesn't resolve", host);
+        }
+        if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+            bail!("{} resolves to a non public address {}", host, addr.ip());
+        }
+        Ok(addrs[0])
+    }
+}
+
+#[async_trait]
+impl LinkFetcher for HttpFetcher {
+    async fn fetch(&self, url: &Url) -> anyhow::Result<FetchedPage> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let addr = Self::resolve(&url).await?;
+            let host = url.host_str().unwrap_or_default().to_string();
+            let client = reqwest::Client::builder()
+                .redirect(Policy::none())
+                .timeout(self.timeout)
+                .resolve(&host, addr)
+                .user_agent("chat-server link preview")
+                .build()?;
+            let mut res = client
+                .get(url.clone())
+                .header(header::ACCEPT, "text/html, application/json;q=0.9")
+                .send()
+                .await?;
+
+            if res.status().is_redirection() {
+                let location = res
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| anyhow!("redirect without location"))?;
+                url = url.join(location)?;
+                continue;
+            }
+            if !res.status().is_success() {
+                bail!("fetch {} failed: {}", url, res.status());
+            }
+
+            let content_type = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            // previews only need the head of the page, stop reading after max_body_size
+            let mut body = Vec::new();
+            while let Some(chunk) = res.chunk().await? {
+                let room = self.max_body_size - body.len();
+                body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                if body.len() >= self.max_body_size {
+                    break;
+                }
+            }
+            return Ok(FetchedPage {
+                url,
+                content_type,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        bail!("too many redirects")
+    }
+}
+
+/// Only addresses on the public internet may be fetched
+pub(crate) fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_ipv4(v4),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, carrier grade nat 100.64.0.0/10, benchmarking 198.18.0.0/15, reserved 240.0.0.0/4
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first = segments[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local fc00::/7, link local fe80::/10, documentation 2001:db8::/32
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && segments[1] == 0x0db8)
+        // NAT64 64:ff9b::/96 and 6to4 2002::/16 embed an ipv4 address, which may be private
+        || (segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+        || first == 0x2002)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_public_ip_should_work() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "2002:a9fe:a9fe::1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_fetcher_should_refuse_private_addresses() {
+        let fetcher = HttpFetcher::new(Duration::from_secs(1), 1024);
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://localhost/",
+            "http://[::1]/",
+            "file:///etc/passwd",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(fetcher.fetch(&url).await.is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,23 @@
+mod fetcher;
+mod parse;
+
+use async_trait::async_trait;
+use reqwest::Url;
+
+pub use fetcher::HttpFetcher;
+pub use parse::{extract_urls, parse_oembed, parse_page, PageMeta};
+
+/// A page fetched for a link preview
+#[derive(Debug, Clone)]
+pub struct FetchedPage {
+    /// url after redirects
+    pub url: Url,
+    pub content_type: String,
+    pub body: String,
+}
+
+/// Fetch pages to unfurl. The worker uses [`HttpFetcher`], tests plug in a local stand-in.
+#[async_trait]
+pub trait LinkFetcher: Send + Sync {
+    async fn fetch(&self, url: &Url) -> anyhow::Result<FetchedPage>;
+}
@@ -0,0 +1,196 @@
+use reqwest::Url;
+use scraper::{Html, Selector};
+use serde::Deserialize;
+
+/// Metadata of a page, from its OpenGraph tags, oEmbed document or `<title>`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+    /// oEmbed endpoint advertised by the page
+    pub oembed: Option<Url>,
+}
+
+impl PageMeta {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none() && self.image.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OEmbed {
+    title: Option<String>,
+    author_name: Option<String>,
+    provider_name: Option<String>,
+    thumbnail_url: Option<String>,
+}
+
+/// http(s) urls in a message, in order and without duplicates
+pub fn extract_urls(content: &str, max: usize) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for word in content.split_whitespace() {
+        let word = word.trim_start_matches(['(', '<', '[', '"', '\'']);
+        if !(word.starts_with("http://") || word.starts_with("https://")) {
+            continue;
+        }
+        let word = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']);
+        let Ok(url) = Url::parse(word) else {
+            continue;
+        };
+        if url.host_str().is_none() || urls.iter().any(|u| u == url.as_str()) {
+            continue;
+        }
+        urls.push(url.to_string());
+        if urls.len() >= max {
+            break;
+        }
+    }
+    urls
+}
+
+/// Parse the preview metadata of an html page, relative urls resolve against `base`
+pub fn parse_page(html: &str, base: &Url) -> PageMeta {
+    let doc = Html::parse_document(html);
+    let mut meta = PageMeta::default();
+
+    let metas = Selector::parse("meta").expect("valid selector");
+    for el in doc.select(&metas) {
+        let el = el.value();
+        let (Some(key), Some(content)) = (
+            el.attr("property").or_else(|| el.attr("name")),
+            el.attr("content"),
+        ) else {
+            continue;
+        };
+        let content = content.trim();
+        if content.is_empty() {
+            continue;
+        }
+        let slot = match key.to_ascii_lowercase().as_str() {
+            "og:title" => &mut meta.title,
+            "og:description" => &mut meta.description,
+            "description" if meta.description.is_none() => &mut meta.description,
+            "og:image" | "og:image:url" => &mut meta.image,
+            "og:site_name" => &mut meta.site_name,
+            _ => continue,
+        };
+        slot.get_or_insert_with(|| content.to_string());
+    }
+
+    if meta.title.is_none() {
+        let title = Selector::parse("title").expect("valid selector");
+        meta.title = doc
+            .select(&title)
+            .next()
+            .map(|el| el.text().collect::<String>().trim().to_string())
+            .filter(|t| !t.is_empty());
+    }
+
+    let oembed =
+        Selector::parse(r#"link[type="application/json+oembed"]"#).expect("valid selector");
+    meta.oembed = doc
+        .select(&oembed)
+        .next()
+        .and_then(|el| el.value().attr("href"))
+        .and_then(|href| base.join(href).ok());
+
+    // images must be absolute for the clients, and only http(s)
+    meta.image = meta
+        .image
+        .and_then(|img| base.join(&img).ok())
+        .filter(|img| matches!(img.scheme(), "http" | "https"))
+        .map(|img| img.to_string());
+    meta.title = meta.title.map(|t| truncate(&t, 200));
+    meta.description = meta.description.map(|d| truncate(&d, 500));
+    meta
+}
+
+/// Fill the gaps of `meta` from an oEmbed json document
+pub fn parse_oembed(json: &str, meta: &mut PageMeta) -> anyhow::Result<()> {
+    let oembed: OEmbed = serde_json::from_str(json)?;
+    if meta.title.is_none() {
+        meta.title = oembed.title.map(|t| truncate(&t, 200));
+    }
+    if meta.description.is_none() {
+        meta.description = oembed.author_name;
+    }
+    if meta.site_name.is_none() {
+        meta.site_name = oembed.provider_name;
+    }
+    if meta.image.is_none() {
+        meta.image = oembed
+            .thumbnail_url
+            .filter(|u| u.starts_with("http://") || u.starts_with("https://"));
+    }
+    Ok(())
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    match s.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &s[..idx]),
+        None => s.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_urls_should_work() {
+        let content =
+            "see https://example.com/a, (http://foo.org/b?x=1) and https://example.com/a. \
+            ftp://nope.org https://bar.org https://baz.org";
+        assert_eq!(
+            extract_urls(content, 3),
+            vec![
+                "https://example.com/a",
+                "http://foo.org/b?x=1",
+                "https://bar.org/"
+            ]
+        );
+        assert!(extract_urls("no links here", 3).is_empty());
+    }
+
+    #[test]
+    fn parse_page_should_work() {
+        let html = r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="Rust 1.80">
+            <meta name="description" content="Plain description">
+            <meta property="og:image" content="/img/logo.png">
+            <meta property="og:site_name" content="Rust Blog">
+            <link rel="alternate" type="application/json+oembed" href="/oembed?url=x">
+        </head></html>"#;
+        let base = Url::parse("https://blog.rust-lang.org/2024/07/25/Rust-1.80.0.html").unwrap();
+        let meta = parse_page(html, &base);
+        assert_eq!(meta.title.as_deref(), Some("Rust 1.80"));
+        assert_eq!(meta.description.as_deref(), Some("Plain description"));
+        assert_eq!(
+            meta.image.as_deref(),
+            Some("https://blog.rust-lang.org/img/logo.png")
+        );
+        assert_eq!(meta.site_name.as_deref(), Some("Rust Blog"));
+        assert_eq!(
+            meta.oembed.unwrap().as_str(),
+            "https://blog.rust-lang.org/oembed?url=x"
+        );
+    }
+
+    #[test]
+    fn parse_oembed_should_fill_gaps() -> anyhow::Result<()> {
+        let base = Url::parse("https://video.example.com/v/1")?;
+        let mut meta = parse_page("<html><head><title>Video</title></head></html>", &base);
+        let json = r#"{"title":"A video","author_name":"alice","provider_name":"Videos","thumbnail_url":"https://video.example.com/t.jpg"}"#;
+        parse_oembed(json, &mut meta)?;
+        assert_eq!(meta.title.as_deref(), Some("Video"));
+        assert_eq!(meta.site_name.as_deref(), Some("Videos"));
+        assert_eq!(
+            meta.image.as_deref(),
+            Some("https://video.example.com/t.jpg")
+        );
+        Ok(())
+    }
+}
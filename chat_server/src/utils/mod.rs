@@ -0,0 +1,9 @@
+mod download;
+mod rate_limit;
+mod thumbnail;
+mod token;
+
+pub use download::{content_disposition, etag_matches, parse_range, ByteRange};
+pub use rate_limit::RateLimiter;
+pub use thumbnail::{image_dimensions, make_thumbnails, thumbnail_ext, ThumbnailSize};
+pub use token::random_hex;
@@ -0,0 +1,118 @@
+use std::io::Cursor;
+
+use axum::body::Bytes;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+
+/// Thumbnail sizes generated for uploaded images, by their longest side
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbnailSize {
+    pub const ALL: [ThumbnailSize; 3] = [Self::Small, Self::Medium, Self::Large];
+
+    pub fn max_side(&self) -> u32 {
+        match self {
+            Self::Small => 160,
+            Self::Medium => 480,
+            Self::Large => 1280,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Large => "large",
+        }
+    }
+}
+
+/// Extension of the thumbnails of an image, jpeg stays jpeg and everything else becomes png
+/// so transparency survives. `None` if we don't make thumbnails for this kind of file.
+pub fn thumbnail_ext(ext: &str) -> Option<&'static str> {
+    match ext {
+        "jpg" | "jpeg" => Some("jpg"),
+        "png" | "gif" | "webp" => Some("png"),
+        _ => None,
+    }
+}
+
+/// Width and height of an image, only the header is decoded
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Make the thumbnails of an image. Sizes larger than the image itself are skipped,
+/// clients get the original for them. This is cpu heavy, run it in a blocking task.
+pub fn make_thumbnails(data: &[u8], ext: &str) -> anyhow::Result<Vec<(ThumbnailSize, Bytes)>> {
+    let Some(thumb_ext) = thumbnail_ext(ext) else {
+        return Ok(vec![]);
+    };
+    let format = if thumb_ext == "jpg" {
+        ImageFormat::Jpeg
+    } else {
+        ImageFormat::Png
+    };
+
+    let img = image::load_from_memory(data)?;
+    let mut ret = vec![];
+    for size in ThumbnailSize::ALL {
+        let side = size.max_side();
+        if img.width() <= side && img.height() <= side {
+            break;
+        }
+        let thumb = img.thumbnail(side, side);
+        // the jpeg encoder doesn't accept an alpha channel
+        let thumb = match format {
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(thumb.to_rgb8()),
+            _ => thumb,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        thumb.write_to(&mut buf, format)?;
+        ret.push((size, Bytes::from(buf.into_inner())));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn make_thumbnails_should_work() -> anyhow::Result<()> {
+        let data = png(1000, 500);
+        assert_eq!(image_dimensions(&data), Some((1000, 500)));
+
+        let thumbs = make_thumbnails(&data, "png")?;
+        let sizes: Vec<_> = thumbs.iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, vec![ThumbnailSize::Small, ThumbnailSize::Medium]);
+        assert_eq!(image_dimensions(&thumbs[0].1), Some((160, 80)));
+        assert_eq!(image_dimensions(&thumbs[1].1), Some((480, 240)));
+        Ok(())
+    }
+
+    #[test]
+    fn make_thumbnails_should_skip_non_images() -> anyhow::Result<()> {
+        assert!(make_thumbnails(b"hello world", "txt")?.is_empty());
+        assert_eq!(image_dimensions(b"hello world"), None);
+        Ok(())
+    }
+}
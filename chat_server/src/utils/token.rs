@@ -0,0 +1,20 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+/// Generate a random hex string from `len` bytes of OS randomness.
+pub fn random_hex(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_hex_should_work() {
+        let s = random_hex(32);
+        assert_eq!(s.len(), 64);
+        assert_ne!(s, random_hex(32));
+    }
+}
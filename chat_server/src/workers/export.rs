@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Run queued exports one at a time
+pub(crate) async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        loop {
+            match state.process_export_job().await {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    warn!("Failed to process export: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Delete the archives of exports older than their ttl
+pub(crate) async fn expire(state: AppState) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.expire_exports(state.config.export.archive_ttl_secs).await {
+            Ok(0) => {}
+            Ok(n) => info!("Expired {} export archives", n),
+            Err(e) => warn!("Failed to expire exports: {}", e),
+        }
+    }
+}
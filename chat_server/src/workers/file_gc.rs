@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::{models::AuditContext, AppState};
+
+/// Periodically delete uploaded files no message references
+pub(crate) async fn run(state: AppState) {
+    let gc = &state.config.gc;
+    let mut interval = tokio::time::interval(Duration::from_secs(gc.interval_secs));
+    let ctx = AuditContext::default();
+    loop {
+        interval.tick().await;
+        if let Err(e) = state
+            .collect_orphaned_files(None, gc.grace_secs, gc.dry_run, None, &ctx)
+            .await
+        {
+            warn!("Failed to collect orphaned files: {}", e);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::AppState;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Run queued imports one at a time
+pub(crate) async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        loop {
+            match state.process_import_job().await {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    warn!("Failed to process import: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+mod export;
+mod file_gc;
+mod import;
+mod reminder;
+mod retention;
+mod scheduler;
+mod unfurl;
+mod upload_expiry;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{unfurl::HttpFetcher, AppState};
+
+/// Spawn the background workers of the chat server
+pub fn spawn_workers(state: &AppState) {
+    chat_core::metrics::track_pool(state.pool.clone());
+    tokio::spawn(reminder::run(state.clone()));
+    tokio::spawn(scheduler::run(state.clone()));
+    tokio::spawn(export::run(state.clone()));
+    tokio::spawn(export::expire(state.clone()));
+    tokio::spawn(import::run(state.clone()));
+    tokio::spawn(upload_expiry::run(state.clone()));
+    if state.config.unfurl.enabled {
+        let config = &state.config.unfurl;
+        let fetcher = HttpFetcher::new(
+            Duration::from_secs(config.timeout_secs),
+            config.max_body_size,
+        );
+        tokio::spawn(unfurl::run(state.clone(), Arc::new(fetcher)));
+    }
+    if state.config.gc.enabled {
+        tokio::spawn(file_gc::run(state.clone()));
+    }
+    if state.config.retention.enabled {
+        tokio::spawn(retention::run(state.clone()));
+    }
+}
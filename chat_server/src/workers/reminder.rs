@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Deliver due reminders set by /remind
+pub(crate) async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.deliver_due_reminders().await {
+            Ok(reminders) if !reminders.is_empty() => {
+                info!("Delivered {} reminders", reminders.len())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to deliver reminders: {}", e),
+        }
+    }
+}
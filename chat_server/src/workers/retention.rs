@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::{models::AuditContext, AppState};
+
+/// Periodically purge messages older than the retention of their chat
+pub(crate) async fn run(state: AppState) {
+    let interval = Duration::from_secs(state.config.retention.interval_secs);
+    let mut interval = tokio::time::interval(interval);
+    let ctx = AuditContext::default();
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.purge_expired_messages(None, None, &ctx).await {
+            warn!("Failed to purge expired messages: {}", e);
+        }
+    }
+}
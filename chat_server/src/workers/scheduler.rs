@@ -0,0 +1,20 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Send scheduled messages when they are due
+pub(crate) async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.deliver_scheduled_messages().await {
+            Ok(n) if n > 0 => info!("Delivered {} scheduled messages", n),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to deliver scheduled messages: {}", e),
+        }
+    }
+}
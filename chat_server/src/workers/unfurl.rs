@@ -0,0 +1,20 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{unfurl::LinkFetcher, AppState};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Fetch link previews of new messages
+pub(crate) async fn run(state: AppState, fetcher: Arc<dyn LinkFetcher>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.process_unfurl_jobs(fetcher.as_ref()).await {
+            Ok(n) if n > 0 => info!("Unfurled links of {} messages", n),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to unfurl links: {}", e),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Drop resumable uploads idle for longer than their ttl, with their chunks
+pub(crate) async fn run(state: AppState) {
+    let upload = &state.config.upload;
+    let mut interval = tokio::time::interval(Duration::from_secs(upload.expire_interval_secs));
+    loop {
+        interval.tick().await;
+        match state.expire_uploads(upload.session_ttl_secs).await {
+            Ok(0) => {}
+            Ok(n) => info!("Expired {} abandoned uploads", n),
+            Err(e) => warn!("Failed to expire uploads: {}", e),
+        }
+    }
+}
@@ -13,6 +13,10 @@ use futures::StreamExt;
 struct AuthToken {
     token: String,
 }
+#[derive(Debug, Deserialize)]
+struct Attachment {
+    url: String,
+}
 struct ChatServer {
     addr: SocketAddr,
     token: String,
@@ -61,9 +65,8 @@ impl NotifyServer {
                             assert_eq!(msg.files.len(), 1);
                             assert_eq!(msg.sender_id, 1);
                         }
-                        _ => {
-                            panic!("unexpected event: {:?}", message);
-                        }
+                        // e.g. link previews or the final Shutdown event
+                        _ => println!("ignore event: {:?}", message),
                     },
                     Err(err) => {
                         println!("Error: {}", err);
@@ -136,7 +139,8 @@ impl ChatServer {
             .send()
             .await?;
         assert_eq!(res.status(), StatusCode::OK);
-        let ret: Vec<String> = res.json().await?;
+        let ret: Vec<Attachment> = res.json().await?;
+        let ret: Vec<String> = ret.into_iter().map(|file| file.url).collect();
         let body = serde_json::to_string(&json!({
             "content": "hello",
             "files": ret,
@@ -0,0 +1,17 @@
+-- Add migration script here
+-- incoming webhooks, each one posts into a single chat as its own bot user
+CREATE TABLE IF NOT EXISTS webhooks(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  -- messages delivered through the webhook are sent by this user
+  bot_id bigint NOT NULL REFERENCES users(id),
+  creator_id bigint NOT NULL REFERENCES users(id),
+  name varchar(64) NOT NULL,
+  -- random hex secret, part of the webhook url
+  secret varchar(64) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS webhook_secret_index ON webhooks(secret);
+
+CREATE INDEX IF NOT EXISTS webhook_chat_id_index ON webhooks(chat_id);
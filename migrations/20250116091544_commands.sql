@@ -0,0 +1,86 @@
+-- Add migration script here
+-- chat topic, set by /topic
+ALTER TABLE chats
+  ADD COLUMN topic text;
+-- chats muted by a member, set by /mute
+CREATE TABLE IF NOT EXISTS chat_mutes(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
+-- custom slash commands, forwarded to an http endpoint
+CREATE TABLE IF NOT EXISTS commands(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  name varchar(32) NOT NULL,
+  url text NOT NULL,
+  -- responses of the endpoint are posted by this user
+  bot_id bigint NOT NULL REFERENCES users(id),
+  creator_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS command_ws_id_name_index ON commands(ws_id, name);
+-- reminders, set by /remind
+CREATE TABLE IF NOT EXISTS reminders(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  remind_at timestamptz NOT NULL,
+  delivered_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS reminder_remind_at_index ON reminders(remind_at)
+WHERE
+  delivered_at IS NULL;
+-- if a reminder is delivered, notify the user who set it
+CREATE OR REPLACE FUNCTION reminder_due()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF OLD.delivered_at IS NULL AND NEW.delivered_at IS NOT NULL THEN
+    RAISE NOTICE 'reminder_due: %', NEW;
+    PERFORM
+      pg_notify('reminder_due', row_to_json(NEW)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+CREATE TRIGGER reminder_due_trigger
+  AFTER UPDATE ON reminders
+  FOR EACH ROW
+  EXECUTE FUNCTION reminder_due();
+-- if new message added, notify the chat members who haven't muted the chat
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    SELECT
+      array_agg(m) INTO USERS
+    FROM
+      chats,
+      unnest(chats.members) AS m
+    WHERE
+      chats.id = NEW.chat_id
+      AND m NOT IN (
+        SELECT
+          user_id
+        FROM
+          chat_mutes
+        WHERE
+          chat_id = NEW.chat_id);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', COALESCE(USERS, '{}'))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- storage used by the uploaded files of a workspace, and its quota in bytes
+-- a NULL quota falls back to upload.workspace_quota in the config
+ALTER TABLE workspaces
+  ADD COLUMN storage_used bigint NOT NULL DEFAULT 0,
+  ADD COLUMN storage_quota bigint;
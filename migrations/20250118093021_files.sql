@@ -0,0 +1,15 @@
+-- Add migration script here
+-- metadata of uploaded files, the same content may be uploaded several times
+-- under different names, they share the url and the stored blob
+CREATE TABLE IF NOT EXISTS files(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  url text NOT NULL,
+  filename varchar(255) NOT NULL,
+  content_type varchar(255) NOT NULL,
+  size bigint NOT NULL,
+  uploader_id bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS file_url_index ON files(url);
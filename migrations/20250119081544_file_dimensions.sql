@@ -0,0 +1,5 @@
+-- Add migration script here
+-- dimensions of uploaded images, NULL for other files
+ALTER TABLE files
+  ADD COLUMN width int,
+  ADD COLUMN height int;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- file access checks look up the messages referencing a file url
+CREATE INDEX IF NOT EXISTS message_files_index ON messages USING GIN(files);
@@ -0,0 +1,15 @@
+-- Add migration script here
+-- resumable uploads, chunks are stored under uploads/{id}/{offset} until complete
+CREATE TABLE IF NOT EXISTS upload_sessions(
+  id varchar(64) PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id),
+  user_id bigint NOT NULL REFERENCES users(id),
+  filename varchar(255) NOT NULL,
+  size bigint NOT NULL,
+  "offset" bigint NOT NULL DEFAULT 0,
+  -- expected sha1 of the whole file, checked once all chunks arrived
+  sha1 char(40),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS upload_session_created_at_index ON upload_sessions(created_at);
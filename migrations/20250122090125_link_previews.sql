@@ -0,0 +1,24 @@
+-- Add migration script here
+-- link previews of the urls in a message, filled in by the unfurl worker
+CREATE TABLE IF NOT EXISTS link_previews(
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  url text NOT NULL,
+  title text,
+  description text,
+  image text,
+  site_name text,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS link_preview_message_id_index ON link_previews(message_id);
+
+-- recent previews of an url are reused instead of fetching it again
+CREATE INDEX IF NOT EXISTS link_preview_url_index ON link_previews(url, created_at DESC);
+
+-- messages waiting to be unfurled
+CREATE TABLE IF NOT EXISTS unfurl_jobs(
+  message_id bigint PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+  urls text[] NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
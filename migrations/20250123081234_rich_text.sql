@@ -0,0 +1,16 @@
+-- Add migration script here
+-- content of a message is plain text or a json rich text document
+CREATE TYPE message_content_type AS ENUM(
+  'text',
+  'rich'
+);
+
+ALTER TABLE messages
+  ADD COLUMN content_type message_content_type NOT NULL DEFAULT 'text',
+  -- plain text projection of the content for search and previews
+  ADD COLUMN plain_text text;
+
+UPDATE
+  messages
+SET
+  plain_text = content;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- system messages are written by the server for membership, name and topic changes
+ALTER TYPE message_content_type ADD VALUE 'system';
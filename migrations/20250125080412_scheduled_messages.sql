@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TYPE scheduled_message_status AS ENUM(
+  'pending',
+  'sent',
+  'failed'
+);
+
+-- messages to be sent later, delivered by the scheduler through create_message
+CREATE TABLE IF NOT EXISTS scheduled_messages(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  sender_id bigint NOT NULL REFERENCES users(id),
+  content_type message_content_type NOT NULL DEFAULT 'text',
+  content text NOT NULL,
+  files text[] NOT NULL DEFAULT '{}',
+  send_at timestamptz NOT NULL,
+  status scheduled_message_status NOT NULL DEFAULT 'pending',
+  -- the delivered message, or why delivery failed
+  message_id bigint REFERENCES messages(id) ON DELETE SET NULL,
+  error text,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_message_due_index ON scheduled_messages(send_at)
+WHERE
+  status = 'pending';
+
+CREATE INDEX IF NOT EXISTS scheduled_message_sender_index ON scheduled_messages(sender_id, send_at);
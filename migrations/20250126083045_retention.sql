@@ -0,0 +1,27 @@
+-- Add migration script here
+-- retention_days of a workspace: NULL or 0 keeps messages forever
+-- retention_days of a chat: NULL follows the workspace, 0 keeps messages forever
+-- legal_hold blocks any purge of the workspace or chat
+ALTER TABLE workspaces
+  ADD COLUMN retention_days int CHECK (retention_days >= 0),
+  ADD COLUMN legal_hold boolean NOT NULL DEFAULT FALSE;
+
+ALTER TABLE chats
+  ADD COLUMN retention_days int CHECK (retention_days >= 0),
+  ADD COLUMN legal_hold boolean NOT NULL DEFAULT FALSE;
+
+-- audit trail of the purges
+CREATE TABLE IF NOT EXISTS retention_purges(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL,
+  retention_days int NOT NULL,
+  -- messages created before this were purged
+  purged_before timestamptz NOT NULL,
+  messages bigint NOT NULL,
+  files bigint NOT NULL,
+  freed_bytes bigint NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS retention_purge_ws_id_index ON retention_purges(ws_id, created_at DESC);
@@ -0,0 +1,36 @@
+-- Add migration script here
+CREATE TYPE export_format AS ENUM(
+  'json',
+  'csv',
+  'html'
+);
+
+CREATE TYPE export_status AS ENUM(
+  'pending',
+  'running',
+  'done',
+  'failed'
+);
+
+-- exports of a chat, or of the whole workspace if chat_id is NULL, run in the background
+CREATE TABLE IF NOT EXISTS export_jobs(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  requester_id bigint NOT NULL REFERENCES users(id),
+  chat_id bigint REFERENCES chats(id) ON DELETE CASCADE,
+  format export_format NOT NULL,
+  include_files boolean NOT NULL DEFAULT TRUE,
+  status export_status NOT NULL DEFAULT 'pending',
+  messages_total bigint NOT NULL DEFAULT 0,
+  messages_done bigint NOT NULL DEFAULT 0,
+  -- ChatFile url of the archive
+  url text,
+  error text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  started_at timestamptz,
+  finished_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS export_job_status_index ON export_jobs(status, id);
+
+CREATE INDEX IF NOT EXISTS export_job_requester_index ON export_jobs(requester_id, id DESC);
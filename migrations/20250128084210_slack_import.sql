@@ -0,0 +1,58 @@
+-- Add migration script here
+-- single use tokens to set a new password, only the sha256 of the token is stored
+CREATE TABLE IF NOT EXISTS password_resets(
+  token_hash text PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_user_id_index ON password_resets(user_id);
+
+-- what imported users, chats and messages were created as, so a second import of the
+-- same archive skips them
+CREATE TABLE IF NOT EXISTS import_refs(
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  -- user, chat or message
+  kind text NOT NULL,
+  -- id in the source, e.g. a slack user id or channel:ts of a message
+  external_id text NOT NULL,
+  local_id bigint NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, kind, external_id)
+);
+
+-- imported history is not news, skip the notification while `chat.importing` is set
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF current_setting('chat.importing', TRUE) = 'on' THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    SELECT
+      array_agg(m) INTO USERS
+    FROM
+      chats,
+      unnest(chats.members) AS m
+    WHERE
+      chats.id = NEW.chat_id
+      AND m NOT IN (
+        SELECT
+          user_id
+        FROM
+          chat_mutes
+        WHERE
+          chat_id = NEW.chat_id);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', COALESCE(USERS, '{}'))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,58 @@
+-- Add migration script here
+CREATE TYPE audit_action AS ENUM(
+  'user_signup',
+  'user_signin',
+  'user_signin_failed',
+  'user_password_reset',
+  'chat_created',
+  'chat_renamed',
+  'chat_members_added',
+  'chat_members_removed',
+  'chat_retention_updated',
+  'file_uploaded',
+  'file_url_signed',
+  'webhook_created',
+  'webhook_deleted',
+  'command_created',
+  'command_deleted',
+  'workspace_retention_updated',
+  'workspace_messages_purged',
+  'workspace_files_collected',
+  'workspace_export_requested',
+  'workspace_imported'
+);
+
+-- security relevant actions, kept apart from the tables they describe so deleting a
+-- user or chat leaves its history intact
+CREATE TABLE IF NOT EXISTS audit_events(
+  id bigserial PRIMARY KEY,
+  -- NULL for failed signins of unknown emails
+  ws_id bigint,
+  -- NULL if nobody was signed in
+  actor_id bigint,
+  action audit_action NOT NULL,
+  target_type text,
+  target_id text,
+  ip text,
+  request_id text,
+  metadata jsonb NOT NULL DEFAULT '{}',
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS audit_event_ws_id_index ON audit_events(ws_id, id DESC);
+
+CREATE INDEX IF NOT EXISTS audit_event_actor_id_index ON audit_events(actor_id, id DESC);
+
+-- the log is append-only
+CREATE OR REPLACE FUNCTION audit_events_append_only()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$
+LANGUAGE plpgsql;
+CREATE TRIGGER audit_events_append_only_trigger
+  BEFORE UPDATE OR DELETE ON audit_events
+  FOR EACH ROW
+  EXECUTE FUNCTION audit_events_append_only();
@@ -0,0 +1,36 @@
+-- Add migration script here
+-- the server sets `chat.traceparent` in the transaction inserting a message, so
+-- notify_server can continue the trace of the request which sent it
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF current_setting('chat.importing', TRUE) = 'on' THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    SELECT
+      array_agg(m) INTO USERS
+    FROM
+      chats,
+      unnest(chats.members) AS m
+    WHERE
+      chats.id = NEW.chat_id
+      AND m NOT IN (
+        SELECT
+          user_id
+        FROM
+          chat_mutes
+        WHERE
+          chat_id = NEW.chat_id);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', COALESCE(USERS, '{}'),
+        'traceparent', NULLIF(current_setting('chat.traceparent', TRUE), ''))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- a worker claims an unfurl job until locked_until and deletes it once done, so the
+-- job of a worker that died is retried
+ALTER TABLE unfurl_jobs
+  ADD COLUMN attempts int NOT NULL DEFAULT 0,
+  ADD COLUMN locked_until timestamptz;
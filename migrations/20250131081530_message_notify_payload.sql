@@ -0,0 +1,39 @@
+-- Add migration script here
+-- pg_notify payloads must stay below 8000 bytes, so only the columns of chat_core's
+-- Message go out, not the whole row with its plain_text copy of the content
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF current_setting('chat.importing', TRUE) = 'on' THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW.id;
+    SELECT
+      array_agg(m) INTO USERS
+    FROM
+      chats,
+      unnest(chats.members) AS m
+    WHERE
+      chats.id = NEW.chat_id
+      AND m NOT IN (
+        SELECT
+          user_id
+        FROM
+          chat_mutes
+        WHERE
+          chat_id = NEW.chat_id);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', json_build_object('id', NEW.id,
+        'chat_id', NEW.chat_id, 'sender_id', NEW.sender_id, 'content_type', NEW.content_type,
+        'content', NEW.content, 'files', NEW.files, 'created_at', NEW.created_at),
+        'members', COALESCE(USERS, '{}'),
+        'traceparent', NULLIF(current_setting('chat.traceparent', TRUE), ''))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
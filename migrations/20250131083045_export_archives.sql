@@ -0,0 +1,6 @@
+-- Add migration script here
+-- archives are stored outside the workspace files and downloaded from the export, a
+-- worker holds a running job until locked_until and extends it while it works
+ALTER TABLE export_jobs
+  ADD COLUMN archive_key text,
+  ADD COLUMN locked_until timestamptz;
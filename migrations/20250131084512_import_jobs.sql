@@ -0,0 +1,27 @@
+-- Add migration script here
+CREATE TYPE import_status AS ENUM(
+  'pending',
+  'running',
+  'done',
+  'failed'
+);
+
+-- imports of an uploaded archive run in the background like exports
+CREATE TABLE IF NOT EXISTS import_jobs(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  requester_id bigint NOT NULL REFERENCES users(id),
+  -- the uploaded archive in the store, deleted once the job finished
+  archive_key text,
+  -- to download files the archive doesn't include, cleared once the job finished
+  slack_token text,
+  status import_status NOT NULL DEFAULT 'pending',
+  report jsonb,
+  error text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  started_at timestamptz,
+  locked_until timestamptz,
+  finished_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS import_job_status_index ON import_jobs(status, id);
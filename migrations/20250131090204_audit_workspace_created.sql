@@ -0,0 +1,3 @@
+-- Add migration script here
+-- workspaces created by a signup or chat-admin
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'workspace_created';
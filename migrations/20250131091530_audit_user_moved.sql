@@ -0,0 +1,4 @@
+-- Add migration script here
+-- users moved to another workspace and workspaces handed over by chat-admin
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'user_moved';
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'workspace_owner_changed';
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- bots of deleted webhooks which already posted are kept as the sender of their messages,
+-- but no longer listed or added to chats
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS disabled_at timestamptz;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- open sessions hold their size against the workspace quota until they complete or go,
+-- and expire after a while without a chunk rather than a while after they started
+ALTER TABLE upload_sessions
+  ADD COLUMN IF NOT EXISTS reserved bigint NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS upload_session_updated_at_index ON upload_sessions(updated_at);
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- archives are stored by their hash like uploads and served from their file url, the
+-- ones stored under exports/ before have no such url and are left to expire
+UPDATE export_jobs SET url = NULL WHERE url LIKE '/api/exports/%';
+
+CREATE INDEX IF NOT EXISTS export_jobs_url_index ON export_jobs(url);
@@ -1,12 +1,16 @@
-use std::{env, fs::File};
-
 use serde::{Deserialize, Serialize};
-use anyhow::{Result,bail};
+use anyhow::Result;
+use chat_core::config::{load_config, ConfigReport, ConfigValidator};
+use chat_core::migrate::MigrateMode;
+use chat_core::telemetry::TelemetryConfig;
+use chat_core::DecodingKey;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,21 +22,63 @@ pub struct AuthConfig {
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// check or apply the embedded migrations on startup, `--check` forces `check`
+    #[serde(default)]
+    pub migrate: MigrateMode,
+    /// how long SSE clients and in-flight requests get after SIGTERM
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    /// port of `GET /metrics` for a scraper inside the cluster
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// serve `GET /metrics` next to the events as well, only if the server isn't public
+    #[serde(default)]
+    pub metrics_on_api: bool,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
 }
 
 impl AppConfig {
     pub fn load() -> Result<Self> {
-        // read from  ./app.yml, or /etc/config/app.yml, or from env CHAT_CONFIG
-        let ret = match (
-            File::open("notify.yml"),
-            File::open("/etc/config/notify.yml"),
-            env::var("NOTIFY_CONFIG"),
-        ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
-            (_, Ok(reader), _) => serde_yaml::from_reader(reader),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
-            _ => bail!("Config file not found"),
-        };
-        Ok(ret?)
+        Ok(Self::load_with_report()?.0)
+    }
+
+    /// Defaults, then the first of `NOTIFY_CONFIG`, ./notify.yml or /etc/config/notify.yml,
+    /// then env vars like `NOTIFY_SERVER__PORT`
+    pub fn load_with_report() -> Result<(Self, ConfigReport)> {
+        let (config, report) = load_config::<Self>("notify")?;
+        config.validate()?;
+        Ok((config, report))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut v = ConfigValidator::new();
+        let db_url = &self.server.db_url;
+        v.positive(self.server.port as _, "server.port")
+            .positive(self.server.shutdown_timeout_secs, "server.shutdown_timeout_secs")
+            .check(
+                db_url.starts_with("postgres://") || db_url.starts_with("postgresql://"),
+                "server.db_url",
+                "must be a postgres:// url",
+            )
+            .check(
+                self.server.metrics_port != Some(self.server.port),
+                "server.metrics_port",
+                "must differ from server.port",
+            );
+        if let Err(e) = DecodingKey::load(&self.auth.pk) {
+            v.check(false, "auth.pk", format!("not an Ed25519 public key: {}", e));
+        }
+        if self.telemetry.enabled {
+            v.http_url(&self.telemetry.endpoint, "telemetry.endpoint");
+        }
+        v.check(
+            (0.0..=1.0).contains(&self.telemetry.sample_ratio),
+            "telemetry.sample_ratio",
+            "must be between 0 and 1",
+        );
+        v.finish()
     }
-}
\ No newline at end of file
+}
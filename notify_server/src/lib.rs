@@ -4,18 +4,24 @@ mod error;
 mod notif;
 
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use axum::middleware::from_fn_with_state;
+use axum::extract::State;
 use axum::response::{Html, IntoResponse};
 use axum::routing::get;
 use axum::Router;
-use chat_core::middlewares::{verify_token, TokenVerify};
+use chat_core::health::{check_pool, Readiness};
+use chat_core::middlewares::{set_layer, verify_token, TokenVerify};
+use chat_core::shutdown::Shutdown;
 use chat_core::DecodingKey;
 use config::AppConfig;
 use dashmap::DashMap;
 use error::AppError;
 use notif::AppEvent;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
 use sse::sse_handler;
 use tokio::sync::broadcast;
 pub use notif::setup_pg_listener;
@@ -29,19 +35,35 @@ pub struct AppStateInner {
     pub config: AppConfig,
     users: UserMap,
     dk: DecodingKey,
+    /// only for readiness, events come in through the listener
+    pool: PgPool,
+    /// the PgListener is listening on the notification channels
+    listener_attached: AtomicBool,
+    shutdown: Shutdown,
 }
 
 const INDEX_HTML: &'static str = include_str!("../index.html");
 
 pub async  fn get_router(config: AppConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config);
+    get_router_with_shutdown(config, Shutdown::new()).await
+}
+
+/// SSE streams get a final `Shutdown` event and end once `shutdown` is triggered
+pub async fn get_router_with_shutdown(
+    config: AppConfig,
+    shutdown: Shutdown,
+) -> anyhow::Result<Router> {
+    let state = AppState::new(config, shutdown);
     notif::setup_pg_listener(state.clone()).await?;
+    chat_core::metrics::track_pool(state.pool.clone());
     let app = Router::new()
         .route("/events", get(sse_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         .with_state(state.clone());
-    Ok(app)
+    Ok(set_layer(app, state.config.server.metrics_on_api))
 }
 
 
@@ -50,6 +72,26 @@ async fn index_handler() -> impl IntoResponse {
     Html(INDEX_HTML)
 }
 
+async fn healthz_handler() -> impl IntoResponse {
+    "ok"
+}
+
+/// Postgres answers, the listener is attached and the server isn't shutting down
+async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let listener = match state.listener_attached.load(Ordering::Relaxed) {
+        true => Ok(()),
+        false => Err("not attached".to_string()),
+    };
+    let shutdown = match state.shutdown.is_triggered() {
+        true => Err("shutting down".to_string()),
+        false => Ok(()),
+    };
+    Readiness::new()
+        .check("db", check_pool(&state.pool).await)
+        .check("listener", listener)
+        .check("shutdown", shutdown)
+}
+
 impl TokenVerify for AppState {
     type Error = AppError;
 
@@ -67,9 +109,20 @@ impl Deref for AppState {
 }
 
 impl AppState {
-    pub fn new(config: AppConfig) -> Self {
+    pub fn new(config: AppConfig, shutdown: Shutdown) -> Self {
         let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_lazy(&config.server.db_url)
+            .expect("Failed to parse db_url");
+        Self(Arc::new(AppStateInner {
+            config,
+            dk,
+            users,
+            pool,
+            listener_attached: AtomicBool::new(false),
+            shutdown,
+        }))
     }
 }
@@ -1,23 +1,49 @@
+use std::future::IntoFuture;
+use std::time::Duration;
+
+use chat_core::metrics::spawn_metrics_server;
+use chat_core::migrate::{prepare_schema, MigrateMode};
+use chat_core::shutdown::Shutdown;
+use chat_core::telemetry::init_tracing;
 use notify_server::config::AppConfig;
-use notify_server::{get_router, setup_pg_listener};
+use notify_server::get_router_with_shutdown;
+use sqlx::PgPool;
 use tokio::net::TcpListener;
 use tracing::info;
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::fmt::Layer;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::Layer as _;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
-    let addr = "0.0.0.0:6687";
-    let config = AppConfig::load().expect("Failed to load config");
-    let app = get_router(config).await?;
-    let listener = TcpListener::bind(addr).await?;
+    let (mut config, report) = AppConfig::load_with_report()?;
+    // spans still being exported are flushed when the guard drops at the end
+    let _telemetry = init_tracing("notify-server", &config.telemetry)?;
+    report.log();
+    let addr = format!("0.0.0.0:{}", config.server.port);
+    // `--check` only verifies the schema, whatever the config says
+    if std::env::args().skip(1).any(|arg| arg == "--check") {
+        config.server.migrate = MigrateMode::Check;
+    }
+    if config.server.migrate != MigrateMode::Off {
+        // the listener alone doesn't need a pool, it's only held for the migrations
+        let pool = PgPool::connect(&config.server.db_url).await?;
+        prepare_schema(&pool, config.server.migrate).await?;
+        pool.close().await;
+    }
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let metrics_port = config.server.metrics_port;
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_signals();
+    let app = get_router_with_shutdown(config, shutdown.clone()).await?;
+    if let Some(port) = metrics_port {
+        spawn_metrics_server(port, shutdown.triggered()).await?;
+    }
+    let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
-    axum::serve(listener, app.into_make_service()).await?;
+    let server = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown.triggered());
+    shutdown
+        .drain(server.into_future(), shutdown_timeout)
+        .await?;
+    info!("Server stopped");
 
     Ok(())
 }
@@ -1,11 +1,11 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, sync::{atomic::Ordering, Arc}};
 
-use chat_core::{Chat, Message};
+use chat_core::{telemetry::set_remote_parent, Chat, LinkPreview, Message, Reminder};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
-use tracing::{info, warn};
-use futures::StreamExt;
-use crate::AppState;
+use sqlx::postgres::{PgListener, PgNotification};
+use tracing::{info, info_span, warn};
+use futures::{Stream, StreamExt};
+use crate::{AppState, UserMap};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
@@ -14,6 +14,16 @@ pub enum AppEvent {
     AddToChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    MessageUpdated(MessageUpdated),
+    Reminder(Reminder),
+}
+
+/// Link previews of a message are ready
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageUpdated {
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub link_previews: Vec<LinkPreview>,
 }
 
 
@@ -22,6 +32,7 @@ struct Notification {
     // users being impacted, so we should send the notification to them
     user_ids: HashSet<u64>,
     event: Arc<AppEvent>,
+    traceparent: Option<String>,
 }
 
 // pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
@@ -37,35 +48,69 @@ struct ChatUpdated {
 struct ChatMessageCreated {
     message: Message,
     members: Vec<i64>,
+    /// trace of the request which sent the message
+    #[serde(default)]
+    traceparent: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageUpdated {
+    #[serde(flatten)]
+    update: MessageUpdated,
+    members: Vec<i64>,
+}
 
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("reminder_due").await?;
+    listener.listen("message_updated").await?;
+    state.listener_attached.store(true, Ordering::Relaxed);
     let mut stream = listener.into_stream();
     tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
-            info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
-                    }
-                }
-            }
-        }
-        Ok::<_, anyhow::Error>(())
+        let ret = forward_notifications(&state, &mut stream).await;
+        state.listener_attached.store(false, Ordering::Relaxed);
+        warn!("PgListener detached: {:?}", ret);
+        ret
     });
     Ok(())
 }
 
+/// Fan notifications out to the subscribed users until the stream breaks
+async fn forward_notifications(
+    state: &AppState,
+    stream: &mut (impl Stream<Item = Result<PgNotification, sqlx::Error>> + Unpin),
+) -> anyhow::Result<()> {
+    while let Some(Ok(notif)) = stream.next().await {
+        info!("Received notification: {:?}", notif);
+        metrics::counter!("pg_notifications_total", "channel" => notif.channel().to_string())
+            .increment(1);
+        let notification = Notification::load(notif.channel(), notif.payload())?;
+        let span = info_span!("pg_notification", channel = notif.channel());
+        if let Some(traceparent) = &notification.traceparent {
+            set_remote_parent(&span, traceparent);
+        }
+        span.in_scope(|| notification.dispatch(&state.users));
+    }
+    Ok(())
+}
+
 
 impl Notification {
+    /// Push the event to the subscribed users it concerns
+    fn dispatch(self, users: &UserMap) {
+        for user_id in self.user_ids {
+            if let Some(tx) = users.get(&user_id) {
+                let _span = info_span!("sse_push", user_id).entered();
+                info!("Sending notification to user {}", user_id);
+                if let Err(e) = tx.send(self.event.clone()) {
+                    warn!("Failed to send notification to user {}: {}", user_id, e);
+                }
+            }
+        }
+    }
+
     fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
         match r#type {
             "chat_updated" => {
@@ -82,6 +127,7 @@ impl Notification {
                 Ok(Self {
                     user_ids,
                     event: Arc::new(event),
+                    traceparent: None,
                 })
             }
             "chat_message_created" => {
@@ -90,6 +136,26 @@ impl Notification {
                 Ok(Self {
                     user_ids,
                     event: Arc::new(AppEvent::NewMessage(payload.message)),
+                    traceparent: payload.traceparent,
+                })
+            }
+            // pg_notify('message_updated', json_build_object('message_id', .., 'chat_id', .., 'link_previews', .., 'members', ..)::text);
+            "message_updated" => {
+                let payload: ChatMessageUpdated = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageUpdated(payload.update)),
+                    traceparent: None,
+                })
+            }
+            // pg_notify('reminder_due', row_to_json(NEW)::text);
+            "reminder_due" => {
+                let payload: Reminder = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: HashSet::from([payload.user_id as u64]),
+                    event: Arc::new(AppEvent::Reminder(payload)),
+                    traceparent: None,
                 })
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
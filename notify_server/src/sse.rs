@@ -6,16 +6,17 @@ use axum_extra::{headers, TypedHeader};
 use chat_core::User;
 use futures::Stream;
 use tokio::sync::broadcast;
-use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use std::convert::Infallible;
 use std::time::Duration;
 use tokio_stream::StreamExt;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::notif::AppEvent;
 use crate::AppState;
 
 const CHANNEL_CAPACITY: usize = 256;
+const SHUTDOWN_EVENT: &str = "Shutdown";
 
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
@@ -37,16 +38,39 @@ pub(crate) async fn sse_handler(
         rx
     };
     info!("User {} subscribed", user_id);
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        Ok(Event::default().data(v).event(name))
-    });
+    let connected = ConnectedGuard::new();
+    let stream = BroadcastStream::new(rx)
+        .filter_map(move |v| {
+            // the guard lives as long as the stream, that is the connection
+            let _ = &connected;
+            match v {
+                Ok(v) => Some(v),
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    warn!("User {} lagged behind, {} events dropped", user_id, n);
+                    metrics::counter!("sse_lagged_events_total").increment(n);
+                    None
+                }
+            }
+        })
+        .map(|v| {
+            let name = match v.as_ref() {
+                AppEvent::NewChat(_) => "NewChat",
+                AppEvent::AddToChat(_) => "AddToChat",
+                AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+                AppEvent::NewMessage(_) => "NewMessage",
+                AppEvent::MessageUpdated(_) => "MessageUpdated",
+                AppEvent::Reminder(_) => "Reminder",
+            };
+            let v = serde_json::to_string(&v).expect("Failed to serialize event");
+            Ok(Event::default().data(v).event(name))
+        });
+    // on shutdown clients get a last event to reconnect to another instance, then the
+    // stream ends so the connection can drain
+    let stream = futures::StreamExt::take_until(stream, state.shutdown.triggered()).chain(
+        tokio_stream::once(Ok(Event::default()
+            .event(SHUTDOWN_EVENT)
+            .data(r#"{"event":"Shutdown"}"#))),
+    );
 
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
@@ -54,3 +78,19 @@ pub(crate) async fn sse_handler(
             .text("keep-alive-text"),
     )
 }
+
+/// Counts a connected SSE client in `sse_connected_clients` while alive
+struct ConnectedGuard;
+
+impl ConnectedGuard {
+    fn new() -> Self {
+        metrics::gauge!("sse_connected_clients").increment(1.0);
+        Self
+    }
+}
+
+impl Drop for ConnectedGuard {
+    fn drop(&mut self) {
+        metrics::gauge!("sse_connected_clients").decrement(1.0);
+    }
+}